@@ -1,7 +1,18 @@
-use std::io;
+use std::borrow::Cow;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use ropey::Rope;
 use thiserror::Error;
 use log::{debug, error, info, warn};
 
+use crate::encoding::{self, Encoding, EncodingError};
+use crate::diff;
+use crate::fileio;
+use crate::text;
+use crate::swap;
+use crate::history::{content_hash, extend_hash, text_end, Branch, Edit, History, Span, HASH_SEED};
+
 #[derive(Error, Debug)]
 pub enum BufferError {
     #[error("File not found: {0}")]
@@ -12,78 +23,606 @@ pub enum BufferError {
     InvalidLineIndex(usize),
     #[error("Invalid column index: {0} in line {1}")]
     InvalidColumnIndex(usize, usize),
+    #[error("{0}")]
+    Encoding(#[from] EncodingError),
+    #[error("File changed on disk since it was read: {0}")]
+    ChangedOnDisk(String),
+}
+
+/// Line break style of a file, the `fileformat` of vim.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Unix),
+            "dos" => Some(LineEnding::Dos),
+            _ => None,
+        }
+    }
+
+    /// `Dos` only when every line break is CRLF; with mixed endings the stray
+    /// `\r`s stay in the text so the file still round-trips byte for byte.
+    fn detect(content: &str) -> Self {
+        let breaks = content.matches('\n').count();
+        if breaks > 0 && content.matches("\r\n").count() == breaks {
+            LineEnding::Dos
+        } else {
+            LineEnding::Unix
+        }
+    }
+}
+
+/// What a file looked like on disk when it was last read or written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskState {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl DiskState {
+    fn read(path: &str, hash: u64) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some(Self { modified: meta.modified().ok(), len: meta.len(), hash })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiskChange {
+    Unchanged,
+    Changed(DiskState),
+    Deleted,
 }
 
 pub struct Buffer {
     pub file: Option<String>,
-    pub lines: Vec<String>,
+    // a rope keeps edits and line lookups O(log n) on very large files
+    text: Rope,
     pub modified: bool,
+    pub line_ending: LineEnding,
+    /// Whether the last line ends with a line break.
+    pub trailing_newline: bool,
+    pub encoding: Encoding,
+    pub bom: bool,
+    /// Where the undo tree is persisted on save, `None` to keep it in memory only.
+    pub undo_file: Option<PathBuf>,
+    disk_state: Option<DiskState>,
+    /// Written with unsaved changes while idle; `None` when another vix owns it.
+    pub swap_file: Option<PathBuf>,
+    swap_dirty: bool,
+    lock: Option<swap::Lock>,
+    /// Pid of another vix editing the same file.
+    pub locked_by: Option<u32>,
+    /// A swap or recovery file left by a session that did not exit cleanly.
+    pub recovery: Option<PathBuf>,
+    history: History,
+    saved_seq: usize,
+    // The line ending and encoding of the file as last read or written. A
+    // buffer switched to others stays modified until written, whatever is
+    // undone.
+    saved_format: (LineEnding, Encoding),
+    // Lines followed through edits for commands such as `:g`, one set per
+    // command running; `None` once the line is deleted.
+    tracked: Vec<Vec<Option<usize>>>,
+}
+
+// Counts and hashes everything written through it, to tag the undo file.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hash: u64,
+    written: usize,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hash = extend_hash(self.hash, &buf[..n]);
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where vix keeps its log, undo store and swap files: `$VIX_HOME` when it
+/// is set, otherwise `~/.vix`.
+#[cfg(not(test))]
+pub fn state_dir() -> Option<PathBuf> {
+    match std::env::var_os("VIX_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => Some(dirs::home_dir()?.join(".vix")),
+    }
+}
+
+// Tests never touch the real `~/.vix`: without a directory of their own
+// they get no undo store or swap files at all.
+#[cfg(test)]
+pub fn state_dir() -> Option<PathBuf> {
+    TEST_STATE_DIR.with(|dir| dir.borrow().clone())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The state directory for the test running on this thread.
+    pub static TEST_STATE_DIR: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// The file kept for `file_path` under `<state dir>/<dir>/`, such as its
+/// undo store or swap file: the absolute path with `/` replaced by `%`,
+/// then `suffix`.
+fn vix_file_for(dir: &str, file_path: &str, suffix: &str) -> Option<PathBuf> {
+    let path = Path::new(file_path);
+    let absolute = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => std::env::current_dir().ok()?.join(path),
+    };
+    let name = absolute.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%");
+    Some(state_dir()?.join(dir).join(name + suffix))
+}
+
+fn undo_file_for(file_path: &str) -> Option<PathBuf> {
+    vix_file_for("undo", file_path, "")
+}
+
+fn recovery_file_for(file_path: &str) -> String {
+    format!("{}.recovery", file_path)
 }
 
 impl Buffer {
-    pub fn from_file(file: Option<String>) -> Result<Self, BufferError> {
-        let lines = match &file {
-            Some(file_path) => {
-                info!("Opening file: {}", file_path);
-                if !std::path::Path::new(file_path).exists() {
-                    warn!("File not found: {}", file_path);
-                    return Err(BufferError::FileNotFound(file_path.clone()));
+    /// Opens `file` decoding it as `encoding`, or a detected encoding when `None`.
+    pub fn from_file(file: Option<String>, encoding: Option<Encoding>) -> Result<Self, BufferError> {
+        let mut buffer = Self::empty();
+        let Some(file_path) = file else {
+            info!("Creating new empty buffer");
+            return Ok(buffer);
+        };
+
+        info!("Opening file: {}", file_path);
+        if !std::path::Path::new(&file_path).exists() {
+            warn!("File not found: {}", file_path);
+            return Err(BufferError::FileNotFound(file_path.clone()));
+        }
+        let raw = std::fs::read(&file_path)?;
+        let hash = content_hash(&raw);
+        buffer.load_bytes(&raw, encoding)?;
+        debug!(
+            "Read {} lines from file ({}, {}, final newline: {}, BOM: {})",
+            buffer.len(), buffer.encoding.name(), buffer.line_ending.name(), buffer.trailing_newline, buffer.bom
+        );
+
+        buffer.disk_state = DiskState::read(&file_path, hash);
+        buffer.undo_file = undo_file_for(&file_path);
+        if let Some(history) = buffer.undo_file.as_deref().and_then(|path| History::load_from(path, hash)) {
+            info!("Restored undo history at change #{}", history.current_seq());
+            buffer.saved_seq = history.current_seq();
+            buffer.history = history;
+        }
+        buffer.open_swap(&file_path);
+        buffer.file = Some(file_path);
+        Ok(buffer)
+    }
+
+    // Locks the file against other vix instances and looks for changes a
+    // crashed session left behind.
+    fn open_swap(&mut self, file_path: &str) {
+        let mut candidates = vec![PathBuf::from(recovery_file_for(file_path))];
+        if let Some(swap_file) = vix_file_for("swap", file_path, ".swp") {
+            self.lock_swap(file_path, swap_file, &mut candidates);
+        }
+        self.recovery = candidates.into_iter()
+            .filter_map(|path| Some((std::fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .max()
+            .map(|(_, path)| path);
+        if let Some(path) = &self.recovery {
+            info!("Found recovery file {:?} for {}", path, file_path);
+        }
+    }
+
+    // Takes the lock beside `swap_file`, adding the swap file to the
+    // `candidates` for recovery when nobody else holds it.
+    fn lock_swap(&mut self, file_path: &str, swap_file: PathBuf, candidates: &mut Vec<PathBuf>) {
+        let lock_file = swap_file.with_extension("swp.lock");
+        if let Some(pid) = swap::holder(&lock_file) {
+            warn!("{} is already being edited by vix (pid {})", file_path, pid);
+            self.locked_by = Some(pid);
+        } else {
+            match swap::Lock::acquire(&lock_file) {
+                Ok(lock) => {
+                    // nobody else holds the lock, so a swap file here is orphaned
+                    candidates.push(swap_file.clone());
+                    self.lock = Some(lock);
+                    self.swap_file = Some(swap_file);
                 }
-                let content: Vec<String> = std::fs::read_to_string(file_path)?
-                    .lines()
-                    .map(|s| s.to_string())
-                    .collect();
-                debug!("Read {} lines from file", content.len());
-                content
-            }
-            None => {
-                info!("Creating new empty buffer");
-                vec![String::new()]
+                Err(e) => warn!("Could not lock {}: {}", file_path, e),
             }
+        }
+    }
+
+    /// An empty buffer that will be written to `file_path`, which does not exist yet.
+    pub fn new_file(file_path: String) -> Self {
+        info!("Creating buffer for new file: {}", file_path);
+        let mut buffer = Self::empty();
+        buffer.undo_file = undo_file_for(&file_path);
+        buffer.open_swap(&file_path);
+        buffer.file = Some(file_path);
+        buffer
+    }
+
+    /// An empty buffer with no file, like the one `:bd` leaves behind.
+    pub fn empty() -> Self {
+        Self {
+            file: None,
+            text: Rope::new(),
+            modified: false,
+            line_ending: LineEnding::Unix,
+            trailing_newline: true,
+            encoding: Encoding::Utf8,
+            bom: false,
+            undo_file: None,
+            disk_state: None,
+            swap_file: None,
+            swap_dirty: false,
+            lock: None,
+            locked_by: None,
+            recovery: None,
+            history: History::new(),
+            saved_seq: 0,
+            saved_format: (LineEnding::Unix, Encoding::Utf8),
+            tracked: Vec::new(),
+        }
+    }
+
+    fn load_bytes(&mut self, raw: &[u8], encoding: Option<Encoding>) -> Result<(), BufferError> {
+        (self.encoding, self.bom) = match encoding {
+            Some(encoding) => (encoding, !encoding.bom().is_empty() && raw.starts_with(encoding.bom())),
+            None => encoding::detect(raw),
         };
-        Ok(Self { file, lines, modified: false })
+        let body = if self.bom { &raw[self.encoding.bom().len()..] } else { raw };
+        let raw = self.encoding.decode(body)?.into_owned();
+        self.line_ending = LineEnding::detect(&raw);
+        self.saved_format = (self.line_ending, self.encoding);
+        let mut content = match self.line_ending {
+            LineEnding::Dos => raw.replace("\r\n", "\n"),
+            LineEnding::Unix => raw,
+        };
+        self.trailing_newline = content.ends_with('\n');
+        if self.trailing_newline {
+            content.pop();
+        }
+        self.text = Rope::from_str(&content);
+        Ok(())
+    }
+
+    /// Switches the encoding used when writing, like `:set fileencoding`.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        if self.encoding != encoding {
+            info!("Converting {} from {} to {}", self.display_name(), self.encoding.name(), encoding.name());
+            self.encoding = encoding;
+            self.update_modified();
+            self.swap_dirty = true;
+        }
+    }
+
+    /// Switches the line break style used when writing, like `:set fileformat`.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.line_ending != line_ending {
+            info!("Converting {} from {} to {}", self.display_name(), self.line_ending.name(), line_ending.name());
+            self.line_ending = line_ending;
+            self.update_modified();
+            self.swap_dirty = true;
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.lines.len()
+        self.text.len_lines()
     }
 
-    pub fn get_line(&self, index: usize) -> Result<&String, BufferError> {
-        self.lines.get(index)
-            .ok_or(BufferError::InvalidLineIndex(index))
+    /// The text of line `index`, without its line break. Borrowed unless the
+    /// line straddles two rope chunks.
+    pub fn get_line(&self, index: usize) -> Result<Cow<'_, str>, BufferError> {
+        let line = self.line_slice(index)?;
+        Ok(match line.as_str() {
+            Some(text) => Cow::Borrowed(text),
+            None => Cow::Owned(line.to_string()),
+        })
     }
 
-    pub fn get_line_mut(&mut self, index: usize) -> Result<&mut String, BufferError> {
-        self.lines.get_mut(index)
-            .ok_or(BufferError::InvalidLineIndex(index))
+    fn line_slice(&self, index: usize) -> Result<ropey::RopeSlice<'_>, BufferError> {
+        if index >= self.len() {
+            return Err(BufferError::InvalidLineIndex(index));
+        }
+        let line = self.text.line(index);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            Ok(line.slice(..len - 1))
+        } else {
+            Ok(line)
+        }
     }
 
-    pub fn insert_char(&mut self, line: usize, col: usize, c: char) -> Result<(), BufferError> {
-        {
-            let line_content = self.get_line_mut(line)?;
-            if col > line_content.len() {
-                return Err(BufferError::InvalidColumnIndex(col, line));
-            }
-            line_content.insert(col, c);
+    /// Inserts `c` and returns the column just past it.
+    pub fn insert_char(&mut self, line: usize, col: usize, c: char) -> Result<usize, BufferError> {
+        let mut encoded = [0u8; 4];
+        let (_, end) = self.insert_text(line, col, c.encode_utf8(&mut encoded))?;
+        Ok(end)
+    }
+
+    /// Removes the grapheme at `col` and returns it.
+    pub fn remove_char(&mut self, line: usize, col: usize) -> Result<String, BufferError> {
+        if col >= self.line_length(line)? {
+            return Err(BufferError::InvalidColumnIndex(col, line));
         }
-        self.modified = true;
+        self.delete_text((line, col), (line, col + 1))
+    }
+
+    /// Breaks `line` in two at `col`, moving the tail onto a new line below.
+    pub fn split_line(&mut self, line: usize, col: usize) -> Result<(), BufferError> {
+        self.insert_text(line, col, "\n")?;
         Ok(())
     }
 
-    pub fn remove_char(&mut self, line: usize, col: usize) -> Result<char, BufferError> {
-        let removed = {
-            let line_content = self.get_line_mut(line)?;
-            if col >= line_content.len() {
-                return Err(BufferError::InvalidColumnIndex(col, line));
-            }
-            line_content.remove(col)
-        };
+    /// Inserts `text`, which may contain newlines, and returns the position just past it.
+    pub fn insert_text(&mut self, line: usize, col: usize, text: &str) -> Result<(usize, usize), BufferError> {
+        let byte = self.byte_col(line, col)?;
+        let end = self.raw_insert(line, byte, text)?;
+        self.history.record(Edit::Insert { line, col: byte, text: text.to_string() });
         self.modified = true;
+        Ok(self.grapheme_pos(end))
+    }
+
+    /// Removes the text between `start` (inclusive) and `end` (exclusive) and returns it.
+    pub fn delete_text(&mut self, start: (usize, usize), end: (usize, usize)) -> Result<String, BufferError> {
+        let start = (start.0, self.byte_col(start.0, start.1)?);
+        let end = (end.0, self.byte_col(end.0, end.1)?);
+        let removed = self.raw_delete(start, end)?;
+        if !removed.is_empty() {
+            self.history.record(Edit::Delete { line: start.0, col: start.1, text: removed.clone() });
+            self.modified = true;
+        }
+        Ok(removed)
+    }
+
+    // Columns in the public API are grapheme clusters; edits and the rope
+    // work with byte offsets within the line.
+    fn byte_col(&self, line: usize, col: usize) -> Result<usize, BufferError> {
+        text::byte_offset(&self.get_line(line)?, col)
+            .ok_or(BufferError::InvalidColumnIndex(col, line))
+    }
+
+    fn grapheme_pos(&self, (line, byte): (usize, usize)) -> (usize, usize) {
+        let col = self.get_line(line)
+            .map(|content| text::grapheme_col(&content, byte))
+            .unwrap_or(0);
+        (line, col)
+    }
+
+    // char index into the rope of a (line, byte offset) position
+    fn char_index(&self, line: usize, col: usize) -> Result<usize, BufferError> {
+        let content = self.line_slice(line)?;
+        if col > content.len_bytes() {
+            return Err(BufferError::InvalidColumnIndex(col, line));
+        }
+        let char_col = content.byte_to_char(col);
+        if content.char_to_byte(char_col) != col {
+            return Err(BufferError::InvalidColumnIndex(col, line));
+        }
+        Ok(self.text.line_to_char(line) + char_col)
+    }
+
+    fn raw_insert(&mut self, line: usize, col: usize, text: &str) -> Result<(usize, usize), BufferError> {
+        let at = self.char_index(line, col)?;
+        self.text.insert(at, text);
+        self.track_insert(line, col, text);
+        self.swap_dirty = true;
+        Ok(text_end(line, col, text))
+    }
+
+    fn raw_delete(&mut self, start: (usize, usize), end: (usize, usize)) -> Result<String, BufferError> {
+        let from = self.char_index(start.0, start.1)?;
+        let to = self.char_index(end.0, end.1)?;
+        if to <= from {
+            return Ok(String::new());
+        }
+        let removed = self.text.slice(from..to).to_string();
+        self.text.remove(from..to);
+        self.track_delete(start, end);
+        self.swap_dirty = true;
         Ok(removed)
     }
 
+    /// Groups every edit until `end_undo_group` into one undo step that
+    /// restores the cursor to `cursor`.
+    pub fn begin_undo_group(&mut self, (line, col): (usize, usize)) {
+        let byte = self.byte_col(line, col).unwrap_or(0);
+        self.history.begin((line, byte));
+    }
+
+    pub fn end_undo_group(&mut self) {
+        self.history.end();
+    }
+
+    /// Like `begin_undo_group`, but the group stays open through
+    /// `end_undo_group` until `end_undo_block`, so that a command running
+    /// other commands is undone in one step.
+    pub fn begin_undo_block(&mut self, (line, col): (usize, usize)) {
+        let byte = self.byte_col(line, col).unwrap_or(0);
+        self.history.hold((line, byte));
+    }
+
+    pub fn end_undo_block(&mut self) {
+        self.history.release();
+    }
+
+    /// Starts following `lines` through later edits, and returns the handle
+    /// to look them up with. Sets are stacked: the last one tracked is the
+    /// first untracked.
+    pub fn track_lines(&mut self, lines: &[usize]) -> usize {
+        self.tracked.push(lines.iter().copied().map(Some).collect());
+        self.tracked.len() - 1
+    }
+
+    /// Where the `index`th line of set `set` is now, or `None` if it was deleted.
+    pub fn tracked_line(&self, set: usize, index: usize) -> Option<usize> {
+        self.tracked.get(set)?.get(index).copied().flatten()
+    }
+
+    pub fn untrack_lines(&mut self) {
+        self.tracked.pop();
+    }
+
+    // Lines after an insert move down by the line breaks it adds. A
+    // whole-line insert, at the start of a line and ending in a line break,
+    // moves that line too.
+    fn track_insert(&mut self, line: usize, col: usize, text: &str) {
+        let added = text.matches('\n').count();
+        if added == 0 {
+            return;
+        }
+        let moves = |l: usize| l > line || (l == line && col == 0 && text.ends_with('\n'));
+        for l in self.tracked.iter_mut().flatten().flatten() {
+            if moves(*l) {
+                *l += added;
+            }
+        }
+    }
+
+    // Lines joined into the start line by a delete are gone, and later ones
+    // move up. Deleting whole lines, from a line start to a line start, takes
+    // the start line too and keeps the end line.
+    fn track_delete(&mut self, start: (usize, usize), end: (usize, usize)) {
+        let removed = end.0 - start.0;
+        if removed == 0 {
+            return;
+        }
+        let whole = start.1 == 0 && end.1 == 0;
+        for l in self.tracked.iter_mut().flatten() {
+            *l = match *l {
+                Some(line) if line > end.0 || (whole && line == end.0) => Some(line - removed),
+                Some(line) if line < start.0 || (!whole && line == start.0) => Some(line),
+                _ => None,
+            };
+        }
+    }
+
+    /// Reverts the most recent change and returns the cursor position it started at,
+    /// or `None` when there is nothing left to undo.
+    pub fn undo(&mut self) -> Result<Option<(usize, usize)>, BufferError> {
+        let Some(seq) = self.history.undo_target() else {
+            return Ok(None);
+        };
+        self.revert_change(seq).map(Some)
+    }
+
+    pub fn redo(&mut self) -> Result<Option<(usize, usize)>, BufferError> {
+        let Some(seq) = self.history.redo_target() else {
+            return Ok(None);
+        };
+        self.apply_change(seq).map(Some)
+    }
+
+    /// Moves to the text as it was `span` before the current state.
+    pub fn earlier(&mut self, span: Span) -> Result<Option<(usize, usize)>, BufferError> {
+        self.history.end();
+        let target = self.history.earlier(span);
+        self.goto_state(target)
+    }
+
+    /// Moves to the text as it was `span` after the current state.
+    pub fn later(&mut self, span: Span) -> Result<Option<(usize, usize)>, BufferError> {
+        self.history.end();
+        let target = self.history.later(span);
+        self.goto_state(target)
+    }
+
+    pub fn undo_branches(&self) -> Vec<Branch> {
+        self.history.branches()
+    }
+
+    pub fn undo_seq(&self) -> usize {
+        self.history.current_seq()
+    }
+
+    /// Walks the undo tree to state `target`, undoing back to the common
+    /// ancestor and redoing down the other branch.
+    fn goto_state(&mut self, target: usize) -> Result<Option<(usize, usize)>, BufferError> {
+        if target == self.history.current_seq() {
+            return Ok(None);
+        }
+        let (revert, apply) = self.history.path_to(target);
+        let mut cursor = None;
+        for seq in revert {
+            cursor = Some(self.revert_change(seq)?);
+        }
+        for seq in apply {
+            cursor = Some(self.apply_change(seq)?);
+        }
+        Ok(cursor)
+    }
+
+    fn revert_change(&mut self, seq: usize) -> Result<(usize, usize), BufferError> {
+        let change = self.history.change(seq).clone();
+        for edit in change.edits.iter().rev() {
+            match edit {
+                Edit::Insert { line, col, .. } => {
+                    self.raw_delete((*line, *col), edit.end())?;
+                }
+                Edit::Delete { line, col, text } => {
+                    self.raw_insert(*line, *col, text)?;
+                }
+            }
+        }
+        debug!("Undid change #{}", seq);
+        self.history.undone(seq);
+        self.update_modified();
+        Ok(self.grapheme_pos(change.cursor))
+    }
+
+    fn update_modified(&mut self) {
+        self.modified = self.history.current_seq() != self.saved_seq || (self.line_ending, self.encoding) != self.saved_format;
+    }
+
+    fn apply_change(&mut self, seq: usize) -> Result<(usize, usize), BufferError> {
+        let change = self.history.change(seq).clone();
+        for edit in &change.edits {
+            match edit {
+                Edit::Insert { line, col, text } => {
+                    self.raw_insert(*line, *col, text)?;
+                }
+                Edit::Delete { line, col, .. } => {
+                    self.raw_delete((*line, *col), edit.end())?;
+                }
+            }
+        }
+        debug!("Redid change #{}", seq);
+        self.history.redone(seq);
+        self.update_modified();
+        Ok(self.grapheme_pos(change.cursor))
+    }
+
+    /// Length of line `index` in grapheme clusters.
     pub fn line_length(&self, index: usize) -> Result<usize, BufferError> {
-        self.get_line(index).map(|line| line.len())
+        self.get_line(index).map(|line| text::grapheme_count(&line))
     }
 
     pub fn display_name(&self) -> String {
@@ -94,59 +633,256 @@ impl Buffer {
     }
 
     pub fn join_with_previous_line(&mut self, line_index: usize) -> Result<usize, BufferError> {
-        if line_index == 0 {
+        if line_index == 0 || line_index >= self.len() {
             return Err(BufferError::InvalidLineIndex(line_index));
         }
 
-        let current_line = self.lines.remove(line_index);
-        let previous_length = {
-            let previous_line = self.get_line_mut(line_index - 1)?;
-            let len = previous_line.len();
-            previous_line.push_str(&current_line);
-            len
-        };
-        self.modified = true;
+        let previous_length = self.line_length(line_index - 1)?;
+        self.delete_text((line_index - 1, previous_length), (line_index, 0))?;
         Ok(previous_length)
     }
 
-    pub fn delete_line(&mut self, index: usize) -> Result<(), BufferError> {
-        if self.lines.is_empty() {
-            return Err(BufferError::InvalidLineIndex(index));
+    /// Removes lines `first..=last` and returns them, each ending in a newline.
+    pub fn delete_lines(&mut self, first: usize, last: usize) -> Result<String, BufferError> {
+        if last >= self.len() || first > last {
+            return Err(BufferError::InvalidLineIndex(last));
         }
-        if self.lines.len() == 1 {
+        let len = self.line_length(last)?;
+        if first == 0 && last + 1 == self.len() {
             // keep a single empty line
-            self.lines[0].clear();
-            self.modified = true;
-            return Ok(());
+            return Ok(self.delete_text((0, 0), (last, len))? + "\n");
         }
-        if index >= self.lines.len() {
-            return Err(BufferError::InvalidLineIndex(index));
+        if last + 1 < self.len() {
+            self.delete_text((first, 0), (last + 1, 0))
+        } else {
+            // last line: take the newline before it instead
+            let previous_length = self.line_length(first - 1)?;
+            let removed = self.delete_text((first - 1, previous_length), (last, len))?;
+            Ok(removed[1..].to_string() + "\n")
         }
-        self.lines.remove(index);
-        self.modified = true;
+    }
+
+    /// The text between `start` (inclusive) and `end` (exclusive).
+    pub fn text_range(&self, start: (usize, usize), end: (usize, usize)) -> Result<String, BufferError> {
+        let from = self.char_index(start.0, self.byte_col(start.0, start.1)?)?;
+        let to = self.char_index(end.0, self.byte_col(end.0, end.1)?)?;
+        Ok(if to > from { self.text.slice(from..to).to_string() } else { String::new() })
+    }
+
+    /// Writes the file, refusing when another program changed it since it was
+    /// read; `force_save` overwrites regardless.
+    pub fn save(&mut self) -> Result<(), BufferError> {
+        if let DiskChange::Changed(_) = self.check_disk() {
+            let file_path = self.display_name();
+            warn!("Refusing to overwrite {}, it changed on disk", file_path);
+            return Err(BufferError::ChangedOnDisk(file_path));
+        }
+        self.force_save()
+    }
+
+    pub fn force_save(&mut self) -> Result<(), BufferError> {
+        let file_path = self.file.clone()
+            .ok_or_else(|| BufferError::FileNotFound("No file path set".to_string()))?;
+        
+        let (written, hash) = self.write_to(Path::new(&file_path))?;
+        debug!("Successfully saved {} bytes to {}", written, file_path);
+        self.disk_state = DiskState::read(&file_path, hash);
+        self.mark_saved(hash);
         Ok(())
     }
 
-    pub fn save(&self) -> Result<(), BufferError> {
+    /// Compares the file on disk with what was last read or written. Only
+    /// rereads the file when its size or mtime moved, and a touch that left
+    /// the contents alone is not reported.
+    pub fn check_disk(&mut self) -> DiskChange {
+        let (Some(path), Some(known)) = (&self.file, self.disk_state) else {
+            return DiskChange::Unchanged;
+        };
+        let meta = match std::fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return DiskChange::Deleted,
+            Err(_) => return DiskChange::Unchanged,
+        };
+        if meta.modified().ok() == known.modified && meta.len() == known.len {
+            return DiskChange::Unchanged;
+        }
+        let Ok(raw) = std::fs::read(path) else {
+            return DiskChange::Unchanged;
+        };
+        let state = DiskState { modified: meta.modified().ok(), len: meta.len(), hash: content_hash(&raw) };
+        if state.hash == known.hash {
+            self.disk_state = Some(state);
+            return DiskChange::Unchanged;
+        }
+        debug!("{} changed on disk", path);
+        DiskChange::Changed(state)
+    }
+
+    fn read_copy(&self, path: &Path) -> Result<(Buffer, u64), BufferError> {
+        let raw = std::fs::read(path)?;
+        let mut copy = Self::empty();
+        copy.load_bytes(&raw, Some(self.encoding))?;
+        Ok((copy, content_hash(&raw)))
+    }
+
+    fn read_disk_copy(&self) -> Result<(Buffer, u64), BufferError> {
         let file_path = self.file.as_ref()
             .ok_or_else(|| BufferError::FileNotFound("No file path set".to_string()))?;
-        
-        let content = self.lines.join("\n");
-        std::fs::write(file_path, &content)?;
-        debug!("Successfully saved {} bytes to {}", content.len(), file_path);
+        self.read_copy(Path::new(file_path))
+    }
+
+    // swaps in the text of `other` as a single undo step
+    fn replace_text(&mut self, other: &Buffer) -> Result<(), BufferError> {
+        let last = self.len() - 1;
+        let end = (last, self.line_length(last)?);
+        self.begin_undo_group((0, 0));
+        self.delete_text((0, 0), end)?;
+        self.insert_text(0, 0, &other.text.to_string())?;
+        self.end_undo_group();
+        self.line_ending = other.line_ending;
+        self.trailing_newline = other.trailing_newline;
+        self.bom = other.bom;
+        Ok(())
+    }
+
+    /// Replaces the text with the file's current contents. The reload is a
+    /// single undo step, so the discarded text can be brought back with `u`.
+    pub fn reload(&mut self) -> Result<(), BufferError> {
+        let (fresh, hash) = self.read_disk_copy()?;
+        info!("Reloading {} from disk", self.display_name());
+        self.replace_text(&fresh)?;
+        self.saved_seq = self.history.current_seq();
+        self.saved_format = (self.line_ending, self.encoding);
+        self.modified = false;
+        self.disk_state = self.file.as_deref().and_then(|path| DiskState::read(path, hash));
         Ok(())
     }
 
+    /// Unified diff from the file on disk to the buffer.
+    pub fn diff_with_disk(&self) -> Result<Vec<String>, BufferError> {
+        let (disk, _) = self.read_disk_copy()?;
+        let name = self.display_name();
+        diff_buffers(&disk, self, &format!("{} (on disk)", name), &format!("{} (buffer)", name))
+    }
+
+    /// Unified diff from the buffer to the recovery file found on open.
+    pub fn diff_with_recovery(&self) -> Result<Vec<String>, BufferError> {
+        let path = self.recovery.clone()
+            .ok_or_else(|| BufferError::FileNotFound("No recovery file".to_string()))?;
+        let (recovered, _) = self.read_copy(&path)?;
+        diff_buffers(self, &recovered, &self.display_name(), &path.to_string_lossy())
+    }
+
+    /// Loads the recovery file as one undoable change and deletes it; the
+    /// buffer is left modified so the recovered text still has to be saved.
+    pub fn restore_recovery(&mut self) -> Result<(), BufferError> {
+        let path = self.recovery.clone()
+            .ok_or_else(|| BufferError::FileNotFound("No recovery file".to_string()))?;
+        let (recovered, _) = self.read_copy(&path)?;
+        info!("Restoring {} from {:?}", self.display_name(), path);
+        self.replace_text(&recovered)?;
+        self.discard_recovery()
+    }
+
+    pub fn discard_recovery(&mut self) -> Result<(), BufferError> {
+        if let Some(path) = self.recovery.take()
+            && self.swap_file.as_ref() != Some(&path)
+        {
+            std::fs::remove_file(&path)?;
+        }
+        // an orphaned swap file is simply overwritten by ours
+        self.swap_dirty = true;
+        Ok(())
+    }
+
+    /// Keeps the swap file in step with the buffer: rewritten when there are
+    /// unsaved edits it does not have yet, removed once there are none. Does
+    /// nothing while a recovery file is waiting to be dealt with.
+    pub fn update_swap(&mut self) {
+        let Some(swap_file) = &self.swap_file else { return };
+        if self.recovery.is_some() {
+            return;
+        }
+        if !self.modified {
+            if swap_file.exists() {
+                debug!("Removing swap file {:?}", swap_file);
+                if let Err(e) = std::fs::remove_file(swap_file) {
+                    warn!("Could not remove swap file {:?}: {}", swap_file, e);
+                }
+            }
+            return;
+        }
+        if !self.swap_dirty {
+            return;
+        }
+        match self.write_to(swap_file) {
+            Ok((written, _)) => {
+                debug!("Wrote {} bytes to swap file {:?}", written, swap_file);
+                self.swap_dirty = false;
+            }
+            Err(e) => warn!("Could not write swap file {:?}: {}", swap_file, e),
+        }
+    }
+
+    /// Streams the rope to `path` chunk by chunk, returning the byte count and
+    /// content hash of what was written. The file is replaced atomically.
+    fn write_to(&self, path: &Path) -> Result<(usize, u64), BufferError> {
+        // encoded before touching the file, which is left alone when the
+        // text does not fit the encoding
+        let pieces = self.encoded()?;
+        fileio::write_atomic(path, |file| {
+            let mut writer = HashingWriter { inner: BufWriter::new(file), hash: HASH_SEED, written: 0 };
+            for piece in &pieces {
+                writer.write_all(piece)?;
+            }
+            writer.flush()?;
+            Ok((writer.written, writer.hash))
+        })
+    }
+
+    /// The text in the encoding, BOM, line endings and final newline it was
+    /// read with, in pieces that borrow from the rope where they can.
+    fn encoded(&self) -> Result<Vec<Cow<'_, [u8]>>, BufferError> {
+        let mut pieces = Vec::new();
+        if self.bom {
+            pieces.push(Cow::Borrowed(self.encoding.bom()));
+        }
+        let line_ending = self.encoding.encode(self.line_ending.as_str())?;
+        for chunk in self.text.chunks() {
+            match self.line_ending {
+                LineEnding::Unix => pieces.push(self.encoding.encode(chunk)?),
+                LineEnding::Dos => {
+                    for (i, piece) in chunk.split('\n').enumerate() {
+                        if i > 0 {
+                            pieces.push(line_ending.clone());
+                        }
+                        pieces.push(self.encoding.encode(piece)?);
+                    }
+                }
+            }
+        }
+        if self.trailing_newline {
+            pieces.push(line_ending);
+        }
+        Ok(pieces)
+    }
+
+    fn mark_saved(&mut self, hash: u64) {
+        self.history.end();
+        self.saved_seq = self.history.current_seq();
+        self.saved_format = (self.line_ending, self.encoding);
+        self.modified = false;
+        if let Some(path) = &self.undo_file
+            && let Err(e) = self.history.save_to(path, hash)
+        {
+            warn!("Failed to write undo file {:?}: {}", path, e);
+        }
+    }
+
     pub fn save_as(&mut self, file_path: String) -> Result<(), BufferError> {
         info!("Saving as: {}", file_path);
         if std::path::Path::new(&file_path).exists() {
             debug!("File exists, overwriting");
-            let content = self.lines.join("\n");
-            std::fs::write(&file_path, &content)?;
-            debug!("Successfully saved {} bytes", content.len());
-            self.file = Some(file_path);
-            self.modified = false;
-            Ok(())
         } else {
             let parent = std::path::Path::new(&file_path)
                 .parent()
@@ -157,13 +893,21 @@ impl Buffer {
             
             debug!("Creating directory structure: {:?}", parent);
             std::fs::create_dir_all(parent)?;
-            let content = self.lines.join("\n");
-            std::fs::write(&file_path, &content)?;
-            debug!("Successfully saved {} bytes", content.len());
-            self.file = Some(file_path);
-            self.modified = false;
-            Ok(())
         }
+        let (written, hash) = self.write_to(Path::new(&file_path))?;
+        debug!("Successfully saved {} bytes", written);
+        self.disk_state = DiskState::read(&file_path, hash);
+        self.undo_file = undo_file_for(&file_path);
+        self.file = Some(file_path);
+        self.mark_saved(hash);
+        Ok(())
+    }
+
+    /// Writes the buffer to `path` without making it the buffer's file, like `:w path`.
+    pub fn write_copy(&self, path: &str) -> Result<usize, BufferError> {
+        let (written, _) = self.write_to(Path::new(path))?;
+        debug!("Wrote copy of {} bytes to {}", written, path);
+        Ok(written)
     }
 
     /// Attempts to save any modified changes to a recovery file during a panic
@@ -174,15 +918,416 @@ impl Buffer {
         }
 
         let recovery_path = match &self.file {
-            Some(path) => format!("{}.recovery", path),
+            Some(path) => recovery_file_for(path),
             None => ".unnamed.recovery".to_string(),
         };
 
-        let content = self.lines.join("\n");
-        if let Err(e) = std::fs::write(&recovery_path, &content) {
-            error!("Failed to save recovery file: {}", e);
-        } else {
-            debug!("Recovery file saved: {} ({} bytes)", recovery_path, content.len());
+        match self.write_to(Path::new(&recovery_path)) {
+            Ok((written, _)) => debug!("Recovery file saved: {} ({} bytes)", recovery_path, written),
+            Err(e) => error!("Failed to save recovery file: {}", e),
         }
     }
 }
+
+impl Drop for Buffer {
+    // a clean exit leaves no swap file behind; the lock goes with `self.lock`
+    fn drop(&mut self) {
+        if self.lock.is_some()
+            && let Some(swap_file) = &self.swap_file
+            && swap_file.exists()
+            && let Err(e) = std::fs::remove_file(swap_file)
+        {
+            warn!("Could not remove swap file {:?}: {}", swap_file, e);
+        }
+    }
+}
+
+fn diff_buffers(old: &Buffer, new: &Buffer, old_name: &str, new_name: &str) -> Result<Vec<String>, BufferError> {
+    let old_lines: Vec<Cow<str>> = (0..old.len()).map(|i| old.get_line(i)).collect::<Result<_, _>>()?;
+    let new_lines: Vec<Cow<str>> = (0..new.len()).map(|i| new.get_line(i)).collect::<Result<_, _>>()?;
+    let old_lines: Vec<&str> = old_lines.iter().map(|l| l.as_ref()).collect();
+    let new_lines: Vec<&str> = new_lines.iter().map(|l| l.as_ref()).collect();
+    Ok(diff::unified(&old_lines, &new_lines, old_name, new_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(lines: &[&str]) -> Buffer {
+        let mut buffer = Buffer::from_file(None, None).unwrap();
+        buffer.text = Rope::from_str(&lines.join("\n"));
+        buffer
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        (0..buffer.len()).map(|i| buffer.get_line(i).unwrap().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_insert_and_delete_multiline_text() {
+        let mut buffer = buffer_with(&["hello world"]);
+        assert_eq!(buffer.insert_text(0, 5, ",\nbig\n").unwrap(), (2, 0));
+        assert_eq!(lines(&buffer), vec!["hello,", "big", " world"]);
+
+        let removed = buffer.delete_text((0, 5), (2, 0)).unwrap();
+        assert_eq!(removed, ",\nbig\n");
+        assert_eq!(lines(&buffer), vec!["hello world"]);
+    }
+
+    #[test]
+    fn test_undo_redo_delete_line() {
+        let mut buffer = buffer_with(&["one", "two", "three"]);
+        buffer.delete_lines(2, 2).unwrap();
+        buffer.delete_lines(0, 0).unwrap();
+        assert_eq!(lines(&buffer), vec!["two"]);
+
+        assert_eq!(buffer.undo().unwrap(), Some((0, 0)));
+        assert_eq!(lines(&buffer), vec!["one", "two"]);
+        assert_eq!(buffer.undo().unwrap(), Some((1, 3)));
+        assert_eq!(lines(&buffer), vec!["one", "two", "three"]);
+        assert!(!buffer.modified);
+        assert_eq!(buffer.undo().unwrap(), None);
+
+        buffer.redo().unwrap();
+        buffer.redo().unwrap();
+        assert_eq!(lines(&buffer), vec!["two"]);
+        assert!(buffer.modified);
+        assert_eq!(buffer.redo().unwrap(), None);
+    }
+
+    #[test]
+    fn test_tracked_lines_follow_edits() {
+        let mut buffer = buffer_with(&["a", "b", "c", "d", "e"]);
+        let set = buffer.track_lines(&[1, 2, 4]);
+        buffer.begin_undo_block((0, 0));
+        // a line put above "b", one opened below "d", then "c" deleted
+        buffer.insert_text(1, 0, "x\n").unwrap();
+        buffer.insert_text(4, 1, "\ny").unwrap();
+        buffer.delete_lines(3, 3).unwrap();
+        buffer.end_undo_group();
+        assert_eq!(lines(&buffer), ["a", "x", "b", "d", "y", "e"]);
+        assert_eq!((0..3).map(|i| buffer.tracked_line(set, i)).collect::<Vec<_>>(), [Some(2), None, Some(5)]);
+        buffer.delete_text((2, 1), (5, 0)).unwrap();
+        assert_eq!((0..3).map(|i| buffer.tracked_line(set, i)).collect::<Vec<_>>(), [Some(2), None, None]);
+        buffer.untrack_lines();
+        assert_eq!(buffer.tracked_line(set, 0), None);
+
+        // the block is still one undo step
+        buffer.end_undo_block();
+        buffer.undo().unwrap();
+        assert_eq!(lines(&buffer), ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn test_undo_group_reverts_together() {
+        let mut buffer = buffer_with(&["ab"]);
+        buffer.begin_undo_group((0, 1));
+        buffer.insert_char(0, 1, 'x').unwrap();
+        buffer.split_line(0, 2).unwrap();
+        buffer.join_with_previous_line(1).unwrap();
+        buffer.remove_char(0, 0).unwrap();
+        buffer.end_undo_group();
+        assert_eq!(lines(&buffer), vec!["xb"]);
+
+        assert_eq!(buffer.undo().unwrap(), Some((0, 1)));
+        assert_eq!(lines(&buffer), vec!["ab"]);
+        buffer.redo().unwrap();
+        assert_eq!(lines(&buffer), vec!["xb"]);
+    }
+
+    #[test]
+    fn test_undo_tree_keeps_abandoned_branch() {
+        let mut buffer = buffer_with(&[""]);
+        buffer.insert_text(0, 0, "a").unwrap();
+        buffer.insert_text(0, 1, "b").unwrap();
+        buffer.undo().unwrap();
+        buffer.insert_text(0, 1, "c").unwrap();
+        assert_eq!(lines(&buffer), vec!["ac"]);
+        assert_eq!(buffer.undo_branches().len(), 2);
+
+        // walk over to the other branch and back to the original text
+        assert_eq!(buffer.goto_state(2).unwrap(), Some((0, 1)));
+        assert_eq!(lines(&buffer), vec!["ab"]);
+        buffer.earlier(Span::Seconds(60)).unwrap();
+        assert_eq!(lines(&buffer), vec![""]);
+        assert!(!buffer.modified);
+    }
+
+    #[test]
+    fn test_save_writes_undo_file_for_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "first").unwrap();
+        let undo_path = dir.path().join("undo");
+
+        let mut buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        buffer.undo_file = Some(undo_path.clone());
+        buffer.insert_text(0, 5, " line").unwrap();
+        buffer.save().unwrap();
+
+        let saved = std::fs::read(&path).unwrap();
+        let history = History::load_from(&undo_path, content_hash(&saved)).unwrap();
+        assert_eq!(history.current_seq(), 1);
+        assert!(History::load_from(&undo_path, content_hash(b"first")).is_none());
+    }
+
+    #[test]
+    fn test_grapheme_columns_with_multilingual_text() {
+        let mut buffer = buffer_with(&["café", "日本語", "hi 👨\u{200d}👩\u{200d}👧!"]);
+        assert_eq!(buffer.line_length(0).unwrap(), 4);
+        assert_eq!(buffer.line_length(2).unwrap(), 5);
+
+        // typing after a multi-byte character used to panic
+        assert_eq!(buffer.insert_char(0, 4, 's').unwrap(), 5);
+        assert_eq!(buffer.insert_char(1, 1, 'x').unwrap(), 2);
+        assert_eq!(lines(&buffer)[..2], ["cafés", "日x本語"]);
+
+        // a ZWJ family emoji is removed as a whole
+        assert_eq!(buffer.remove_char(2, 3).unwrap(), "👨\u{200d}👩\u{200d}👧");
+        assert_eq!(buffer.get_line(2).unwrap(), "hi !");
+
+        // combining accent joins the previous cluster
+        assert_eq!(buffer.insert_char(1, 2, '\u{301}').unwrap(), 2);
+        assert_eq!(buffer.line_length(1).unwrap(), 4);
+        assert!(buffer.remove_char(2, 4).is_err());
+
+        buffer.split_line(1, 2).unwrap();
+        assert_eq!(lines(&buffer)[1..3], ["日x\u{301}", "本語"]);
+        assert_eq!(buffer.join_with_previous_line(2).unwrap(), 2);
+        assert_eq!(buffer.undo().unwrap(), Some((1, 2)));
+    }
+
+    #[test]
+    fn test_external_change_blocks_save_until_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.txt");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Unchanged);
+
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        buffer.insert_text(0, 0, "> ").unwrap();
+        assert!(matches!(buffer.check_disk(), DiskChange::Changed(_)));
+        assert!(matches!(buffer.save(), Err(BufferError::ChangedOnDisk(_))));
+        assert_eq!(buffer.diff_with_disk().unwrap()[2..], ["@@ -1,3 +1,2 @@", "-one", "+> one", " two", "-three"]);
+
+        buffer.reload().unwrap();
+        assert_eq!(lines(&buffer), vec!["one", "two", "three"]);
+        assert!(!buffer.modified);
+        assert_eq!(buffer.check_disk(), DiskChange::Unchanged);
+        // the text from before the reload is one undo away
+        buffer.undo().unwrap();
+        assert_eq!(lines(&buffer), vec!["> one", "two"]);
+
+        std::fs::write(&path, "x\n").unwrap();
+        buffer.force_save().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "> one\ntwo\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(buffer.check_disk(), DiskChange::Deleted);
+    }
+
+    #[test]
+    fn test_swap_file_follows_unsaved_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let swap = dir.path().join("file.swp");
+        let mut buffer = buffer_with(&["text"]);
+        buffer.swap_file = Some(swap.clone());
+
+        buffer.update_swap();
+        assert!(!swap.exists());
+        buffer.insert_text(0, 4, "!").unwrap();
+        buffer.update_swap();
+        assert_eq!(std::fs::read_to_string(&swap).unwrap(), "text!\n");
+        buffer.undo().unwrap();
+        buffer.update_swap();
+        assert!(!swap.exists());
+    }
+
+    #[test]
+    fn test_swap_and_lock_live_in_state_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        TEST_STATE_DIR.set(Some(state.path().to_path_buf()));
+        let path = dir.path().join("locked.txt").to_string_lossy().to_string();
+        std::fs::write(&path, "saved\n").unwrap();
+        let mut buffer = Buffer::from_file(Some(path.clone()), None).unwrap();
+        let swap = buffer.swap_file.clone().unwrap();
+        assert!(swap.starts_with(state.path().join("swap")));
+        assert!(swap.with_extension("swp.lock").exists());
+        buffer.insert_text(0, 0, "> ").unwrap();
+        buffer.update_swap();
+        assert!(swap.exists());
+        drop(buffer);
+        assert!(!swap.with_extension("swp.lock").exists());
+
+        // a swap file with nobody holding its lock was left by a crash
+        std::fs::write(&swap, "crashed\n").unwrap();
+        let buffer = Buffer::from_file(Some(path), None).unwrap();
+        assert_eq!(buffer.recovery, Some(swap));
+    }
+
+    #[test]
+    fn test_restores_recovery_file_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crashed.txt").to_string_lossy().to_string();
+        std::fs::write(&path, "saved\n").unwrap();
+        let mut buffer = Buffer::from_file(Some(path.clone()), None).unwrap();
+        assert_eq!(buffer.recovery, None);
+        buffer.insert_text(0, 5, " and unsaved").unwrap();
+        buffer.try_save_recovery();
+        drop(buffer);
+
+        let mut buffer = Buffer::from_file(Some(path.clone()), None).unwrap();
+        let recovery = PathBuf::from(format!("{}.recovery", path));
+        assert_eq!(buffer.recovery.as_ref(), Some(&recovery));
+        assert_eq!(buffer.diff_with_recovery().unwrap()[3..], ["-saved", "+saved and unsaved"]);
+        buffer.restore_recovery().unwrap();
+        assert_eq!(lines(&buffer), vec!["saved and unsaved"]);
+        assert!(buffer.modified);
+        assert!(!recovery.exists());
+    }
+
+    fn round_trip(bytes: &[u8]) -> (Buffer, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, bytes).unwrap();
+        let mut buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        buffer.save().unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        (buffer, saved)
+    }
+
+    #[test]
+    fn test_line_endings_round_trip_exactly() {
+        let cases: [&[u8]; 7] = [
+            b"one\r\ntwo\r\n",
+            b"one\r\ntwo",
+            b"one\ntwo\n",
+            b"one\ntwo",
+            b"mixed\r\nendings\nhere\n",
+            b"\xEF\xBB\xBFbom\r\n",
+            b"",
+        ];
+        for case in cases {
+            let (_, saved) = round_trip(case);
+            assert_eq!(saved, case, "{:?}", String::from_utf8_lossy(case));
+        }
+    }
+
+    #[test]
+    fn test_detects_file_format() {
+        let (buffer, _) = round_trip(b"\xEF\xBB\xBFa\r\nb");
+        assert_eq!(buffer.line_ending, LineEnding::Dos);
+        assert!(buffer.bom);
+        assert!(!buffer.trailing_newline);
+        assert_eq!(lines(&buffer), vec!["a", "b"]);
+
+        // a lone LF makes the file unix and keeps the other CR as text
+        let (buffer, _) = round_trip(b"a\r\nb\n");
+        assert_eq!(buffer.line_ending, LineEnding::Unix);
+        assert_eq!(lines(&buffer), vec!["a\r", "b"]);
+    }
+
+    #[test]
+    fn test_set_line_ending_converts_on_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        buffer.set_line_ending(LineEnding::Dos);
+        assert!(buffer.modified);
+        // undoing edits made since leaves the conversion to be written
+        buffer.delete_lines(1, 1).unwrap();
+        buffer.undo().unwrap();
+        assert!(buffer.modified);
+        buffer.set_line_ending(LineEnding::Unix);
+        assert!(!buffer.modified);
+        buffer.set_line_ending(LineEnding::Dos);
+        buffer.save().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\r\ntwo\r\n");
+        assert!(!buffer.modified);
+    }
+
+    #[test]
+    fn test_legacy_encodings_round_trip() {
+        let cases: [&[u8]; 3] = [
+            b"[section]\r\nname=Jos\xe9\r\n",
+            b"\xFF\xFEk\x00=\x00\xe9\x00\r\x00\n\x00",
+            b"\x93\xfa\x96\x7b\x8c\xea\x82\xcc\x83\x65\x83\x4c\x83\x58\x83\x67\n",
+        ];
+        for case in cases {
+            let (_, saved) = round_trip(case);
+            assert_eq!(saved, case);
+        }
+        let (buffer, _) = round_trip(cases[0]);
+        assert_eq!(buffer.encoding, Encoding::Latin1);
+        assert_eq!(buffer.get_line(1).unwrap(), "name=José");
+    }
+
+    #[test]
+    fn test_explicit_encoding_and_conversion() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, b"caf\xc3\xa9").unwrap();
+        let name = Some(path.to_string_lossy().to_string());
+
+        // read the UTF-8 bytes as Latin-1 on purpose
+        let buffer = Buffer::from_file(name.clone(), Some(Encoding::Latin1)).unwrap();
+        assert_eq!(buffer.get_line(0).unwrap(), "cafÃ©");
+        assert!(Buffer::from_file(name.clone(), Some(Encoding::Utf16Be)).is_err());
+
+        let mut buffer = Buffer::from_file(name, None).unwrap();
+        buffer.set_encoding(Encoding::Latin1);
+        buffer.save().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9");
+
+        // unencodable text is refused without truncating the file
+        buffer.insert_text(0, 4, " €").unwrap();
+        assert!(matches!(buffer.save(), Err(BufferError::Encoding(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9");
+    }
+
+    // Run with `cargo test --release bench_ -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_million_line_edits() {
+        use std::time::Instant;
+
+        const LINES: usize = 1_000_000;
+        const EDITS: usize = 2_000;
+        let source: Vec<String> = (0..LINES).map(|i| format!("log line {} with some payload", i)).collect();
+
+        // the previous storage: one String per line in a Vec
+        let mut naive = source.clone();
+        let started = Instant::now();
+        for i in 0..EDITS {
+            let at = LINES / 2 + i;
+            let tail = naive[at].split_off(4);
+            naive.insert(at + 1, tail);
+            let removed = naive.remove(at + 1);
+            naive[at].push_str(&removed);
+            naive.remove(at);
+            naive.insert(at, source[at].clone());
+        }
+        let _ = naive.join("\n");
+        let naive_time = started.elapsed();
+
+        let mut buffer = buffer_with(&[]);
+        buffer.text = Rope::from_str(&source.join("\n"));
+        let started = Instant::now();
+        for i in 0..EDITS {
+            let at = LINES / 2 + i;
+            buffer.split_line(at, 4).unwrap();
+            buffer.join_with_previous_line(at + 1).unwrap();
+            buffer.delete_lines(at, at).unwrap();
+            buffer.insert_text(at, 0, &format!("{}\n", source[at])).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        buffer.write_to(&dir.path().join("out.log")).unwrap();
+        let rope_time = started.elapsed();
+
+        assert_eq!(buffer.len(), LINES);
+        assert!(rope_time < naive_time, "rope {:?}, Vec<String> {:?}", rope_time, naive_time);
+    }
+}
@@ -0,0 +1,161 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use log::{debug, warn};
+
+/// The `+` register is the system clipboard, `*` the primary selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selection {
+    Clipboard,
+    Primary,
+}
+
+// A program pair that reads and writes the selections, usable when the
+// environment variable it needs is set and it is on PATH.
+struct Tool {
+    needs: Option<&'static str>,
+    copy: [&'static [&'static str]; 2],
+    paste: [&'static [&'static str]; 2],
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        needs: Some("WAYLAND_DISPLAY"),
+        copy: [&["wl-copy"], &["wl-copy", "--primary"]],
+        paste: [&["wl-paste", "--no-newline"], &["wl-paste", "--no-newline", "--primary"]],
+    },
+    Tool {
+        needs: Some("DISPLAY"),
+        copy: [&["xclip", "-selection", "clipboard", "-in"], &["xclip", "-selection", "primary", "-in"]],
+        paste: [&["xclip", "-selection", "clipboard", "-out"], &["xclip", "-selection", "primary", "-out"]],
+    },
+    Tool {
+        needs: Some("DISPLAY"),
+        copy: [&["xsel", "--clipboard", "--input"], &["xsel", "--primary", "--input"]],
+        paste: [&["xsel", "--clipboard", "--output"], &["xsel", "--primary", "--output"]],
+    },
+    Tool {
+        needs: None,
+        copy: [&["pbcopy"], &["pbcopy"]],
+        paste: [&["pbpaste"], &["pbpaste"]],
+    },
+];
+
+impl Selection {
+    fn index(self) -> usize {
+        match self {
+            Selection::Clipboard => 0,
+            Selection::Primary => 1,
+        }
+    }
+}
+
+/// Copies go to the terminal as an OSC 52 sequence, which also works over
+/// ssh, and to a clipboard program when one is installed, since not every
+/// terminal honours OSC 52. Pasting needs the program: terminals rarely
+/// let OSC 52 read the clipboard. The default does neither, for tests.
+#[derive(Default)]
+pub struct Clipboard {
+    osc52: bool,
+    tool: Option<&'static Tool>,
+}
+
+impl Clipboard {
+    pub fn detect() -> Self {
+        let tool = TOOLS.iter().find(|tool| {
+            tool.needs.is_none_or(|var| env::var_os(var).is_some()) && on_path(tool.copy[0][0])
+        });
+        debug!("Clipboard program: {:?}", tool.map(|tool| tool.copy[0][0]));
+        Self { osc52: true, tool }
+    }
+
+    pub fn copy(&self, selection: Selection, text: &str) {
+        if self.osc52 {
+            let mut stdout = io::stdout();
+            if let Err(e) = stdout.write_all(osc52(selection, text).as_bytes()).and_then(|()| stdout.flush()) {
+                warn!("Could not send OSC 52: {}", e);
+            }
+        }
+        if let Some(tool) = self.tool {
+            let args = tool.copy[selection.index()];
+            if let Err(e) = run_copy(args, text) {
+                warn!("Could not run {}: {}", args[0], e);
+            }
+        }
+    }
+
+    /// The selection's text, or None when there is no program to read it.
+    pub fn paste(&self, selection: Selection) -> Option<String> {
+        let args = self.tool?.paste[selection.index()];
+        let output = Command::new(args[0]).args(&args[1..]).stderr(Stdio::null()).output();
+        match output {
+            Ok(output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+            Ok(output) => {
+                warn!("{} failed: {}", args[0], output.status);
+                None
+            }
+            Err(e) => {
+                warn!("Could not run {}: {}", args[0], e);
+                None
+            }
+        }
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+fn run_copy(args: &[&str], text: &str) -> io::Result<()> {
+    // the programs keep running in the background to own the selection
+    let mut child = Command::new(args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn osc52(selection: Selection, text: &str) -> String {
+    let target = match selection {
+        Selection::Clipboard => 'c',
+        Selection::Primary => 'p',
+    };
+    format!("\x1b]52;{};{}\x07", target, base64(text.as_bytes()))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(osc52(Selection::Primary, "héllo\n"), "\x1b]52;p;aMOpbGxvCg==\x07");
+    }
+}
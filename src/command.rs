@@ -0,0 +1,402 @@
+use thiserror::Error;
+
+use crate::text;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Not an editor command: {0}")]
+    Unknown(String),
+    #[error("No file name")]
+    NoFileName,
+    #[error("Trailing characters: {0}")]
+    TrailingCharacters(String),
+    #[error("No range allowed")]
+    NoRange,
+    #[error("Invalid range")]
+    InvalidRange,
+    #[error("Invalid address")]
+    InvalidAddress,
+    #[error("Mark not set: {0}")]
+    MarkNotSet(char),
+    #[error("Positive count required")]
+    ZeroCount,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandKind {
+    Write,
+    Quit,
+    QuitAll,
+    WriteQuit,
+    Exit,
+    Edit,
+    SaveAs,
+    NoHighlight,
+    Substitute,
+    Delete,
+    Move,
+    Copy,
+    Normal,
+    Global,
+    VGlobal,
+    Set,
+    ListBuffers,
+    Buffer,
+    NextBuffer,
+    PreviousBuffer,
+    DeleteBuffer,
+    Split,
+    VSplit,
+    Close,
+    Only,
+    Resize,
+    Vertical,
+    TabNew,
+    TabClose,
+    TabNext,
+    TabPrevious,
+    Earlier,
+    Later,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Arg {
+    None,
+    Optional,
+    Required,
+    /// Everything after the name as it is, like `/a/b/g` after `:s`.
+    Raw,
+    /// Optional, and a number may follow the name without a space, as in `:b2`.
+    Buffer,
+}
+
+// Full name, shortest accepted abbreviation, argument, whether a range is
+// accepted, as in `:h :write`.
+const COMMANDS: &[(&str, usize, CommandKind, Arg, bool)] = &[
+    ("write", 1, CommandKind::Write, Arg::Optional, false),
+    ("quit", 1, CommandKind::Quit, Arg::None, false),
+    ("qall", 2, CommandKind::QuitAll, Arg::None, false),
+    ("quitall", 5, CommandKind::QuitAll, Arg::None, false),
+    ("wq", 2, CommandKind::WriteQuit, Arg::Optional, false),
+    ("xit", 1, CommandKind::Exit, Arg::Optional, false),
+    ("exit", 3, CommandKind::Exit, Arg::Optional, false),
+    ("edit", 1, CommandKind::Edit, Arg::Optional, false),
+    ("saveas", 3, CommandKind::SaveAs, Arg::Required, false),
+    ("nohlsearch", 3, CommandKind::NoHighlight, Arg::None, false),
+    ("substitute", 1, CommandKind::Substitute, Arg::Raw, true),
+    ("delete", 1, CommandKind::Delete, Arg::Optional, true),
+    ("move", 1, CommandKind::Move, Arg::Raw, true),
+    ("copy", 2, CommandKind::Copy, Arg::Raw, true),
+    ("t", 1, CommandKind::Copy, Arg::Raw, true),
+    ("normal", 4, CommandKind::Normal, Arg::Raw, true),
+    ("global", 1, CommandKind::Global, Arg::Raw, true),
+    ("vglobal", 1, CommandKind::VGlobal, Arg::Raw, true),
+    ("set", 2, CommandKind::Set, Arg::Optional, false),
+    ("ls", 2, CommandKind::ListBuffers, Arg::None, false),
+    ("buffers", 7, CommandKind::ListBuffers, Arg::None, false),
+    ("files", 5, CommandKind::ListBuffers, Arg::None, false),
+    ("buffer", 1, CommandKind::Buffer, Arg::Buffer, false),
+    ("bnext", 2, CommandKind::NextBuffer, Arg::None, false),
+    ("bprevious", 2, CommandKind::PreviousBuffer, Arg::None, false),
+    ("bNext", 2, CommandKind::PreviousBuffer, Arg::None, false),
+    ("bdelete", 2, CommandKind::DeleteBuffer, Arg::Buffer, false),
+    ("split", 2, CommandKind::Split, Arg::Optional, false),
+    ("vsplit", 2, CommandKind::VSplit, Arg::Optional, false),
+    ("close", 3, CommandKind::Close, Arg::None, false),
+    ("only", 2, CommandKind::Only, Arg::None, false),
+    ("resize", 3, CommandKind::Resize, Arg::Optional, false),
+    ("vertical", 4, CommandKind::Vertical, Arg::Raw, false),
+    ("tabnext", 4, CommandKind::TabNext, Arg::None, false),
+    ("tabnew", 6, CommandKind::TabNew, Arg::Optional, false),
+    ("tabclose", 4, CommandKind::TabClose, Arg::None, false),
+    ("tabprevious", 4, CommandKind::TabPrevious, Arg::None, false),
+    ("tabNext", 4, CommandKind::TabPrevious, Arg::None, false),
+    ("earlier", 2, CommandKind::Earlier, Arg::Optional, false),
+    ("later", 3, CommandKind::Later, Arg::Optional, false),
+];
+
+/// Which line an address in a range names, before it is looked up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineSpec {
+    /// A line number, counted from 1.
+    Number(usize),
+    /// `.`, the cursor line.
+    Current,
+    /// `$`
+    Last,
+    /// `'a`, or `'<` and `'>` for the last Visual selection.
+    Mark(char),
+}
+
+/// One end of a range: a line and lines to add, as in `.+3` or `'a-1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub line: LineSpec,
+    pub offset: isize,
+}
+
+/// The lines a command works on, `:h cmdline-ranges`. `%` is `1,$`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: Address,
+    pub end: Address,
+}
+
+/// A parsed Ex command line such as `w! other.txt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub range: Option<LineRange>,
+    pub kind: CommandKind,
+    pub bang: bool,
+    pub arg: Option<String>,
+}
+
+fn parse_address(text: &str) -> Result<(Option<Address>, &str), CommandError> {
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (line, mut rest) = if digits > 0 {
+        (Some(LineSpec::Number(text[..digits].parse().map_err(|_| CommandError::InvalidRange)?)), &text[digits..])
+    } else if let Some(rest) = text.strip_prefix('.') {
+        (Some(LineSpec::Current), rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Some(LineSpec::Last), rest)
+    } else if let Some(rest) = text.strip_prefix('\'') {
+        let mark = rest.chars().next().ok_or(CommandError::InvalidRange)?;
+        (Some(LineSpec::Mark(mark)), &rest[mark.len_utf8()..])
+    } else {
+        (None, text)
+    };
+    let mut offset = 0;
+    let mut has_offset = false;
+    while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+        let after = &rest[1..];
+        let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+        // a lone `+` or `-` is one line
+        let n: isize = if digits == 0 { 1 } else { after[..digits].parse().map_err(|_| CommandError::InvalidRange)? };
+        offset += if sign == '-' { -n } else { n };
+        has_offset = true;
+        rest = &after[digits..];
+    }
+    let address = match (line, has_offset) {
+        (Some(line), _) => Some(Address { line, offset }),
+        (None, true) => Some(Address { line: LineSpec::Current, offset }),
+        (None, false) => None,
+    };
+    Ok((address, rest))
+}
+
+/// The single address `:m` and `:t` take, where line 0 is above the first.
+pub fn parse_target(text: &str) -> Result<Address, CommandError> {
+    match parse_address(text.trim())? {
+        (Some(address), "") => Ok(address),
+        _ => Err(CommandError::InvalidAddress),
+    }
+}
+
+/// The `[x] [count]` argument of `:d`: a register, then how many lines to
+/// delete from the last line of the range. A number alone is the count.
+pub fn parse_register_count(arg: Option<&str>) -> Result<(Option<char>, Option<usize>), CommandError> {
+    let arg = arg.unwrap_or_default().trim();
+    let (register, rest) = match arg.chars().next() {
+        Some(name) if !name.is_ascii_digit() => (Some(name), arg[name.len_utf8()..].trim_start()),
+        _ => (None, arg),
+    };
+    if rest.is_empty() {
+        return Ok((register, None));
+    }
+    match rest.parse::<usize>() {
+        Ok(0) => Err(CommandError::ZeroCount),
+        Ok(count) => Ok((register, Some(count))),
+        Err(_) => Err(CommandError::TrailingCharacters(rest.to_string())),
+    }
+}
+
+fn parse_range(text: &str) -> Result<(Option<LineRange>, &str), CommandError> {
+    if let Some(rest) = text.strip_prefix('%') {
+        let start = Address { line: LineSpec::Number(1), offset: 0 };
+        let end = Address { line: LineSpec::Last, offset: 0 };
+        return Ok((Some(LineRange { start, end }), rest));
+    }
+    let (start, rest) = parse_address(text)?;
+    let Some(rest) = rest.strip_prefix(',') else {
+        return Ok((start.map(|start| LineRange { start, end: start }), rest));
+    };
+    // a missing address on either side of the comma is the cursor line
+    let current = Address { line: LineSpec::Current, offset: 0 };
+    let (end, rest) = parse_address(rest)?;
+    Ok((Some(LineRange { start: start.unwrap_or(current), end: end.unwrap_or(current) }), rest))
+}
+
+pub fn parse(line: &str) -> Result<Command, CommandError> {
+    let line = line.trim_start_matches(|c: char| c == ':' || c.is_whitespace()).trim_end();
+    let (range, rest) = parse_range(line)?;
+    let rest = rest.trim_start();
+    let name_len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(name_len);
+    let &(_, _, kind, arg_kind, takes_range) = COMMANDS.iter()
+        .find(|(full, min, ..)| name.len() >= *min && full.starts_with(name))
+        .ok_or_else(|| CommandError::Unknown(line.to_string()))?;
+    if range.is_some() && !takes_range {
+        return Err(CommandError::NoRange);
+    }
+    if arg_kind == Arg::Raw {
+        let arg = Some(rest.trim_start()).filter(|arg| !arg.is_empty()).map(str::to_string);
+        return Ok(Command { range, kind, bang: false, arg });
+    }
+
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    // "w!x" is not "w! x"
+    let numbered = arg_kind == Arg::Buffer && rest.starts_with(|c: char| c.is_ascii_digit());
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) && !numbered {
+        return Err(CommandError::Unknown(line.to_string()));
+    }
+    let arg = Some(rest.trim()).filter(|arg| !arg.is_empty()).map(str::to_string);
+    match (arg_kind, &arg) {
+        (Arg::None, Some(arg)) => return Err(CommandError::TrailingCharacters(arg.clone())),
+        (Arg::Required, None) => return Err(CommandError::NoFileName),
+        _ => {}
+    }
+    Ok(Command { range, kind, bang, arg })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEdit {
+    Insert(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+}
+
+/// The text typed after `:`, with a cursor counted in grapheme clusters.
+#[derive(Clone, Debug, Default)]
+pub struct CommandLine {
+    pub text: String,
+    pub cursor: usize,
+}
+
+impl CommandLine {
+    /// A line holding `text`, with the cursor at its end.
+    pub fn with_text(text: &str) -> Self {
+        CommandLine { text: text.to_string(), cursor: text::grapheme_count(text) }
+    }
+
+    pub fn apply(&mut self, edit: LineEdit) {
+        let len = text::grapheme_count(&self.text);
+        let at = |col| text::byte_offset(&self.text, col).unwrap_or(self.text.len());
+        match edit {
+            LineEdit::Insert(c) => {
+                let byte = at(self.cursor);
+                self.text.insert(byte, c);
+                self.cursor = text::grapheme_col(&self.text, byte + c.len_utf8());
+            }
+            LineEdit::Backspace if self.cursor > 0 => {
+                self.text.replace_range(at(self.cursor - 1)..at(self.cursor), "");
+                self.cursor -= 1;
+            }
+            LineEdit::Delete if self.cursor < len => {
+                self.text.replace_range(at(self.cursor)..at(self.cursor + 1), "");
+            }
+            LineEdit::Left => self.cursor = self.cursor.saturating_sub(1),
+            LineEdit::Right => self.cursor = (self.cursor + 1).min(len),
+            LineEdit::Home => self.cursor = 0,
+            LineEdit::End => self.cursor = len,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(kind: CommandKind, bang: bool, arg: Option<&str>) -> Command {
+        Command { range: None, kind, bang, arg: arg.map(str::to_string) }
+    }
+
+    fn address(line: LineSpec, offset: isize) -> Address {
+        Address { line, offset }
+    }
+
+    #[test]
+    fn test_parse_names_bang_and_argument() {
+        assert_eq!(parse("w"), Ok(command(CommandKind::Write, false, None)));
+        assert_eq!(parse(":write  notes.txt "), Ok(command(CommandKind::Write, false, Some("notes.txt"))));
+        assert_eq!(parse("q!"), Ok(command(CommandKind::Quit, true, None)));
+        assert_eq!(parse("wq"), Ok(command(CommandKind::WriteQuit, false, None)));
+        assert_eq!(parse("x"), Ok(command(CommandKind::Exit, false, None)));
+        assert_eq!(parse("e! other.rs"), Ok(command(CommandKind::Edit, true, Some("other.rs"))));
+        assert_eq!(parse("sav a b.txt"), Ok(command(CommandKind::SaveAs, false, Some("a b.txt"))));
+        assert_eq!(parse("b2"), Ok(command(CommandKind::Buffer, false, Some("2"))));
+        assert_eq!(parse("b! notes"), Ok(command(CommandKind::Buffer, true, Some("notes"))));
+        assert_eq!(parse("bN"), Ok(command(CommandKind::PreviousBuffer, false, None)));
+        assert_eq!(parse("buffers"), Ok(command(CommandKind::ListBuffers, false, None)));
+        assert_eq!(parse("bd!3"), Ok(command(CommandKind::DeleteBuffer, true, Some("3"))));
+        assert_eq!(parse("se nohid"), Ok(command(CommandKind::Set, false, Some("nohid"))));
+        assert_eq!(parse("vs other.rs"), Ok(command(CommandKind::VSplit, false, Some("other.rs"))));
+        assert_eq!(parse("vert res +5"), Ok(command(CommandKind::Vertical, false, Some("res +5"))));
+        assert_eq!(parse("tabn"), Ok(command(CommandKind::TabNext, false, None)));
+        assert_eq!(parse("tabnew x"), Ok(command(CommandKind::TabNew, false, Some("x"))));
+        assert_eq!(parse("tabc!"), Ok(command(CommandKind::TabClose, true, None)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("sa x"), Err(CommandError::Unknown("sa x".to_string())));
+        assert_eq!(parse("wx"), Err(CommandError::Unknown("wx".to_string())));
+        assert_eq!(parse("saveas"), Err(CommandError::NoFileName));
+        assert_eq!(parse("q now"), Err(CommandError::TrailingCharacters("now".to_string())));
+        assert_eq!(parse("w!x"), Err(CommandError::Unknown("w!x".to_string())));
+        assert_eq!(parse("1,2w"), Err(CommandError::NoRange));
+        assert_eq!(parse("bx"), Err(CommandError::Unknown("bx".to_string())));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let sub = parse("%s/a/b/g").unwrap();
+        assert_eq!(sub.kind, CommandKind::Substitute);
+        assert_eq!(sub.arg.as_deref(), Some("/a/b/g"));
+        assert_eq!(sub.range.map(|r| (r.start, r.end)), Some((address(LineSpec::Number(1), 0), address(LineSpec::Last, 0))));
+
+        let range = |line| parse(line).unwrap().range.map(|r| (r.start, r.end));
+        assert_eq!(range("s"), None);
+        assert_eq!(range("'<,'>s/x/y/"), Some((address(LineSpec::Mark('<'), 0), address(LineSpec::Mark('>'), 0))));
+        assert_eq!(range(".,$-2 s/x/y/"), Some((address(LineSpec::Current, 0), address(LineSpec::Last, -2))));
+        assert_eq!(range("3s"), Some((address(LineSpec::Number(3), 0), address(LineSpec::Number(3), 0))));
+        assert_eq!(range(",+s"), Some((address(LineSpec::Current, 0), address(LineSpec::Current, 1))));
+        assert_eq!(range("'a+1-3,7s"), Some((address(LineSpec::Mark('a'), -2), address(LineSpec::Number(7), 0))));
+
+        let global = parse("g!/x/m0").unwrap();
+        assert_eq!((global.kind, global.arg.as_deref()), (CommandKind::Global, Some("!/x/m0")));
+        assert_eq!(parse("t.").unwrap().kind, CommandKind::Copy);
+        assert_eq!(parse_target(" $-1"), Ok(address(LineSpec::Last, -1)));
+        assert_eq!(parse_target("0"), Ok(address(LineSpec::Number(0), 0)));
+        assert_eq!(parse_target("1x"), Err(CommandError::InvalidAddress));
+
+        assert_eq!(parse_register_count(None), Ok((None, None)));
+        assert_eq!(parse_register_count(Some("3")), Ok((None, Some(3))));
+        assert_eq!(parse_register_count(Some("a 2")), Ok((Some('a'), Some(2))));
+        assert_eq!(parse_register_count(Some("_")), Ok((Some('_'), None)));
+        assert_eq!(parse_register_count(Some("0")), Err(CommandError::ZeroCount));
+        assert_eq!(parse_register_count(Some("ab")), Err(CommandError::TrailingCharacters("b".to_string())));
+    }
+
+    #[test]
+    fn test_line_editing_by_grapheme() {
+        let mut line = CommandLine::default();
+        for c in "e café".chars() {
+            line.apply(LineEdit::Insert(c));
+        }
+        line.apply(LineEdit::Left);
+        line.apply(LineEdit::Backspace);
+        line.apply(LineEdit::Insert('F'));
+        assert_eq!(line.text, "e caFé");
+        line.apply(LineEdit::Home);
+        line.apply(LineEdit::Delete);
+        assert_eq!((line.text.as_str(), line.cursor), (" caFé", 0));
+    }
+}
@@ -0,0 +1,169 @@
+// Line diffs (Myers' O(ND) algorithm) rendered in unified format.
+
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+fn diff_ops(old: &[&str], new: &[&str]) -> Vec<Op> {
+    // common prefix and suffix are cheap to strip and usually most of the file
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<Op> = (0..prefix).map(|i| Op::Equal(i, i)).collect();
+    ops.extend(myers(a, b).into_iter().map(|op| match op {
+        Op::Equal(i, j) => Op::Equal(i + prefix, j + prefix),
+        Op::Delete(i) => Op::Delete(i + prefix),
+        Op::Insert(j) => Op::Insert(j + prefix),
+    }));
+    ops.extend((0..suffix).map(|k| Op::Equal(old.len() - suffix + k, new.len() - suffix + k)));
+    ops
+}
+
+fn myers(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: for d in 0..=max as isize {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    // walk the trace backwards from (n, m) to recover the edit script
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let idx = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) { k + 1 } else { k - 1 };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            ops.push(Op::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(Op::Insert(prev_y as usize));
+            } else {
+                ops.push(Op::Delete(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Unified diff of `old` against `new`, with three lines of context.
+/// Empty when the two are identical.
+pub fn unified(old: &[&str], new: &[&str], old_name: &str, new_name: &str) -> Vec<String> {
+    let ops = diff_ops(old, new);
+    let changed: Vec<usize> = ops.iter().enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(..)))
+        .map(|(i, _)| i)
+        .collect();
+    if changed.is_empty() {
+        return Vec::new();
+    }
+
+    // group changes whose context overlaps into hunks of op indices
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in &changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = vec![format!("--- {}", old_name), format!("+++ {}", new_name)];
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| !matches!(op, Op::Insert(_))).count();
+        let new_len = hunk.iter().filter(|op| !matches!(op, Op::Delete(_))).count();
+        // 1-based start lines, counted from the ops before the hunk
+        let old_start = ops[..start].iter().filter(|op| !matches!(op, Op::Insert(_))).count() + 1;
+        let new_start = ops[..start].iter().filter(|op| !matches!(op, Op::Delete(_))).count() + 1;
+        // an empty side is numbered by the line before it, as GNU diff does
+        let old_start = if old_len == 0 { old_start - 1 } else { old_start };
+        let new_start = if new_len == 0 { new_start - 1 } else { new_start };
+        out.push(format!("@@ -{},{} +{},{} @@", old_start, old_len, new_start, new_len));
+        for op in hunk {
+            out.push(match *op {
+                Op::Equal(i, _) => format!(" {}", old[i]),
+                Op::Delete(i) => format!("-{}", old[i]),
+                Op::Insert(j) => format!("+{}", new[j]),
+            });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_has_no_diff() {
+        assert!(unified(&["a", "b"], &["a", "b"], "old", "new").is_empty());
+    }
+
+    #[test]
+    fn test_unified_hunks() {
+        let old: Vec<String> = (1..=12).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[1] = "two".to_string();
+        new.remove(9);
+        new.push("13".to_string());
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+
+        let diff = unified(&old, &new, "disk", "buffer");
+        assert_eq!(diff, vec![
+            "--- disk", "+++ buffer",
+            "@@ -1,5 +1,5 @@", " 1", "-2", "+two", " 3", " 4", " 5",
+            "@@ -7,6 +7,6 @@", " 7", " 8", " 9", "-10", " 11", " 12", "+13",
+        ]);
+    }
+
+    #[test]
+    fn test_diff_from_and_to_empty() {
+        assert_eq!(unified(&[], &["x"], "a", "b")[2..], ["@@ -0,0 +1,1 @@", "+x"]);
+        assert_eq!(unified(&["x", "y"], &[], "a", "b")[2..], ["@@ -1,2 +0,0 @@", "-x", "-y"]);
+    }
+}
@@ -1,17 +1,42 @@
 use anyhow::Result;
 use crossterm::event::{Event, KeyCode};
 use crossterm::style::Color;
-use crossterm::{terminal, cursor::MoveTo, style::{Print, SetForegroundColor, SetBackgroundColor, ResetColor}};
-use crossterm::QueueableCommand;
+use crossterm::terminal;
 use log::{debug, info, warn};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::io::Write;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Actions {
-    MoveUp,
-    MoveDown,
-    MoveLeft,
-    MoveRight,
+    Move(Motion, Option<usize>),
+    Operate(Operator, Target, Option<usize>),
+    Put { before: bool, count: Option<usize> },
+    /// `"x`: the register the next yank, delete or put uses.
+    SelectRegister(char),
+    /// Visual mode `iw`, `a(` and so on: selects the object.
+    SelectObject(TextObject, Option<usize>),
+    /// Visual mode `o`: moves the cursor to the other end of the selection.
+    SwapSelectionEnds,
+    /// Visual mode `I` and `A`; in a block, inserts on every line.
+    InsertSelection { append: bool },
+    /// `.`: replays the last change, with a new count if one is given.
+    Repeat(Option<usize>),
+    /// `q{reg}`: records the keys typed from now on into a register.
+    RecordMacro(char),
+    StopRecording,
+    /// `@{reg}`: types a register's keys again, count times; `@@` repeats the last one.
+    PlayMacro(char, Option<usize>),
+    /// `/` and `?`: opens the search prompt; the count picks the match.
+    StartSearch { forward: bool, count: Option<usize> },
+    /// Up and Down in the search prompt.
+    SearchHistory { older: bool },
+    ExecuteSearch,
+    /// `m{a-z}`: remembers the cursor position for ranges such as `:'a,.s`.
+    SetMark(char),
+    /// An answer to `:s///c`'s question about the match under the cursor.
+    ConfirmSubstitute(Confirm),
+    Insert(InsertAt),
     EnterMode(Mode),
     PrintChar(char),
     Backspace,
@@ -19,35 +44,248 @@ pub enum Actions {
     Save,
     SaveAs(String),
     DeleteLine,
+    Undo,
+    Redo,
+    Earlier(Span),
+    Later(Span),
+    UndoList,
+    Reload,
+    ForceSave,
+    ShowDiff,
+    Dismiss,
+    ScrollPager(isize),
+    ClosePager,
+    RestoreRecovery,
+    ShowRecoveryDiff,
+    DeleteRecovery,
+    Quit,
+    ForceQuit,
+    /// `:qa`, and `:qa!` with `force` to drop every unsaved change.
+    QuitAll { force: bool },
+    EditCommandLine(LineEdit),
+    ExecuteCommand,
+    WriteCopy(String),
+    Edit { path: Option<String>, force: bool },
+    Window(WindowCommand),
+    /// `gt` goes to the next tab page, or to page `count`; `gT` goes back one, or `count`.
+    SwitchTab { forward: bool, count: Option<usize> },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Mode {
     Normal,
     Insert,
+    Command,
+    Search,
+    Visual,
+    VisualLine,
+    VisualBlock,
 }
 
-pub fn handle_normal_event(ev: Event) -> Option<Actions> {
-    match ev {
-        Event::Key(key) => {
-            use crossterm::event::KeyModifiers;
-            match (key.code, key.modifiers) {
-                (KeyCode::Char('h'), KeyModifiers::NONE) => Some(Actions::MoveLeft),
-                (KeyCode::Char('j'), KeyModifiers::NONE) => Some(Actions::MoveDown),
-                (KeyCode::Char('k'), KeyModifiers::NONE) => Some(Actions::MoveUp),
-                (KeyCode::Char('l'), KeyModifiers::NONE) => Some(Actions::MoveRight),
-                (KeyCode::Char('i'), KeyModifiers::NONE) => Some(Actions::EnterMode(Mode::Insert)),
-                (KeyCode::Char('s'), KeyModifiers::CONTROL) => Some(Actions::Save),
-                (KeyCode::Char('S'), KeyModifiers::CONTROL) => {
-                    // For now, just save to a hardcoded path. We'll add proper UI for this later.
-                    Some(Actions::SaveAs("new_file.txt".to_string()))
-                },
-                (KeyCode::Char('d'), KeyModifiers::CONTROL) => Some(Actions::DeleteLine),
-                _ => None,
-            }
-        },
-        _ => None,
+impl Mode {
+    pub fn is_visual(&self) -> bool {
+        matches!(self, Mode::Visual | Mode::VisualLine | Mode::VisualBlock)
+    }
+}
+
+/// `Ctrl-W` commands and the Ex commands that do the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowCommand {
+    Split { vertical: bool },
+    /// `Ctrl-W h`, `j`, `k` and `l`, count windows away.
+    Focus { direction: Direction, count: usize },
+    /// `Ctrl-W w` and `W`: the next or previous window, or window `count`.
+    Cycle { forward: bool, count: Option<usize> },
+    Close { force: bool },
+    Only { force: bool },
+    /// Makes the window wider when `vertical`, or taller, by `delta` cells.
+    Resize { vertical: bool, delta: i32 },
+    /// `Ctrl-W _` and `|`: `size` rows or columns, or as many as there is room for.
+    SetSize { vertical: bool, size: Option<usize> },
+    Equalize,
+}
+
+/// Where `i`, `a`, `o` and friends start inserting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertAt {
+    Cursor,
+    AfterCursor,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove,
+}
+
+// motions, text objects and operators, shared by Normal and Visual mode
+fn bind_targets(keymap: &mut Keymap) {
+    for (keys, motion) in [
+        ("h", Motion::Left),
+        ("j", Motion::Down),
+        ("k", Motion::Up),
+        ("gj", Motion::ScreenLine { down: true }),
+        ("gk", Motion::ScreenLine { down: false }),
+        ("l", Motion::Right),
+        ("0", Motion::LineStart),
+        ("^", Motion::FirstNonBlank),
+        ("$", Motion::LineEnd),
+        ("w", Motion::WordForward { big: false }),
+        ("W", Motion::WordForward { big: true }),
+        ("b", Motion::WordBackward { big: false }),
+        ("B", Motion::WordBackward { big: true }),
+        ("e", Motion::WordEnd { big: false }),
+        ("E", Motion::WordEnd { big: true }),
+        ("gg", Motion::FirstLine),
+        ("G", Motion::LastLine),
+        ("}", Motion::ParagraphForward),
+        ("{", Motion::ParagraphBackward),
+        (";", Motion::RepeatFind { reverse: false }),
+        (",", Motion::RepeatFind { reverse: true }),
+        ("n", Motion::SearchNext { reverse: false }),
+        ("N", Motion::SearchNext { reverse: true }),
+        ("*", Motion::SearchWord { forward: true }),
+        ("#", Motion::SearchWord { forward: false }),
+        ("%", Motion::MatchBracket),
+    ] {
+        keymap.bind(keys, Binding::Motion(motion));
+    }
+    let finds: [(&str, MotionBuilder); 4] = [
+        ("f", |target| Motion::Find { target, forward: true, till: false }),
+        ("F", |target| Motion::Find { target, forward: false, till: false }),
+        ("t", |target| Motion::Find { target, forward: true, till: true }),
+        ("T", |target| Motion::Find { target, forward: false, till: true }),
+    ];
+    for (keys, make) in finds {
+        keymap.bind(keys, Binding::CharMotion(make));
+    }
+    let objects = [
+        ("w", ObjectKind::Word { big: false }),
+        ("W", ObjectKind::Word { big: true }),
+        ("s", ObjectKind::Sentence),
+        ("p", ObjectKind::Paragraph),
+        ("\"", ObjectKind::Quote('"')),
+        ("'", ObjectKind::Quote('\'')),
+        ("`", ObjectKind::Quote('`')),
+        ("(", ObjectKind::Bracket { open: '(', close: ')' }),
+        (")", ObjectKind::Bracket { open: '(', close: ')' }),
+        ("b", ObjectKind::Bracket { open: '(', close: ')' }),
+        ("[", ObjectKind::Bracket { open: '[', close: ']' }),
+        ("]", ObjectKind::Bracket { open: '[', close: ']' }),
+        ("{", ObjectKind::Bracket { open: '{', close: '}' }),
+        ("}", ObjectKind::Bracket { open: '{', close: '}' }),
+        ("B", ObjectKind::Bracket { open: '{', close: '}' }),
+        ("<", ObjectKind::Bracket { open: '<', close: '>' }),
+        (">", ObjectKind::Bracket { open: '<', close: '>' }),
+        ("t", ObjectKind::Tag),
+    ];
+    for (key, kind) in objects {
+        keymap.bind(&format!("i{}", key), Binding::Object(TextObject { kind, inner: true }));
+        keymap.bind(&format!("a{}", key), Binding::Object(TextObject { kind, inner: false }));
     }
+    for (keys, op) in [
+        ("d", Operator::Delete),
+        ("c", Operator::Change),
+        ("y", Operator::Yank),
+        (">", Operator::ShiftRight),
+        ("<", Operator::ShiftLeft),
+        ("gu", Operator::Lowercase),
+        ("gU", Operator::Uppercase),
+        ("=", Operator::Reindent),
+    ] {
+        keymap.bind(keys, Binding::Operator(op));
+    }
+}
+
+pub fn normal_keymap() -> Keymap {
+    let mut keymap = Keymap::default();
+    bind_targets(&mut keymap);
+    let actions: [(&str, ActionBuilder); 23] = [
+        ("i", |_| Actions::Insert(InsertAt::Cursor)),
+        ("a", |_| Actions::Insert(InsertAt::AfterCursor)),
+        ("I", |_| Actions::Insert(InsertAt::LineStart)),
+        ("A", |_| Actions::Insert(InsertAt::LineEnd)),
+        ("o", |_| Actions::Insert(InsertAt::LineBelow)),
+        ("O", |_| Actions::Insert(InsertAt::LineAbove)),
+        ("p", |count| Actions::Put { before: false, count }),
+        ("P", |count| Actions::Put { before: true, count }),
+        (".", Actions::Repeat),
+        (":", |_| Actions::EnterMode(Mode::Command)),
+        ("/", |count| Actions::StartSearch { forward: true, count }),
+        ("?", |count| Actions::StartSearch { forward: false, count }),
+        ("v", |_| Actions::EnterMode(Mode::Visual)),
+        ("V", |_| Actions::EnterMode(Mode::VisualLine)),
+        ("<C-v>", |_| Actions::EnterMode(Mode::VisualBlock)),
+        ("<C-s>", |_| Actions::Save),
+        ("<C-d>", |_| Actions::DeleteLine),
+        ("u", |_| Actions::Undo),
+        ("<C-r>", |_| Actions::Redo),
+        // step through the undo tree by wall-clock time, a minute per count
+        ("<A-->", |count| Actions::Earlier(Span::Seconds(60 * count.unwrap_or(1) as u64))),
+        ("<A-+>", |count| Actions::Later(Span::Seconds(60 * count.unwrap_or(1) as u64))),
+        ("<A-u>", |_| Actions::UndoList),
+        ("<Esc>", |_| Actions::EnterMode(Mode::Normal)),
+    ];
+    for (keys, build) in actions {
+        keymap.bind(keys, Binding::Action(build));
+    }
+    let windows: [(&str, ActionBuilder); 21] = [
+        ("<C-w>s", |_| Actions::Window(WindowCommand::Split { vertical: false })),
+        ("<C-w>v", |_| Actions::Window(WindowCommand::Split { vertical: true })),
+        ("<C-w>h", |count| Actions::Window(WindowCommand::Focus { direction: Direction::Left, count: count.unwrap_or(1) })),
+        ("<C-w>j", |count| Actions::Window(WindowCommand::Focus { direction: Direction::Down, count: count.unwrap_or(1) })),
+        ("<C-w>k", |count| Actions::Window(WindowCommand::Focus { direction: Direction::Up, count: count.unwrap_or(1) })),
+        ("<C-w>l", |count| Actions::Window(WindowCommand::Focus { direction: Direction::Right, count: count.unwrap_or(1) })),
+        ("<C-w>w", |count| Actions::Window(WindowCommand::Cycle { forward: true, count })),
+        ("<C-w><C-w>", |count| Actions::Window(WindowCommand::Cycle { forward: true, count })),
+        ("<C-w>W", |count| Actions::Window(WindowCommand::Cycle { forward: false, count })),
+        ("<C-w>c", |_| Actions::Window(WindowCommand::Close { force: false })),
+        ("<C-w>q", |_| Actions::Quit),
+        ("<C-w>o", |_| Actions::Window(WindowCommand::Only { force: false })),
+        ("<C-w>+", |count| Actions::Window(WindowCommand::Resize { vertical: false, delta: count.unwrap_or(1) as i32 })),
+        ("<C-w>-", |count| Actions::Window(WindowCommand::Resize { vertical: false, delta: -(count.unwrap_or(1) as i32) })),
+        ("<C-w>>", |count| Actions::Window(WindowCommand::Resize { vertical: true, delta: count.unwrap_or(1) as i32 })),
+        ("<C-w><lt>", |count| Actions::Window(WindowCommand::Resize { vertical: true, delta: -(count.unwrap_or(1) as i32) })),
+        ("<C-w>_", |size| Actions::Window(WindowCommand::SetSize { vertical: false, size })),
+        ("<C-w>|", |size| Actions::Window(WindowCommand::SetSize { vertical: true, size })),
+        ("<C-w>=", |_| Actions::Window(WindowCommand::Equalize)),
+        ("gt", |count| Actions::SwitchTab { forward: true, count }),
+        ("gT", |count| Actions::SwitchTab { forward: false, count }),
+    ];
+    for (keys, build) in windows {
+        keymap.bind(keys, Binding::Action(build));
+    }
+    keymap.bind("q", Binding::CharAction(|name, _| Actions::RecordMacro(name)));
+    keymap.bind("@", Binding::CharAction(Actions::PlayMacro));
+    keymap.bind("m", Binding::CharAction(|name, _| Actions::SetMark(name)));
+    keymap
+}
+
+pub fn visual_keymap() -> Keymap {
+    let mut keymap = Keymap::visual();
+    bind_targets(&mut keymap);
+    for (keys, op) in [
+        ("x", Operator::Delete),
+        ("s", Operator::Change),
+        ("u", Operator::Lowercase),
+        ("U", Operator::Uppercase),
+    ] {
+        keymap.bind(keys, Binding::Operator(op));
+    }
+    let actions: [(&str, ActionBuilder); 8] = [
+        ("o", |_| Actions::SwapSelectionEnds),
+        // the command line starts with the selection's range, `'<,'>`
+        (":", |_| Actions::EnterMode(Mode::Command)),
+        ("I", |_| Actions::InsertSelection { append: false }),
+        ("A", |_| Actions::InsertSelection { append: true }),
+        // the current Visual mode's key leaves it, another switches to that mode
+        ("v", |_| Actions::EnterMode(Mode::Visual)),
+        ("V", |_| Actions::EnterMode(Mode::VisualLine)),
+        ("<C-v>", |_| Actions::EnterMode(Mode::VisualBlock)),
+        ("<Esc>", |_| Actions::EnterMode(Mode::Normal)),
+    ];
+    for (keys, build) in actions {
+        keymap.bind(keys, Binding::Action(build));
+    }
+    keymap
 }
 
 pub fn handle_insert_event(ev: Event) -> Option<Actions> {
@@ -63,110 +301,619 @@ pub fn handle_insert_event(ev: Event) -> Option<Actions> {
     }
 }
 
-use crate::buffer::Buffer;
+/// A question shown in the status line that takes a single key as answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prompt {
+    FileChanged,
+    Recover,
+    Locked(u32),
+    Substitute,
+}
+
+impl Prompt {
+    fn text(&self) -> String {
+        match self {
+            Prompt::FileChanged => "File changed on disk: [r]eload, [o]verwrite, [d]iff, [i]gnore".to_string(),
+            Prompt::Recover => "Unsaved changes from a crashed session: [r]estore, [d]iff, [x] delete, [i]gnore".to_string(),
+            Prompt::Locked(pid) => format!("Already open in vix (pid {}): [e]dit anyway, [q]uit", pid),
+            Prompt::Substitute => "Replace this match? [y]es, [n]o, [a]ll, [q]uit, [l]ast".to_string(),
+        }
+    }
+}
+
+pub fn handle_prompt_event(prompt: Prompt, ev: Event) -> Option<Actions> {
+    let Event::Key(key) = ev else { return None };
+    match (prompt, key.code) {
+        (Prompt::FileChanged, KeyCode::Char('r')) => Some(Actions::Reload),
+        (Prompt::FileChanged, KeyCode::Char('o')) => Some(Actions::ForceSave),
+        (Prompt::FileChanged, KeyCode::Char('d')) => Some(Actions::ShowDiff),
+        (Prompt::FileChanged, KeyCode::Char('i') | KeyCode::Esc) => Some(Actions::Dismiss),
+        (Prompt::Recover, KeyCode::Char('r')) => Some(Actions::RestoreRecovery),
+        (Prompt::Recover, KeyCode::Char('d')) => Some(Actions::ShowRecoveryDiff),
+        (Prompt::Recover, KeyCode::Char('x')) => Some(Actions::DeleteRecovery),
+        (Prompt::Recover, KeyCode::Char('i') | KeyCode::Esc) => Some(Actions::Dismiss),
+        (Prompt::Locked(_), KeyCode::Char('e') | KeyCode::Esc) => Some(Actions::Dismiss),
+        (Prompt::Locked(_), KeyCode::Char('q')) => Some(Actions::Quit),
+        (Prompt::Substitute, KeyCode::Char('y')) => Some(Actions::ConfirmSubstitute(Confirm::Yes)),
+        (Prompt::Substitute, KeyCode::Char('n')) => Some(Actions::ConfirmSubstitute(Confirm::No)),
+        (Prompt::Substitute, KeyCode::Char('a')) => Some(Actions::ConfirmSubstitute(Confirm::All)),
+        (Prompt::Substitute, KeyCode::Char('q') | KeyCode::Esc) => Some(Actions::ConfirmSubstitute(Confirm::Quit)),
+        (Prompt::Substitute, KeyCode::Char('l')) => Some(Actions::ConfirmSubstitute(Confirm::Last)),
+        _ => None,
+    }
+}
+
+/// Read-only text shown over the whole window, such as a diff.
+pub struct Pager {
+    lines: Vec<String>,
+    top: usize,
+}
+
+pub fn handle_pager_event(ev: Event) -> Option<Actions> {
+    let Event::Key(key) = ev else { return None };
+    match key.code {
+        KeyCode::Char('j') | KeyCode::Down => Some(Actions::ScrollPager(1)),
+        KeyCode::Char('k') | KeyCode::Up => Some(Actions::ScrollPager(-1)),
+        KeyCode::Char(' ') | KeyCode::PageDown => Some(Actions::ScrollPager(20)),
+        KeyCode::Char('b') | KeyCode::PageUp => Some(Actions::ScrollPager(-20)),
+        KeyCode::Char('q') | KeyCode::Esc | KeyCode::Enter => Some(Actions::ClosePager),
+        _ => None,
+    }
+}
+
+pub fn handle_command_event(ev: Event) -> Option<Actions> {
+    match ev {
+        Event::Key(key) => match key.code {
+            KeyCode::Esc => Some(Actions::EnterMode(Mode::Normal)),
+            KeyCode::Enter => Some(Actions::ExecuteCommand),
+            KeyCode::Char(c) => Some(Actions::EditCommandLine(LineEdit::Insert(c))),
+            KeyCode::Backspace => Some(Actions::EditCommandLine(LineEdit::Backspace)),
+            KeyCode::Delete => Some(Actions::EditCommandLine(LineEdit::Delete)),
+            KeyCode::Left => Some(Actions::EditCommandLine(LineEdit::Left)),
+            KeyCode::Right => Some(Actions::EditCommandLine(LineEdit::Right)),
+            KeyCode::Home => Some(Actions::EditCommandLine(LineEdit::Home)),
+            KeyCode::End => Some(Actions::EditCommandLine(LineEdit::End)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn handle_search_event(ev: Event) -> Option<Actions> {
+    let Event::Key(key) = ev else { return None };
+    match key.code {
+        KeyCode::Enter => Some(Actions::ExecuteSearch),
+        KeyCode::Up => Some(Actions::SearchHistory { older: true }),
+        KeyCode::Down => Some(Actions::SearchHistory { older: false }),
+        _ => handle_command_event(ev),
+    }
+}
+
+use crate::buffer::{Buffer, BufferError, DiskChange, LineEnding};
+use crate::encoding::Encoding;
+use crate::history::Span;
+use crate::keymap::{self, ActionBuilder, Binding, Key, KeyParser, Keymap, MotionBuilder};
+use crate::motion::{self, Motion};
+use crate::operator::{self, Block, Operator, Range, Target};
+use crate::register::{Register, RegisterError, RegisterKind, Registers};
+use crate::command::{self, Address, CommandError, CommandKind, CommandLine, LineEdit, LineRange, LineSpec};
+use crate::screen::{Screen, Style};
+use crate::search::{self, History, Pattern};
+use crate::settings::Settings;
+use crate::substitute::{self, Confirm, Flags, Substitution};
+use crate::text;
+use crate::textobject::{ObjectKind, TextObject};
+use crate::window::{Direction, Layout, Rect, WindowError};
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn format_time(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|t| t.with_timezone(&chrono::Local).format("%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// The last change, for `.`: the command that made it, the register it
+/// used, and what was typed in the Insert session it started, if any.
+#[derive(Clone, Debug)]
+struct Change {
+    action: Actions,
+    register: Option<char>,
+    typed: Vec<Actions>,
+    // the selection a Visual command was given, selected again from the cursor
+    region: Option<Region>,
+    // how many times the text of an Insert change is typed
+    count: Option<usize>,
+}
+
+// The size of a Visual selection: how many lines, and the column the last
+// one ends in, or how many columns past the start it ends when there is
+// one line. For Visual-Block, how many screen columns wide it is.
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    mode: Mode,
+    lines: usize,
+    cols: usize,
+}
+
+// Text typed after Visual-Block `I`, `A` or `c` is copied to the block's
+// other lines at `column` when Insert mode ends.
+struct BlockInsert {
+    first: usize,
+    last: usize,
+    column: usize,
+    append: bool,
+}
+
+// The `/` or `?` being typed. The cursor jumps to the first match from
+// `origin` as the pattern changes, and goes back there if it is abandoned.
+struct SearchPrompt {
+    forward: bool,
+    count: Option<usize>,
+    origin: (usize, usize),
+    // the history entry shown, and what was typed before Up was pressed
+    history: Option<usize>,
+    typed: String,
+}
+
+// A `:s` under way: the lines left to go through, where to look next in the
+// current one, and what has been done. With the `c` flag it waits here for
+// the answer about each match.
+struct Substituting {
+    pattern: Pattern,
+    replacement: String,
+    flags: Flags,
+    line: usize,
+    byte: usize,
+    last: usize,
+    found: bool,
+    count: usize,
+    lines: usize,
+    // the line of the last match counted in `lines`
+    counted: Option<usize>,
+}
+
+// A buffer in the list other than the current one, with what to go back
+// to: where its cursor was, its marks, and a question not yet asked about
+// it. Another window may still show it.
+struct Stashed {
+    number: usize,
+    buffer: Buffer,
+    cursor: (usize, usize),
+    row_offset: usize,
+    marks: HashMap<char, (usize, usize)>,
+    prompt: Option<Prompt>,
+    dismissed: Option<DiskChange>,
+}
+
+// A window other than the current one: the buffer it shows, by number, and
+// its view of it.
+struct Window {
+    id: usize,
+    buffer: usize,
+    cursor: (usize, usize),
+    row_offset: usize,
+    col_offset: usize,
+}
+
+// A tab page other than the current one: its windows, including the one
+// that was current in it, and how they share the screen.
+struct TabPage {
+    layout: Layout,
+    windows: Vec<Window>,
+    window_id: usize,
+}
+
+// what to ask about a freshly opened file before it is edited
+fn opening_prompt(buffer: &Buffer) -> Option<Prompt> {
+    match (buffer.locked_by, &buffer.recovery) {
+        (Some(pid), _) => Some(Prompt::Locked(pid)),
+        (None, Some(_)) => Some(Prompt::Recover),
+        _ => None,
+    }
+}
+
+// how deep macros may call macros, so that one calling itself cannot run forever
+const MAX_MACRO_DEPTH: usize = 100;
 
 pub struct Editor {
     pub buffer: Buffer,
-    pub cx: u16,
-    pub cy: u16,
+    // the current buffer's number in `:ls`; the others wait in `stashed`, in order
+    buffer_number: usize,
+    stashed: Vec<Stashed>,
+    next_number: usize,
+    // the current window's view is `cx`, `cy`, `row_offset` and `col_offset`; the others are in `windows`
+    window_id: usize,
+    windows: Vec<Window>,
+    layout: Layout,
+    next_window: usize,
+    // the other tab pages, in order, with the current one's place among them
+    tabs: Vec<TabPage>,
+    tab: usize,
+    // what is on the terminal, and the next frame drawn over it
+    screen: Screen,
+    pub settings: Settings,
+    pub cx: usize,
+    pub cy: usize,
     pub row_offset: usize,
+    // screen columns scrolled off the left of the current window; with 'wrap',
+    // rows of the top line scrolled off above it instead, as vim's 'skipcol'
+    // lets a line taller than the window show its cursor row
+    pub col_offset: usize,
     pub mode: Mode,
+    pub keymap: Keymap,
+    pub visual_keymap: Keymap,
+    keys: KeyParser,
+    // the end of the Visual selection that stays put while the cursor moves
+    visual_start: (usize, usize),
+    block_insert: Option<BlockInsert>,
+    pub registers: Registers,
+    pending_register: Option<char>,
+    // what this Insert session typed, for the `.` register
+    inserted: String,
+    last_change: Option<Change>,
+    macro_recording: Option<(char, Vec<Key>)>,
+    last_macro: Option<char>,
+    macro_depth: usize,
+    // set when a motion or command fails, which ends a macro
+    failed: bool,
+    // the change being made, until it leaves Insert mode
+    recording: Option<Change>,
+    replaying: bool,
+    last_find: Option<Motion>,
+    search_prompt: Option<SearchPrompt>,
+    // the pattern being typed, highlighted as it changes
+    preview: Option<Pattern>,
+    search: Option<Pattern>,
+    search_forward: bool,
+    search_history: History,
+    // whether the last pattern's matches are highlighted; `:nohlsearch` turns it off
+    highlight: bool,
+    // `'<` and `'>` are where the last Visual selection started and ended
+    marks: HashMap<char, (usize, usize)>,
+    substituting: Option<Substituting>,
+    in_global: bool,
+    // screen column `j` and `k` aim for; usize::MAX after `$` keeps to line ends
+    want_col: usize,
+    pub command_line: CommandLine,
     pub status_message: Option<String>,
+    pub prompt: Option<Prompt>,
+    pub pager: Option<Pager>,
+    pub should_quit: bool,
+    // the last disk change the user chose to ignore, so it is not asked about again
+    dismissed: Option<DiskChange>,
 }
 
 impl Editor {
-    pub fn new() -> Self {
-        Self {
-            buffer: Buffer { file: None, lines: vec![String::new()], modified: false },
-            cx: 0,
-            cy: 0,
-            row_offset: 0,
-            mode: Mode::Normal,
-            status_message: None,
-        }
-    }
-
     pub fn with_buffer(buffer: Buffer) -> Self {
+        let prompt = opening_prompt(&buffer);
         Self {
             buffer,
+            buffer_number: 1,
+            stashed: Vec::new(),
+            next_number: 2,
+            window_id: 1,
+            windows: Vec::new(),
+            layout: Layout::Window(1),
+            next_window: 2,
+            tabs: Vec::new(),
+            tab: 0,
+            screen: Screen::new(80, 24),
+            settings: Settings::default(),
             cx: 0,
             cy: 0,
             row_offset: 0,
+            col_offset: 0,
             mode: Mode::Normal,
+            keymap: normal_keymap(),
+            visual_keymap: visual_keymap(),
+            keys: KeyParser::default(),
+            visual_start: (0, 0),
+            block_insert: None,
+            registers: Registers::default(),
+            pending_register: None,
+            inserted: String::new(),
+            last_change: None,
+            macro_recording: None,
+            last_macro: None,
+            macro_depth: 0,
+            failed: false,
+            recording: None,
+            replaying: false,
+            last_find: None,
+            search_prompt: None,
+            preview: None,
+            search: None,
+            search_forward: true,
+            search_history: History::default(),
+            highlight: false,
+            marks: HashMap::new(),
+            substituting: None,
+            in_global: false,
+            want_col: 0,
+            command_line: CommandLine::default(),
             status_message: None,
+            prompt,
+            pager: None,
+            should_quit: false,
+            dismissed: None,
         }
     }
-    pub fn handle_event(&self, ev: Event) -> Option<Actions> {
+    pub fn handle_event(&mut self, ev: Event) -> Option<Actions> {
+        if self.pager.is_some() {
+            return handle_pager_event(ev);
+        }
+        if let Some(prompt) = self.prompt {
+            return handle_prompt_event(prompt, ev);
+        }
+        if let Event::Key(key) = ev
+            && self.macro_depth == 0
+            && let Some((_, keys)) = &mut self.macro_recording
+        {
+            let key = Key::from(key);
+            let stops = self.mode == Mode::Normal || self.mode.is_visual();
+            if stops && self.keys.is_empty() && key == Key::char('q') {
+                return Some(Actions::StopRecording);
+            }
+            keys.push(key);
+        }
         match self.mode {
-            Mode::Normal => handle_normal_event(ev),
+            Mode::Normal => match ev {
+                Event::Key(key) => self.keys.feed(&self.keymap, Key::from(key)),
+                _ => None,
+            },
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => match ev {
+                Event::Key(key) => self.keys.feed(&self.visual_keymap, Key::from(key)),
+                _ => None,
+            },
             Mode::Insert => handle_insert_event(ev),
+            Mode::Command => handle_command_event(ev),
+            Mode::Search => handle_search_event(ev),
         }
     }
     pub fn apply_action(&mut self, action: Actions) {
+        if let Actions::SelectRegister(name) = action {
+            self.pending_register = Some(name);
+            return;
+        }
+        // j and k keep the column the cursor last moved to, not the one a short line clamped it to
+        let keeps_column = matches!(action, Actions::Move(Motion::Up | Motion::Down | Motion::ScreenLine { .. }, _));
+        // gj and gk keep how far across the window it was, on whichever row
+        let across = matches!(action, Actions::Move(Motion::ScreenLine { .. }, _)).then(|| self.want_across());
+        let to_line_end = matches!(action, Actions::Move(Motion::LineEnd, _));
+        let before = (self.cy, self.cx);
+        let selection = self.mode.is_visual().then_some((self.visual_start, before));
+        let failed = std::mem::replace(&mut self.failed, false);
+        let recording = self.recording.is_some();
+        if !self.replaying {
+            self.record(&action);
+        }
+        self.perform(action);
+        // a command that failed is not a change to repeat
+        if self.failed && !recording {
+            self.recording = None;
+        }
+        self.failed |= failed;
+        if let Some((start, end)) = selection
+            && !self.mode.is_visual()
+        {
+            self.marks.insert('<', start.min(end));
+            self.marks.insert('>', start.max(end));
+        }
+        // a register only lasts for the command after it
+        self.pending_register = None;
+        if self.mode != Mode::Insert && self.recording.is_some() {
+            self.last_change = self.recording.take();
+        }
+        if to_line_end {
+            self.want_col = usize::MAX;
+        } else if let Some(across) = across
+            && (self.cy, self.cx) != before
+        {
+            let (row, text) = self.cursor_row();
+            self.want_col = text::display_col(&text, row.start).saturating_add(across.saturating_sub(row.x));
+        } else if !keeps_column && (self.cy, self.cx) != before {
+            self.want_col = self.buffer.get_line(self.cy)
+                .map(|line| text::display_col(&line, self.cx))
+                .unwrap_or(0);
+        }
+    }
+
+    fn perform(&mut self, action: Actions) {
         debug!("Applying action: {:?}", action);
         match action {
-            Actions::MoveLeft => {
-                if self.cx > 0 { 
-                    self.cx -= 1;
-                    debug!("Moved cursor left to column {}", self.cx);
+            Actions::Move(motion, count) => {
+                let Some((motion, (line, col))) = self.motion_target(motion, count, false) else {
+                    self.failed = true;
+                    return;
+                };
+                self.cy = line;
+                self.cx = match motion {
+                    Motion::Up | Motion::Down => self.buffer.get_line(line)
+                        .map(|text| text::col_at_display(&text, self.want_col))
+                        .unwrap_or(0),
+                    _ => col,
+                };
+                self.clamp_normal_cursor();
+            }
+            Actions::Operate(op, target, count) => self.operate(op, target, count),
+            Actions::Put { before, count } => self.put(before, count.unwrap_or(1)),
+            Actions::SelectRegister(name) => self.pending_register = Some(name),
+            Actions::SelectObject(object, count) => {
+                if let Some(range) = object.range(&self.buffer, (self.cy, self.cx), count) {
+                    self.select(range);
                 }
             }
-            Actions::MoveRight => {
-                if let Ok(line) = self.buffer.get_line(self.cy as usize) {
-                    let line_len = line.len() as u16;
-                    if self.cx < line_len { 
-                        self.cx += 1;
-                        debug!("Moved cursor right to column {}", self.cx);
-                    }
+            Actions::SwapSelectionEnds => {
+                let cursor = (self.cy, self.cx);
+                self.set_cursor(self.visual_start);
+                self.visual_start = cursor;
+            }
+            Actions::InsertSelection { append } => self.insert_selection(append),
+            Actions::Repeat(count) => self.repeat(count),
+            Actions::RecordMacro(name) => {
+                if name.is_ascii_alphanumeric() {
+                    self.macro_recording = Some((name, Vec::new()));
+                } else {
+                    self.status_message = Some(RegisterError::Invalid(name).to_string());
                 }
             }
-            Actions::MoveUp => {
-                if self.cy > 0 {
-                    self.cy -= 1;
-                    if let Ok(line) = self.buffer.get_line(self.cy as usize) {
-                        let line_len = line.len() as u16;
-                        if self.cx > line_len {
-                            self.cx = line_len;
+            Actions::StopRecording => {
+                if let Some((name, keys)) = self.macro_recording.take()
+                    && let Err(e) = self.registers.store_macro(name, keymap::notation(&keys))
+                {
+                    self.status_message = Some(e.to_string());
+                }
+            }
+            Actions::PlayMacro(name, count) => self.play_macro(name, count.unwrap_or(1)),
+            Actions::StartSearch { forward, count } => {
+                let origin = (self.cy, self.cx);
+                self.search_prompt = Some(SearchPrompt { forward, count, origin, history: None, typed: String::new() });
+                self.command_line = CommandLine::default();
+                self.mode = Mode::Search;
+            }
+            Actions::SearchHistory { older } => {
+                let Some(prompt) = &mut self.search_prompt else { return };
+                if prompt.history.is_none() {
+                    prompt.typed = self.command_line.text.clone();
+                }
+                let index = if older {
+                    self.search_history.older(prompt.history.unwrap_or(self.search_history.len()), &prompt.typed)
+                } else {
+                    prompt.history.and_then(|i| self.search_history.newer(i, &prompt.typed))
+                };
+                let text = match index {
+                    Some(i) => self.search_history.get(i).unwrap_or_default().to_string(),
+                    None if older => return,
+                    // Down past the newest entry brings back what was typed
+                    None => prompt.typed.clone(),
+                };
+                prompt.history = index;
+                self.command_line = CommandLine::with_text(&text);
+                self.preview_search();
+            }
+            Actions::SetMark(name) => {
+                if name.is_ascii_lowercase() {
+                    self.marks.insert(name, (self.cy, self.cx));
+                } else {
+                    self.status_message = Some(format!("Invalid mark name: '{}'", name));
+                }
+            }
+            Actions::ConfirmSubstitute(answer) => {
+                self.prompt = None;
+                self.substitute(Some(answer));
+            }
+            Actions::ExecuteSearch => {
+                self.mode = Mode::Normal;
+                self.preview = None;
+                let Some(prompt) = self.search_prompt.take() else { return };
+                self.set_cursor(prompt.origin);
+                let text = std::mem::take(&mut self.command_line).text;
+                // an empty pattern searches for the last one again
+                if !text.is_empty() {
+                    self.search_history.add(&text);
+                    match Pattern::new(&text) {
+                        Ok(pattern) => self.search = Some(pattern),
+                        Err(e) => {
+                            self.status_message = Some(e.to_string());
+                            self.failed = true;
+                            return;
                         }
                     }
                 }
+                self.search_forward = prompt.forward;
+                self.perform(Actions::Move(Motion::SearchNext { reverse: false }, prompt.count));
             }
-            Actions::MoveDown => {
-                if (self.cy as usize) + 1 < self.buffer.len() {
-                    self.cy += 1;
-                    if let Ok(line) = self.buffer.get_line(self.cy as usize) {
-                        let line_len = line.len() as u16;
-                        if self.cx > line_len {
-                            self.cx = line_len;
+            Actions::Insert(at) => {
+                let len = self.buffer.line_length(self.cy).unwrap_or(0);
+                match at {
+                    InsertAt::Cursor => {}
+                    InsertAt::AfterCursor => self.cx = (self.cx + 1).min(len),
+                    InsertAt::LineStart => self.cx = motion::first_non_blank(&self.buffer, self.cy),
+                    InsertAt::LineEnd => self.cx = len,
+                    InsertAt::LineBelow | InsertAt::LineAbove => {
+                        self.buffer.begin_undo_group((self.cy, self.cx));
+                        let opened = if at == InsertAt::LineBelow {
+                            self.buffer.insert_text(self.cy, len, "\n").map(|_| self.cy + 1)
+                        } else {
+                            self.buffer.insert_text(self.cy, 0, "\n").map(|_| self.cy)
+                        };
+                        if let Ok(line) = opened {
+                            self.cy = line;
                         }
+                        self.cx = 0;
                     }
                 }
+                self.apply_action(Actions::EnterMode(Mode::Insert));
             }
             Actions::EnterMode(m) => {
+                let m = if m == self.mode && m.is_visual() { Mode::Normal } else { m };
                 info!("Switching mode from {:?} to {:?}", self.mode, m);
+                match m {
+                    // everything typed in one Insert session is undone together
+                    Mode::Insert => {
+                        self.buffer.begin_undo_group((self.cy, self.cx));
+                        self.inserted.clear();
+                    }
+                    Mode::Normal => {
+                        if let Some(prompt) = self.search_prompt.take() {
+                            self.preview = None;
+                            self.set_cursor(prompt.origin);
+                        }
+                        if let Some(block) = self.block_insert.take() {
+                            self.finish_block_insert(block);
+                        }
+                        self.buffer.end_undo_group();
+                        if self.mode == Mode::Insert {
+                            self.registers.set_read_only('.', self.inserted.clone());
+                        }
+                    }
+                    Mode::Command if self.mode.is_visual() => self.command_line = CommandLine::with_text("'<,'>"),
+                    Mode::Command | Mode::Search => self.command_line = CommandLine::default(),
+                    Mode::Visual | Mode::VisualLine | Mode::VisualBlock => {
+                        // switching between Visual modes keeps the selection
+                        if !self.mode.is_visual() {
+                            self.visual_start = (self.cy, self.cx);
+                        }
+                    }
+                }
                 self.mode = m;
             },
             Actions::PrintChar(c) => {
-                if let Ok(_) = self.buffer.insert_char(self.cy as usize, self.cx as usize, c) {
-                    self.cx += 1;
+                if let Ok(col) = self.buffer.insert_char(self.cy, self.cx, c) {
+                    self.cx = col;
+                    self.inserted.push(c);
                 }
             }
             Actions::Backspace => {
+                let mut deleted = false;
                 if self.cx > 0 {
-                    if let Ok(_) = self.buffer.remove_char(self.cy as usize, (self.cx - 1) as usize) {
+                    if self.buffer.remove_char(self.cy, self.cx - 1).is_ok() {
                         self.cx -= 1;
+                        deleted = true;
                     }
-                } else if self.cy > 0 {
-                    if let Ok(prev_line_len) = self.buffer.join_with_previous_line(self.cy as usize) {
-                        self.cy -= 1;
-                        self.cx = prev_line_len as u16;
-                    }
+                } else if self.cy > 0
+                    && let Ok(prev_line_len) = self.buffer.join_with_previous_line(self.cy)
+                {
+                    self.cy -= 1;
+                    self.cx = prev_line_len;
+                    deleted = true;
+                }
+                // text that was there before this Insert session is not in
+                // the `.` register to take back out
+                let last = text::grapheme_count(&self.inserted).checked_sub(1);
+                if deleted && let Some(end) = last.and_then(|last| text::byte_offset(&self.inserted, last)) {
+                    self.inserted.truncate(end);
                 }
             }
             Actions::NewLine => {
-                if let Ok(line) = self.buffer.get_line_mut(self.cy as usize) {
-                    let tail = line.split_off(self.cx as usize);
-                    self.buffer.lines.insert((self.cy + 1) as usize, tail);
+                if self.buffer.split_line(self.cy, self.cx).is_ok() {
+                    self.inserted.push('\n');
                     self.cy += 1;
                     self.cx = 0;
                 }
@@ -178,9 +925,14 @@ impl Editor {
                         info!("File saved successfully");
                         self.status_message = Some("Saved.".to_string());
                     }
+                    Err(BufferError::ChangedOnDisk(_)) => {
+                        self.prompt = Some(Prompt::FileChanged);
+                        self.failed = true;
+                    }
                     Err(e) => {
                         warn!("Error saving file: {}", e);
                         self.status_message = Some(format!("Error saving file: {}", e));
+                        self.failed = true;
                     }
                 }
             }
@@ -194,110 +946,3103 @@ impl Editor {
                     Err(e) => {
                         warn!("Error saving file: {}", e);
                         self.status_message = Some(format!("Error saving file: {}", e));
+                        self.failed = true;
                     }
                 }
             }
-            Actions::DeleteLine => {
-                match self.buffer.delete_line(self.cy as usize) {
+            Actions::DeleteLine => self.operate(Operator::Delete, Target::Lines, None),
+            Actions::Undo => {
+                let result = self.buffer.undo();
+                self.finish_undo(result, "Already at oldest change");
+            }
+            Actions::Redo => {
+                let result = self.buffer.redo();
+                self.finish_undo(result, "Already at newest change");
+            }
+            Actions::Earlier(span) => {
+                let result = self.buffer.earlier(span);
+                self.finish_undo(result, "Already at oldest change");
+            }
+            Actions::Later(span) => {
+                let result = self.buffer.later(span);
+                self.finish_undo(result, "Already at newest change");
+            }
+            Actions::UndoList => {
+                let branches = self.buffer.undo_branches();
+                self.status_message = Some(if branches.is_empty() {
+                    "Nothing to undo".to_string()
+                } else {
+                    let list: Vec<String> = branches.iter()
+                        .map(|b| format!("#{} ({} changes, {})", b.seq, b.changes, format_time(b.time)))
+                        .collect();
+                    format!("Branches: {}", list.join(" | "))
+                });
+            }
+            Actions::Reload => {
+                self.prompt = None;
+                // the reload is an undo step of its own, even in the middle of
+                // an Insert session, whose typing goes on in the next one
+                self.buffer.end_undo_group();
+                match self.buffer.reload() {
                     Ok(()) => {
-                        // adjust cursor if we were on the last line
-                        if (self.cy as usize) >= self.buffer.len() {
-                            self.cy = (self.buffer.len().saturating_sub(1)) as u16;
-                        }
-                        // ensure cx is not past end of line
-                        if let Ok(len) = self.buffer.line_length(self.cy as usize) {
-                            if self.cx as usize > len {
-                                self.cx = len as u16;
-                            }
-                        }
-                        self.status_message = Some("Line deleted".to_string());
+                        self.clamp_cursor();
+                        self.status_message = Some(format!("Reloaded {}", self.buffer.display_name()));
+                    }
+                    Err(e) => {
+                        warn!("Error reloading file: {}", e);
+                        self.status_message = Some(format!("Error reloading file: {}", e));
+                    }
+                }
+                if self.mode == Mode::Insert {
+                    self.buffer.begin_undo_group((self.cy, self.cx));
+                }
+            }
+            Actions::ForceSave => {
+                self.prompt = None;
+                match self.buffer.force_save() {
+                    Ok(()) => self.status_message = Some("Saved (overwrote changes on disk).".to_string()),
+                    Err(e) => {
+                        warn!("Error saving file: {}", e);
+                        self.status_message = Some(format!("Error saving file: {}", e));
+                        self.failed = true;
+                    }
+                }
+            }
+            Actions::ShowDiff => match self.buffer.diff_with_disk() {
+                Ok(lines) if lines.is_empty() => self.status_message = Some("No differences".to_string()),
+                Ok(lines) => self.pager = Some(Pager { lines, top: 0 }),
+                Err(e) => self.status_message = Some(format!("Error reading file: {}", e)),
+            },
+            Actions::Dismiss => match self.prompt.take() {
+                Some(Prompt::FileChanged) => self.dismissed = Some(self.buffer.check_disk()),
+                Some(Prompt::Locked(_)) if self.buffer.recovery.is_some() => self.prompt = Some(Prompt::Recover),
+                // the recovery file is kept, and no swap file is written over it
+                Some(Prompt::Recover) => self.status_message = Some("Recovery file kept".to_string()),
+                _ => {}
+            },
+            Actions::ScrollPager(delta) => {
+                if let Some(pager) = &mut self.pager {
+                    let max = pager.lines.len().saturating_sub(1);
+                    pager.top = pager.top.saturating_add_signed(delta).min(max);
+                }
+            }
+            Actions::ClosePager => self.pager = None,
+            Actions::RestoreRecovery => {
+                self.prompt = None;
+                match self.buffer.restore_recovery() {
+                    Ok(()) => {
+                        self.clamp_cursor();
+                        self.status_message = Some("Recovered unsaved changes; save to keep them".to_string());
+                    }
+                    Err(e) => {
+                        warn!("Error restoring recovery file: {}", e);
+                        self.status_message = Some(format!("Error restoring recovery file: {}", e));
+                    }
+                }
+            }
+            Actions::ShowRecoveryDiff => match self.buffer.diff_with_recovery() {
+                Ok(lines) if lines.is_empty() => self.status_message = Some("No differences".to_string()),
+                Ok(lines) => self.pager = Some(Pager { lines, top: 0 }),
+                Err(e) => self.status_message = Some(format!("Error reading recovery file: {}", e)),
+            },
+            Actions::DeleteRecovery => {
+                self.prompt = None;
+                self.status_message = Some(match self.buffer.discard_recovery() {
+                    Ok(()) => "Recovery file deleted".to_string(),
+                    Err(e) => format!("Error deleting recovery file: {}", e),
+                });
+            }
+            Actions::Quit => {
+                if let Some(Prompt::Locked(_)) = self.prompt
+                    && !self.stashed.is_empty()
+                {
+                    // with other files to edit, `q` only gives up this one
+                    self.prompt = None;
+                    self.delete_buffer(self.buffer_number, true);
+                    return;
+                }
+                if !self.windows.is_empty() || !self.tabs.is_empty() {
+                    self.close_window(false);
+                    return;
+                }
+                self.quit_all(false);
+            }
+            Actions::ForceQuit if !self.windows.is_empty() || !self.tabs.is_empty() => self.close_window(true),
+            Actions::ForceQuit => {
+                // like vim, the current buffer's changes are given up but not
+                // those of hidden buffers: the first of them is shown instead
+                match self.stashed.iter().position(|stashed| stashed.buffer.modified) {
+                    Some(index) => {
+                        self.show_buffer(index, false);
+                        self.quit_all(false);
+                    }
+                    None => self.quit_all(true),
+                }
+            }
+            Actions::QuitAll { force } => self.quit_all(force),
+            Actions::EditCommandLine(edit) => {
+                // backspace on an empty line leaves command mode, as in vim
+                if edit == LineEdit::Backspace && self.command_line.text.is_empty() {
+                    self.apply_action(Actions::EnterMode(Mode::Normal));
+                } else {
+                    self.command_line.apply(edit);
+                    self.preview_search();
+                }
+            }
+            Actions::ExecuteCommand => {
+                self.mode = Mode::Normal;
+                let line = std::mem::take(&mut self.command_line).text;
+                if !line.trim().is_empty() {
+                    self.registers.set_read_only(':', line.clone());
+                }
+                self.execute_command(&line);
+            }
+            Actions::WriteCopy(path) => {
+                info!("Writing copy to {}", path);
+                match self.buffer.write_copy(&path) {
+                    Ok(written) => self.status_message = Some(format!("\"{}\" {}B written", path, written)),
+                    Err(e) => {
+                        warn!("Error writing {}: {}", path, e);
+                        self.status_message = Some(format!("Error writing {}: {}", path, e));
+                        self.failed = true;
                     }
+                }
+            }
+            Actions::Edit { path, force } => {
+                let Some(path) = path.or_else(|| self.buffer.file.clone()) else {
+                    self.status_message = Some("No file name".to_string());
+                    self.failed = true;
+                    return;
+                };
+                let same = |buffer: &Buffer| buffer.file.as_deref().is_some_and(|file| same_file(file, &path));
+                if same(&self.buffer) {
+                    if self.buffer.modified && !force {
+                        self.status_message = Some("No write since last change (add ! to override)".to_string());
+                        self.failed = true;
+                        return;
+                    }
+                    // the buffer already holds this file's lock, so reread it in place
+                    self.apply_action(Actions::Reload);
+                    return;
+                }
+                if !self.can_hide(force) {
+                    self.failed = true;
+                    return;
+                }
+                // `:e!` throws changes away unless 'hidden' is set, and an
+                // unnamed buffer with nothing in it is not worth keeping
+                let keep = if self.buffer.modified { self.settings.hidden } else { self.buffer.file.is_some() };
+                if let Some(index) = self.stashed.iter().position(|stashed| same(&stashed.buffer)) {
+                    self.show_buffer(index, keep);
+                    self.status_message = Some(self.buffer_message());
+                    return;
+                }
+                match Buffer::from_file(Some(path.clone()), None) {
+                    Ok(buffer) => self.open(buffer, keep, format!("\"{}\"", path)),
+                    Err(BufferError::FileNotFound(_)) => self.open(Buffer::new_file(path.clone()), keep, format!("\"{}\" [New]", path)),
                     Err(e) => {
-                        self.status_message = Some(format!("Error deleting line: {}", e));
+                        warn!("Error opening {}: {}", path, e);
+                        self.status_message = Some(format!("Error opening {}: {}", path, e));
+                        self.failed = true;
                     }
                 }
             }
+            Actions::Window(command) => self.window_command(command),
+            Actions::SwitchTab { forward, count } => {
+                let pages = self.tabs.len() + 1;
+                let index = match (forward, count) {
+                    (true, Some(n)) => n.clamp(1, pages) - 1,
+                    (true, None) => (self.tab + 1) % pages,
+                    (false, n) => (self.tab + pages - n.unwrap_or(1) % pages) % pages,
+                };
+                self.goto_tab(index);
+            }
         }
     }
-    pub fn render(&mut self, stdout: &mut impl Write) -> Result<()> {
-        let (w, h) = terminal::size()?;
-        stdout.queue(terminal::Clear(terminal::ClearType::All))?;
-        let visible_height = h.saturating_sub(1) as usize; // leave last line for status
 
-        // adjust row_offset so cy is visible
-        if (self.cy as usize) < self.row_offset {
-            self.row_offset = self.cy as usize;
-        } else if (self.cy as usize) >= self.row_offset + visible_height {
-            self.row_offset = (self.cy as usize).saturating_sub(visible_height).saturating_add(1);
+    // Starts recording a change, or adds to the one whose Insert session is
+    // under way.
+    fn record(&mut self, action: &Actions) {
+        if let Some(change) = &mut self.recording {
+            if self.mode == Mode::Insert && matches!(action, Actions::PrintChar(_) | Actions::Backspace | Actions::NewLine) {
+                change.typed.push(action.clone());
+            }
+            return;
         }
+        let mut region = None;
+        let action = match action {
+            Actions::Operate(Operator::Yank, ..) => return,
+            // `.` after a Visual operator works on as much text from the cursor
+            Actions::Operate(op, Target::Selection, _) => {
+                let range = self.selection();
+                match self.mode {
+                    Mode::VisualLine => Actions::Operate(*op, Target::Lines, Some(range.end.0 - range.start.0 + 1)),
+                    Mode::Visual if range.start.0 == range.end.0 => {
+                        Actions::Operate(*op, Target::Motion(Motion::Right), Some(range.end.1 - range.start.1))
+                    }
+                    _ => {
+                        region = Some(self.region());
+                        action.clone()
+                    }
+                }
+            }
+            Actions::InsertSelection { .. } => {
+                region = Some(self.region());
+                action.clone()
+            }
+            Actions::Operate(..) | Actions::Put { .. } | Actions::Insert(_) | Actions::DeleteLine => action.clone(),
+            _ => return,
+        };
+        self.recording = Some(Change { action, register: self.pending_register, typed: Vec::new(), region, count: None });
+    }
 
-        for (i, line) in self.buffer.lines.iter().enumerate().skip(self.row_offset) {
-            let y = (i - self.row_offset) as u16;
-            if y as u16 >= h.saturating_sub(1) { break; }
-            stdout.queue(MoveTo(0, y))?;
-            stdout.queue(Print(line))?;
+    fn region(&self) -> Region {
+        if self.mode == Mode::VisualBlock {
+            let block = self.block();
+            return Region { mode: self.mode, lines: block.last - block.first + 1, cols: block.right - block.left };
         }
-        let mode_name = match self.mode {
-            Mode::Normal => "NORMAL",
-            Mode::Insert => "INSERT",
+        let (start, end) = (self.visual_start.min((self.cy, self.cx)), self.visual_start.max((self.cy, self.cx)));
+        let cols = if start.0 == end.0 { end.1 - start.1 } else { end.1 };
+        Region { mode: self.mode, lines: end.0 - start.0 + 1, cols }
+    }
+
+    // Selects `region` from the cursor, as `.` does for a Visual change.
+    fn select_region(&mut self, region: Region) {
+        let line = (self.cy + region.lines - 1).min(self.buffer.len() - 1);
+        let col = match region.mode {
+            Mode::VisualBlock => {
+                let left = self.buffer.get_line(self.cy).map(|text| text::display_col(&text, self.cx)).unwrap_or(0);
+                let text = self.buffer.get_line(line).unwrap_or_default();
+                text::col_at_display(&text, left + region.cols.max(1) - 1)
+            }
+            _ if region.lines == 1 => self.cx + region.cols,
+            _ => region.cols,
         };
-    let filename = self.buffer.display_name();
-    let modified_marker = if self.buffer.modified { "*" } else { "" };
-        let line = (self.cy + 1).to_string();
-        let col = (self.cx + 1).to_string();
-        let percent = if self.buffer.len() <= 1 {
-            100
-        } else {
-            let last = (self.buffer.len() - 1) as f64;
-            let pct = (self.cy as f64 / last) * 100.0;
-            pct.round() as u16
+        self.mode = region.mode;
+        self.visual_start = (self.cy, self.cx);
+        self.set_cursor((line, col));
+    }
+
+    fn repeat(&mut self, count: Option<usize>) {
+        let Some(mut change) = self.last_change.clone() else { return };
+        if let Actions::Operate(_, _, old) | Actions::Put { count: old, .. } = &mut change.action
+            && count.is_some()
+        {
+            *old = count;
+        }
+        if let Actions::Insert(_) = change.action
+            && count.is_some()
+        {
+            change.count = count;
+        }
+        // repeating a put from "1 puts from "2, and so on, as in `:h redo-register`
+        if let Actions::Put { .. } = change.action
+            && let Some(digit) = change.register.and_then(|name| name.to_digit(10))
+            && (1..9).contains(&digit)
+        {
+            change.register = char::from_digit(digit + 1, 10);
+        }
+        self.replaying = true;
+        self.pending_register = change.register;
+        if let Some(region) = change.region {
+            self.select_region(region);
+        }
+        self.apply_action(change.action.clone());
+        // `3.` after `ofoo<Esc>` opens three lines, after `ifoo<Esc>` types foo three times
+        let opens_line = matches!(change.action, Actions::Insert(InsertAt::LineBelow | InsertAt::LineAbove));
+        for i in 0..change.count.unwrap_or(1) {
+            if i > 0 && opens_line {
+                self.apply_action(Actions::NewLine);
+            }
+            for action in change.typed.iter().cloned() {
+                self.apply_action(action);
+            }
+        }
+        if self.mode == Mode::Insert {
+            self.apply_action(Actions::EnterMode(Mode::Normal));
+        }
+        self.replaying = false;
+        self.last_change = Some(change);
+    }
+
+    fn play_macro(&mut self, name: char, count: usize) {
+        let name = match (name, self.last_macro) {
+            ('@', Some(last)) => last,
+            ('@', None) => {
+                self.status_message = Some("No previously used register".to_string());
+                self.failed = true;
+                return;
+            }
+            (name, _) => name,
         };
-        let left = format!("{} > {}{} >", mode_name, filename, modified_marker);
-        // show status_message on right if present, otherwise show Ln/Col/percent
-        let right = if let Some(msg) = &self.status_message {
-            msg.clone()
-        } else {
-            format!("Ln {} Col {}  {}%", line, col, percent)
-        };
-        let status_y = h.saturating_sub(1);
-        let mut status_line = String::new();
-        let left_len = left.len();
-        let right_len = right.len();
-        let total_width = w as usize;
-        if left_len + right_len >= total_width {
-            let available = total_width.saturating_sub(left_len + 1);
-            status_line.push_str(&left);
-            if available > 0 {
-                let truncated = &right[..available.min(right.len())];
-                status_line.push_str(truncated);
+        let register = match self.read_register(name) {
+            Ok(register) => register,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                self.failed = true;
+                return;
             }
-        } else {
-            status_line.push_str(&left);
-            let pad = total_width.saturating_sub(left_len + right_len);
-            for _ in 0..pad { status_line.push(' '); }
-            status_line.push_str(&right);
+        };
+        self.last_macro = Some(name);
+        if name == ':' {
+            for _ in 0..count {
+                self.execute_command(&register.text);
+            }
+            return;
         }
-        let bar_bg = Color::DarkGrey;
-        let mode_color = match self.mode {
-            Mode::Normal => Color::Magenta,
-            Mode::Insert => Color::Cyan,
+        // a macro yanked back from a line keeps the line break; it is not a key
+        let keys = keymap::keys(register.text.strip_suffix('\n').unwrap_or(&register.text));
+        for _ in 0..count {
+            if !self.type_keys(&keys) {
+                break;
+            }
+        }
+    }
+
+    // Handles `keys` as if typed, for macros and `:normal`. Stops early, and
+    // returns false, when one fails; like vim, a motion that cannot move
+    // ends a macro.
+    fn type_keys(&mut self, keys: &[Key]) -> bool {
+        if self.macro_depth == MAX_MACRO_DEPTH {
+            self.failed = true;
+            return false;
+        }
+        self.macro_depth += 1;
+        let mut finished = true;
+        for key in keys {
+            self.failed = false;
+            if let Some(action) = self.handle_event(Event::Key((*key).into())) {
+                self.apply_action(action);
+            }
+            if self.failed || self.should_quit {
+                finished = false;
+                break;
+            }
+        }
+        self.macro_depth -= 1;
+        finished
+    }
+
+    // moves the cursor to the first match of the pattern being typed
+    fn preview_search(&mut self) {
+        let Some(prompt) = &self.search_prompt else { return };
+        let (origin, forward, count) = (prompt.origin, prompt.forward, prompt.count);
+        self.preview = Pattern::new(&self.command_line.text).ok().filter(|_| !self.command_line.text.is_empty());
+        let found = self.preview.as_ref().and_then(|pattern| pattern.find(&self.buffer, origin, forward, count).ok());
+        self.set_cursor(found.map_or(origin, |(to, _)| to));
+    }
+
+    // where the last pattern's `count`th match from `from` is, saying so in
+    // the status line
+    fn search_from(&mut self, from: (usize, usize), forward: bool, count: Option<usize>) -> Option<(usize, usize)> {
+        let Some(pattern) = &self.search else {
+            self.status_message = Some("No previous regular expression".to_string());
+            return None;
         };
-        stdout.queue(MoveTo(0, status_y))?;
-        stdout.queue(SetBackgroundColor(bar_bg))?;
-        let filler = " ".repeat(total_width);
-        stdout.queue(Print(&filler))?;
-        stdout.queue(MoveTo(0, status_y))?;
-        stdout.queue(SetForegroundColor(mode_color))?;
-        stdout.queue(Print(&left))?;
-        let right_x = (w as usize).saturating_sub(right.len() as usize) as u16;
-        stdout.queue(MoveTo(right_x, status_y))?;
-        stdout.queue(Print(&right))?;
-        stdout.queue(ResetColor)?;
-        let cx = self.cx.min(w.saturating_sub(1));
-        let cy = self.cy.min(h.saturating_sub(1));
-        stdout.queue(MoveTo(cx, cy))?;
-        stdout.flush()?;
-        Ok(())
+        self.highlight = true;
+        match pattern.find(&self.buffer, from, forward, count) {
+            Ok((to, wrapped)) => {
+                self.status_message = Some(match (wrapped, forward) {
+                    (false, _) => format!("{}{}", if forward { '/' } else { '?' }, pattern.text),
+                    (true, true) => "search hit BOTTOM, continuing at TOP".to_string(),
+                    (true, false) => "search hit TOP, continuing at BOTTOM".to_string(),
+                });
+                Some(to)
+            }
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Resolves `;`, `,` and searches, and finds where `motion` goes from the cursor.
+    fn motion_target(&mut self, motion: Motion, count: Option<usize>, past_end: bool) -> Option<(Motion, (usize, usize))> {
+        let cursor = (self.cy, self.cx);
+        let (motion, from) = match motion {
+            Motion::SearchNext { reverse } => {
+                let forward = self.search_forward != reverse;
+                return self.search_from(cursor, forward, count).map(|to| (motion, to));
+            }
+            Motion::SearchWord { forward } => {
+                let line = self.buffer.get_line(self.cy).ok()?;
+                let Some((start, word)) = search::word_at(&line, self.cx) else {
+                    self.status_message = Some("No string under cursor".to_string());
+                    return None;
+                };
+                let pattern = Pattern::word(&word);
+                self.search_history.add(&pattern.text);
+                self.search = Some(pattern);
+                self.search_forward = forward;
+                // `#` looks before the word, not at its start
+                let from = if forward { cursor } else { (self.cy, start) };
+                return self.search_from(from, forward, count).map(|to| (motion, to));
+            }
+            Motion::RepeatFind { reverse } => {
+                let Some(Motion::Find { target, forward, till }) = self.last_find else {
+                    return None;
+                };
+                let forward = forward != reverse;
+                // a repeated `t` must not stop before the same character again
+                let from = match (till, forward) {
+                    (true, true) => (self.cy, self.cx + 1),
+                    (true, false) if self.cx > 0 => (self.cy, self.cx - 1),
+                    _ => cursor,
+                };
+                (Motion::Find { target, forward, till }, from)
+            }
+            Motion::Find { .. } => {
+                self.last_find = Some(motion);
+                (motion, cursor)
+            }
+            // without 'wrap' a screen row is a line
+            Motion::ScreenLine { down } if !self.settings.wrap => (if down { Motion::Down } else { Motion::Up }, cursor),
+            Motion::ScreenLine { down } => return self.screen_line(down, count).map(|to| (motion, to)),
+            _ => (motion, cursor),
+        };
+        let to = motion.apply(&self.buffer, from, count, past_end)?;
+        Some((motion, to))
+    }
+
+    // the screen row of the current line that the cursor is on, and the line
+    fn cursor_row(&self) -> (Row, String) {
+        let text = self.buffer.get_line(self.cy).unwrap_or_default().into_owned();
+        let rows = line_rows(&self.settings, &text, self.window_rect().width as usize, 0);
+        let row = rows.get(row_of(&rows, self.cx)).copied().unwrap_or(Row { start: 0, end: 0, x: 0 });
+        (row, text)
+    }
+
+    // How many cells across the window the cursor wants to be: `want_col`
+    // measured from the start of the row it is on.
+    fn want_across(&self) -> usize {
+        let (row, text) = self.cursor_row();
+        row.x.saturating_add(self.want_col.saturating_sub(text::display_col(&text, row.start)))
+    }
+
+    // `gj` and `gk` with 'wrap': `count` screen rows down or up, keeping as
+    // far across the window as the cursor wants to be.
+    fn screen_line(&self, down: bool, count: Option<usize>) -> Option<(usize, usize)> {
+        let width = self.window_rect().width as usize;
+        let rows_of = |line: usize| {
+            let text = self.buffer.get_line(line).unwrap_or_default();
+            (line_rows(&self.settings, &text, width, 0), text)
+        };
+        let (mut line, (mut rows, mut text)) = (self.cy, rows_of(self.cy));
+        let mut index = row_of(&rows, self.cx);
+        let across = self.want_across();
+        let mut moved = false;
+        for _ in 0..count.unwrap_or(1).max(1) {
+            if down && index + 1 < rows.len() {
+                index += 1;
+            } else if down && line + 1 < self.buffer.len() {
+                line += 1;
+                (rows, text) = rows_of(line);
+                index = 0;
+            } else if !down && index > 0 {
+                index -= 1;
+            } else if !down && line > 0 {
+                line -= 1;
+                (rows, text) = rows_of(line);
+                index = rows.len() - 1;
+            } else {
+                break;
+            }
+            moved = true;
+        }
+        if !moved {
+            return None;
+        }
+        let row = rows[index];
+        let col = text::col_at_display(&text, text::display_col(&text, row.start) + across.saturating_sub(row.x));
+        // the last column of a row, not the first of the next
+        Some((line, if index + 1 < rows.len() { col.min(row.end - 1) } else { col }))
+    }
+
+    fn operate(&mut self, op: Operator, target: Target, count: Option<usize>) {
+        let cursor = (self.cy, self.cx);
+        let mut range = match target {
+            Target::Lines => {
+                let last = (self.cy + count.unwrap_or(1).max(1) - 1).min(self.buffer.len() - 1);
+                Range::lines(self.cy, last)
+            }
+            Target::Selection if self.mode == Mode::VisualBlock => {
+                let block = self.block();
+                self.mode = Mode::Normal;
+                return self.operate_block(op, block);
+            }
+            Target::Selection => {
+                let range = self.selection();
+                self.mode = Mode::Normal;
+                range
+            }
+            Target::Object(object) => match object.range(&self.buffer, cursor, count) {
+                Some(range) => range,
+                None => {
+                    self.failed = true;
+                    return;
+                }
+            },
+            Target::Motion(m) => {
+                let change_word = match m {
+                    Motion::WordForward { big } if op == Operator::Change => {
+                        motion::change_word_target(&self.buffer, cursor, big, count)
+                            .map(|to| (Motion::WordEnd { big }, to))
+                    }
+                    _ => None,
+                };
+                match change_word.or_else(|| self.motion_target(m, count, true)) {
+                    Some((m, to)) => Range::from_motion(&self.buffer, cursor, to, m.kind()),
+                    None => {
+                        self.failed = true;
+                        return;
+                    }
+                }
+            }
+        };
+        if op.is_linewise() {
+            range.linewise = true;
+        }
+        let (first, last) = (range.start.0, range.end.0);
+
+        let kind = if range.linewise { RegisterKind::Linewise } else { RegisterKind::Charwise };
+
+        self.buffer.begin_undo_group(cursor);
+        let result = match op {
+            Operator::Yank => range.text(&self.buffer).map(|text| {
+                self.store_register(Register { text, kind }, true);
+                self.set_cursor(range.start);
+            }),
+            Operator::Delete if range.linewise => self.buffer.delete_lines(first, last).map(|text| {
+                self.store_register(Register { text, kind }, false);
+                let line = first.min(self.buffer.len() - 1);
+                self.set_cursor((line, motion::first_non_blank(&self.buffer, line)));
+            }),
+            Operator::Delete | Operator::Change => range.charwise(&self.buffer)
+                .and_then(|Range { start, end, .. }| {
+                    // `cc` keeps an empty line to type into
+                    let text = self.buffer.delete_text(start, end)?;
+                    let text = if range.linewise { text + "\n" } else { text };
+                    self.store_register(Register { text, kind }, false);
+                    self.set_cursor(start);
+                    Ok(())
+                }),
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                operator::shift(&mut self.buffer, first, last, op == Operator::ShiftRight)
+            }
+            Operator::Reindent => operator::reindent(&mut self.buffer, first, last),
+            Operator::Lowercase | Operator::Uppercase => {
+                operator::change_case(&mut self.buffer, &range, op == Operator::Uppercase)
+                    .map(|()| self.set_cursor(range.start))
+            }
+        };
+        if op.is_linewise() {
+            self.set_cursor((first, motion::first_non_blank(&self.buffer, first)));
+        }
+        if let Err(e) = &result {
+            warn!("Error applying {:?}: {}", op, e);
+            self.status_message = Some(format!("Error: {}", e));
+            self.failed = true;
+        }
+        if op == Operator::Change && result.is_ok() {
+            // the insert that follows belongs to the same undo step
+            self.mode = Mode::Insert;
+        } else {
+            self.buffer.end_undo_group();
+            self.clamp_normal_cursor();
+        }
+    }
+
+    /// The Visual or Visual-Line selection as an operator range.
+    fn selection(&self) -> Range {
+        let cursor = (self.cy, self.cx);
+        let (start, end) = if cursor < self.visual_start { (cursor, self.visual_start) } else { (self.visual_start, cursor) };
+        if self.mode == Mode::VisualLine {
+            return Range::lines(start.0, end.0);
+        }
+        // the character under the end is selected, or the line break past the last one
+        let len = self.buffer.line_length(end.0).unwrap_or(0);
+        let end = if end.1 < len {
+            (end.0, end.1 + 1)
+        } else if end.0 + 1 < self.buffer.len() {
+            (end.0 + 1, 0)
+        } else {
+            (end.0, len)
+        };
+        Range { start, end, linewise: false }
+    }
+
+    fn block(&self) -> Block {
+        // screen columns covered by the character at `pos`
+        let cells = |(line, col): (usize, usize)| {
+            let text = self.buffer.get_line(line).unwrap_or_default();
+            let left = text::display_col(&text, col);
+            (left, left.max(text::display_col(&text, col + 1)).max(left + 1))
+        };
+        let (a, b) = (cells(self.visual_start), cells((self.cy, self.cx)));
+        Block {
+            first: self.visual_start.0.min(self.cy),
+            last: self.visual_start.0.max(self.cy),
+            left: a.0.min(b.0),
+            right: a.1.max(b.1),
+        }
+    }
+
+    /// Makes `range` the Visual selection, switching to Visual-Line mode
+    /// for whole lines.
+    fn select(&mut self, range: Range) {
+        if range.start == range.end {
+            return;
+        }
+        if range.linewise {
+            self.mode = Mode::VisualLine;
+            self.visual_start = range.start;
+            self.set_cursor(range.end);
+            return;
+        }
+        if self.mode == Mode::VisualLine {
+            self.mode = Mode::Visual;
+        }
+        self.visual_start = range.start;
+        let (line, col) = range.end;
+        // the end is exclusive; select up to the character before it
+        let last = match col {
+            0 => (line - 1, self.buffer.line_length(line - 1).unwrap_or(0)),
+            col => (line, col - 1),
+        };
+        self.set_cursor(last);
+    }
+
+    fn insert_selection(&mut self, append: bool) {
+        if self.mode != Mode::VisualBlock {
+            let range = self.selection().charwise(&self.buffer).unwrap_or(self.selection());
+            self.mode = Mode::Normal;
+            self.set_cursor(if append { range.end } else { range.start });
+            self.apply_action(Actions::EnterMode(Mode::Insert));
+            return;
+        }
+        let block = self.block();
+        let column = if append { block.right } else { block.left };
+        self.mode = Mode::Normal;
+        self.buffer.begin_undo_group((self.cy, self.cx));
+        let text = self.buffer.get_line(block.first).unwrap_or_default().into_owned();
+        let width = text::display_width(&text);
+        let col = if width < column && append {
+            let len = text::grapheme_count(&text);
+            self.buffer.insert_text(block.first, len, &" ".repeat(column - width))
+                .map_or(len, |(_, col)| col)
+        } else {
+            text::col_at_display(&text, column)
+        };
+        self.cy = block.first;
+        self.cx = col;
+        self.block_insert = Some(BlockInsert { first: block.first + 1, last: block.last, column, append });
+        self.apply_action(Actions::EnterMode(Mode::Insert));
+    }
+
+    fn finish_block_insert(&mut self, block: BlockInsert) {
+        if self.inserted.is_empty() || self.inserted.contains('\n') {
+            return;
+        }
+        for line in block.first..=block.last {
+            let Ok(text) = self.buffer.get_line(line).map(|text| text.into_owned()) else { break };
+            let width = text::display_width(&text);
+            let result = if width >= block.column {
+                self.buffer.insert_text(line, text::col_at_display(&text, block.column), &self.inserted)
+            } else if block.append {
+                let padded = " ".repeat(block.column - width) + &self.inserted;
+                self.buffer.insert_text(line, text::grapheme_count(&text), &padded)
+            } else {
+                // `I` leaves lines that end before the block alone
+                continue;
+            };
+            if let Err(e) = result {
+                warn!("Error inserting on line {}: {}", line, e);
+            }
+        }
+    }
+
+    fn operate_block(&mut self, op: Operator, block: Block) {
+        let cursor = (self.cy, self.cx);
+        self.buffer.begin_undo_group(cursor);
+        let result = self.apply_block(op, block);
+        let top = self.buffer.get_line(block.first).unwrap_or_default().into_owned();
+        self.set_cursor((block.first, text::col_at_display(&top, block.left)));
+        if let Err(e) = &result {
+            warn!("Error applying {:?}: {}", op, e);
+            self.status_message = Some(format!("Error: {}", e));
+            self.failed = true;
+        }
+        if op == Operator::Change && result.is_ok() {
+            self.block_insert = Some(BlockInsert { first: block.first + 1, last: block.last, column: block.left, append: false });
+            self.apply_action(Actions::EnterMode(Mode::Insert));
+        } else {
+            self.buffer.end_undo_group();
+            self.clamp_normal_cursor();
+        }
+    }
+
+    fn apply_block(&mut self, op: Operator, block: Block) -> Result<(), BufferError> {
+        match op {
+            Operator::Yank | Operator::Delete | Operator::Change => {
+                let mut rows = Vec::new();
+                for line in block.first..=block.last {
+                    let (start, end) = block.cols(&self.buffer.get_line(line)?);
+                    rows.push(self.buffer.text_range((line, start), (line, end))?);
+                    if op != Operator::Yank {
+                        self.buffer.delete_text((line, start), (line, end))?;
+                    }
+                }
+                let register = Register { text: rows.join("\n"), kind: RegisterKind::Blockwise };
+                self.store_register(register, op == Operator::Yank);
+            }
+            Operator::Lowercase | Operator::Uppercase => {
+                for line in block.first..=block.last {
+                    let (start, end) = block.cols(&self.buffer.get_line(line)?);
+                    let range = Range { start: (line, start), end: (line, end), linewise: false };
+                    operator::change_case(&mut self.buffer, &range, op == Operator::Uppercase)?;
+                }
+            }
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                operator::shift(&mut self.buffer, block.first, block.last, op == Operator::ShiftRight)?;
+            }
+            Operator::Reindent => operator::reindent(&mut self.buffer, block.first, block.last)?,
+        }
+        Ok(())
+    }
+
+    fn store_register(&mut self, register: Register, yank: bool) {
+        if let Err(e) = self.registers.store(self.pending_register.take(), register, yank) {
+            self.status_message = Some(e.to_string());
+        }
+    }
+
+    /// The register `name`, including `%` for the file name.
+    fn read_register(&self, name: char) -> Result<Register, RegisterError> {
+        match name {
+            '%' => self.buffer.file.clone().map(Register::charwise).ok_or(RegisterError::Empty('%')),
+            _ => self.registers.get(name),
+        }
+    }
+
+    fn put(&mut self, before: bool, count: usize) {
+        let name = self.pending_register.take().unwrap_or('"');
+        let register = match self.read_register(name) {
+            Ok(register) => register,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        self.buffer.begin_undo_group((self.cy, self.cx));
+        let len = self.buffer.line_length(self.cy).unwrap_or(0);
+        let result = match register.kind {
+            RegisterKind::Linewise => {
+                let text = register.text.repeat(count.max(1));
+                let put = if before {
+                    self.buffer.insert_text(self.cy, 0, &text).map(|_| self.cy)
+                } else {
+                    let below = format!("\n{}", text.strip_suffix('\n').unwrap_or(&text));
+                    self.buffer.insert_text(self.cy, len, &below).map(|_| self.cy + 1)
+                };
+                put.map(|line| self.set_cursor((line, motion::first_non_blank(&self.buffer, line))))
+            }
+            RegisterKind::Charwise => {
+                let text = register.text.repeat(count.max(1));
+                let col = if before { self.cx } else { (self.cx + 1).min(len) };
+                // the cursor ends on the last character put
+                self.buffer.insert_text(self.cy, col, &text)
+                    .map(|(line, col)| self.set_cursor((line, col.saturating_sub(1))))
+            }
+            RegisterKind::Blockwise => {
+                let col = if before { self.cx } else { (self.cx + 1).min(len) };
+                self.put_block(&register.text, col, count.max(1))
+            }
+        };
+        self.buffer.end_undo_group();
+        if let Err(e) = result {
+            warn!("Error putting text: {}", e);
+            self.status_message = Some(format!("Error: {}", e));
+            self.failed = true;
+        }
+    }
+
+    /// Puts each line of a block at the same screen column on successive
+    /// lines from the cursor's, padding short lines and adding lines past the end.
+    fn put_block(&mut self, block: &str, col: usize, count: usize) -> Result<(), BufferError> {
+        let column = text::display_col(&self.buffer.get_line(self.cy)?, col);
+        let rows: Vec<&str> = block.split('\n').collect();
+        let width = rows.iter().map(|row| text::display_width(row)).max().unwrap_or(0);
+        for (i, row) in rows.iter().enumerate() {
+            let line = self.cy + i;
+            if line == self.buffer.len() {
+                let len = self.buffer.line_length(line - 1)?;
+                self.buffer.insert_text(line - 1, len, "\n")?;
+            }
+            let text = self.buffer.get_line(line)?.into_owned();
+            let line_width = text::display_width(&text);
+            let (at, pad) = if line_width < column {
+                (text::grapheme_count(&text), " ".repeat(column - line_width))
+            } else {
+                (text::col_at_display(&text, column), String::new())
+            };
+            let padded = format!("{}{}", row, " ".repeat(width - text::display_width(row)));
+            let mut put = pad + &padded.repeat(count);
+            // nothing after the block on this line, so no padding is needed
+            if at == text::grapheme_count(&text) {
+                put.truncate(put.trim_end_matches(' ').len());
+            }
+            self.buffer.insert_text(line, at, &put)?;
+        }
+        self.set_cursor((self.cy, col));
+        Ok(())
+    }
+
+    /// Runs one line typed in command mode, reporting problems in the status line.
+    pub fn execute_command(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        debug!("Executing command: {}", line);
+        let cmd = match command::parse(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                return;
+            }
+        };
+        let write = |arg: Option<String>, bang: bool| match arg {
+            Some(path) if self.buffer.file.is_none() => Actions::SaveAs(path),
+            Some(path) => Actions::WriteCopy(path),
+            None if bang => Actions::ForceSave,
+            None => Actions::Save,
+        };
+        match cmd.kind {
+            CommandKind::Write => self.apply_action(write(cmd.arg, cmd.bang)),
+            CommandKind::Quit if cmd.bang => self.apply_action(Actions::ForceQuit),
+            CommandKind::Quit => self.apply_action(Actions::Quit),
+            CommandKind::QuitAll => self.apply_action(Actions::QuitAll { force: cmd.bang }),
+            CommandKind::WriteQuit | CommandKind::Exit => {
+                // :x only writes when there is something to write
+                if cmd.kind == CommandKind::WriteQuit || self.buffer.modified || cmd.arg.is_some() {
+                    let action = write(cmd.arg, cmd.bang);
+                    self.failed = false;
+                    self.apply_action(action);
+                    // not even `!` quits when the write failed
+                    if self.failed {
+                        return;
+                    }
+                }
+                self.apply_action(if cmd.bang { Actions::ForceQuit } else { Actions::Quit });
+            }
+            CommandKind::Edit => self.apply_action(Actions::Edit { path: cmd.arg, force: cmd.bang }),
+            CommandKind::NoHighlight => self.highlight = false,
+            CommandKind::Substitute => self.start_substitute(cmd.range, cmd.arg.as_deref().unwrap_or_default()),
+            CommandKind::Delete => {
+                let parsed = command::parse_register_count(cmd.arg.as_deref())
+                    .and_then(|(register, count)| Ok((register, count, self.resolve_range(cmd.range)?)));
+                match parsed {
+                    Ok((register, count, (first, last))) => {
+                        // a count runs from the last line of the range
+                        let (first, last) = match count {
+                            Some(count) => (last, last + count - 1),
+                            None => (first, last),
+                        };
+                        self.set_cursor((first, 0));
+                        self.pending_register = register;
+                        self.operate(Operator::Delete, Target::Lines, Some(last - first + 1));
+                    }
+                    Err(e) => {
+                        self.status_message = Some(e.to_string());
+                        self.failed = true;
+                    }
+                }
+            }
+            CommandKind::Move | CommandKind::Copy => {
+                let copy = cmd.kind == CommandKind::Copy;
+                if let Err(e) = self.transfer_lines(cmd.range, cmd.arg.as_deref().unwrap_or_default(), copy) {
+                    self.status_message = Some(e);
+                    self.failed = true;
+                }
+            }
+            CommandKind::Normal => {
+                let arg = cmd.arg.unwrap_or_default();
+                let keys: Vec<Key> = arg.strip_prefix('!').unwrap_or(&arg).chars().map(Key::char).collect();
+                match cmd.range.map(|range| self.resolve_range(Some(range))) {
+                    None => self.run_normal(&keys),
+                    Some(Ok((first, last))) => {
+                        let lines: Vec<usize> = (first..=last).collect();
+                        self.for_each_line(&lines, |editor| editor.run_normal(&keys));
+                    }
+                    Some(Err(e)) => self.status_message = Some(e.to_string()),
+                }
+            }
+            CommandKind::Global | CommandKind::VGlobal => {
+                let invert = cmd.kind == CommandKind::VGlobal;
+                self.global(cmd.range, cmd.arg.as_deref().unwrap_or_default(), invert);
+            }
+            CommandKind::Earlier => self.earlier_command(false, cmd.arg.as_deref()),
+            CommandKind::Later => self.earlier_command(true, cmd.arg.as_deref()),
+            CommandKind::Set => {
+                self.settings.fileformat = self.buffer.line_ending.name().to_string();
+                self.settings.fileencoding = self.buffer.encoding.name().to_string();
+                let shown = match cmd.arg {
+                    Some(arg) => self.settings.set(&arg),
+                    None => Ok(self.settings.show()),
+                };
+                if let Some(line_ending) = LineEnding::from_name(&self.settings.fileformat) {
+                    self.buffer.set_line_ending(line_ending);
+                }
+                if let Some(encoding) = Encoding::from_name(&self.settings.fileencoding) {
+                    self.buffer.set_encoding(encoding);
+                }
+                match shown {
+                    Ok(shown) if shown.is_empty() => {}
+                    Ok(shown) => self.status_message = Some(shown),
+                    Err(e) => self.status_message = Some(e.to_string()),
+                }
+            }
+            CommandKind::ListBuffers => {
+                let lines = self.buffer_list().iter()
+                    .map(|(number, buffer, line, current)| {
+                        let shown = self.windows_showing(*number) > 0;
+                        let flags = match (*current, shown) {
+                            (true, _) => "%a",
+                            (false, true) => " a",
+                            (false, false) => " h",
+                        };
+                        let modified = if buffer.modified { "+" } else { " " };
+                        format!("{:>3} {} {} \"{}\"  line {}", number, flags, modified, buffer.display_name(), line + 1)
+                    })
+                    .collect();
+                self.pager = Some(Pager { lines, top: 0 });
+            }
+            CommandKind::Buffer | CommandKind::DeleteBuffer => {
+                let number = match cmd.arg.as_deref().map(|arg| self.find_buffer(arg)) {
+                    None => self.buffer_number,
+                    Some(Ok(number)) => number,
+                    Some(Err(e)) => {
+                        self.status_message = Some(e);
+                        return;
+                    }
+                };
+                if cmd.kind == CommandKind::Buffer {
+                    self.switch_buffer(number, cmd.bang);
+                } else {
+                    self.delete_buffer(number, cmd.bang);
+                }
+            }
+            CommandKind::NextBuffer | CommandKind::PreviousBuffer => {
+                self.cycle_buffer(cmd.kind == CommandKind::NextBuffer, cmd.bang);
+            }
+            CommandKind::Split | CommandKind::VSplit => self.split_command(cmd.kind == CommandKind::VSplit, cmd.arg),
+            CommandKind::TabNew => self.new_tab(cmd.arg),
+            CommandKind::TabClose => self.close_tab(cmd.bang),
+            CommandKind::TabNext => self.apply_action(Actions::SwitchTab { forward: true, count: None }),
+            CommandKind::TabPrevious => self.apply_action(Actions::SwitchTab { forward: false, count: None }),
+            CommandKind::Close => self.window_command(WindowCommand::Close { force: cmd.bang }),
+            CommandKind::Only => self.window_command(WindowCommand::Only { force: cmd.bang }),
+            CommandKind::Resize => self.resize_command(false, cmd.arg.as_deref()),
+            CommandKind::Vertical => {
+                // `:vertical` makes the command after it work on columns
+                let arg = cmd.arg.unwrap_or_default();
+                match command::parse(&arg) {
+                    Ok(inner) if inner.kind == CommandKind::Resize => self.resize_command(true, inner.arg.as_deref()),
+                    Ok(inner) if inner.kind == CommandKind::Split => self.split_command(true, inner.arg),
+                    Ok(_) => self.execute_command(&arg),
+                    Err(e) => self.status_message = Some(e.to_string()),
+                }
+            }
+            CommandKind::SaveAs => {
+                let path = cmd.arg.unwrap_or_default();
+                if !cmd.bang && std::path::Path::new(&path).exists() {
+                    self.status_message = Some(format!("File exists (add ! to override): {}", path));
+                } else {
+                    self.apply_action(Actions::SaveAs(path));
+                }
+            }
+        }
+    }
+
+    // `:split` and `:vsplit`, editing `path` in the new window when given
+    fn split_command(&mut self, vertical: bool, path: Option<String>) {
+        let windows = self.windows.len();
+        self.window_command(WindowCommand::Split { vertical });
+        // the new window already shows the current buffer's file
+        let current = |path: &String| self.buffer.file.as_deref().is_some_and(|file| same_file(file, path));
+        if path.as_ref().is_some_and(|path| !current(path)) && self.windows.len() > windows {
+            let failed = std::mem::replace(&mut self.failed, false);
+            self.apply_action(Actions::Edit { path, force: false });
+            // a file that cannot be opened takes its window back with it
+            if self.failed {
+                let message = self.status_message.take();
+                self.close_window(true);
+                self.status_message = message;
+            }
+            self.failed |= failed;
+        }
+    }
+
+    // `:earlier` and `:later` with `N` move N changes, with `Ns`, `Nm`,
+    // `Nh` or `Nd` that much time, and with nothing one change
+    fn earlier_command(&mut self, later: bool, arg: Option<&str>) {
+        let arg = arg.unwrap_or("1");
+        let (digits, unit) = arg.split_at(arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len()));
+        let span = match (digits.parse::<u64>(), unit) {
+            (Ok(n), "") => Span::Changes(n as usize),
+            (Ok(n), "s") => Span::Seconds(n),
+            (Ok(n), "m") => Span::Seconds(n.saturating_mul(60)),
+            (Ok(n), "h") => Span::Seconds(n.saturating_mul(60 * 60)),
+            (Ok(n), "d") => Span::Seconds(n.saturating_mul(24 * 60 * 60)),
+            _ => {
+                self.status_message = Some(CommandError::TrailingCharacters(arg.to_string()).to_string());
+                return;
+            }
+        };
+        self.apply_action(if later { Actions::Later(span) } else { Actions::Earlier(span) });
+    }
+
+    // `:resize` with `+N` or `-N` grows or shrinks the window, with `N` sets
+    // its size, and with nothing makes it as big as it can be
+    fn resize_command(&mut self, vertical: bool, arg: Option<&str>) {
+        let command = match arg {
+            None => WindowCommand::SetSize { vertical, size: None },
+            Some(arg) => {
+                let Ok(n) = arg.trim_start_matches(['+', '-']).parse::<i32>() else {
+                    self.status_message = Some(CommandError::TrailingCharacters(arg.to_string()).to_string());
+                    return;
+                };
+                match arg.chars().next() {
+                    Some('+') => WindowCommand::Resize { vertical, delta: n },
+                    Some('-') => WindowCommand::Resize { vertical, delta: -n },
+                    _ => WindowCommand::SetSize { vertical, size: Some(n as usize) },
+                }
+            }
+        };
+        self.window_command(command);
+    }
+
+    /// The line number `address` names, counted from 1; 0 is above the
+    /// first line.
+    fn resolve_address(&self, address: Address) -> Result<usize, CommandError> {
+        let line = match address.line {
+            LineSpec::Number(n) => n,
+            LineSpec::Current => self.cy + 1,
+            LineSpec::Last => self.buffer.len(),
+            LineSpec::Mark(name) => self.marks.get(&name).ok_or(CommandError::MarkNotSet(name))?.0 + 1,
+        };
+        line.checked_add_signed(address.offset)
+            .filter(|&line| line <= self.buffer.len())
+            .ok_or(CommandError::InvalidRange)
+    }
+
+    /// The first and last line of `range`; without one, the cursor line.
+    fn resolve_range(&self, range: Option<LineRange>) -> Result<(usize, usize), CommandError> {
+        let Some(range) = range else { return Ok((self.cy, self.cy)) };
+        let resolve = |address| self.resolve_address(address).map(|line| line.saturating_sub(1));
+        let (start, end) = (resolve(range.start)?, resolve(range.end)?);
+        // vim asks before turning a backwards range around; this just does it
+        Ok((start.min(end), start.max(end)))
+    }
+
+    // `:m` and `:t`: moves or copies the range's lines to below `target`.
+    fn transfer_lines(&mut self, range: Option<LineRange>, target: &str, copy: bool) -> Result<(), String> {
+        let (first, last) = self.resolve_range(range).map_err(|e| e.to_string())?;
+        let mut target = command::parse_target(target)
+            .and_then(|address| self.resolve_address(address))
+            .map_err(|e| e.to_string())?;
+        let count = last - first + 1;
+        if !copy && target > first && target <= last {
+            return Err("Cannot move a range of lines into itself".to_string());
+        }
+        if copy || (target != first && target != last + 1) {
+            let lines: Result<Vec<_>, _> = (first..=last).map(|line| self.buffer.get_line(line).map(|text| text.into_owned())).collect();
+            let text = lines.map_err(|e| e.to_string())?.join("\n");
+            self.buffer.begin_undo_group((self.cy, self.cx));
+            let mut result = Ok(());
+            if !copy {
+                result = self.buffer.delete_lines(first, last).map(|_| ());
+                if target > last {
+                    target -= count;
+                }
+            }
+            // after line `target`, counted from 1, or above the first line for 0
+            let result = result.and_then(|()| match target {
+                0 => self.buffer.insert_text(0, 0, &format!("{}\n", text)),
+                _ => {
+                    let len = self.buffer.line_length(target - 1)?;
+                    self.buffer.insert_text(target - 1, len, &format!("\n{}", text))
+                }
+            });
+            self.buffer.end_undo_group();
+            result.map_err(|e| e.to_string())?;
+        } else {
+            target = last + 1 - count;
+        }
+        let line = target + count - 1;
+        self.set_cursor((line, motion::first_non_blank(&self.buffer, line)));
+        Ok(())
+    }
+
+    // `:normal`: handles `keys` as Normal mode commands, then drops an
+    // unfinished one and leaves Insert mode, as vim does
+    fn run_normal(&mut self, keys: &[Key]) {
+        self.type_keys(keys);
+        self.keys = KeyParser::default();
+        if self.mode != Mode::Normal {
+            self.apply_action(Actions::EnterMode(Mode::Normal));
+        }
+    }
+
+    // Runs `run` with the cursor on each of `lines` in turn, following them
+    // through the edits it makes, all as one undo step.
+    fn for_each_line(&mut self, lines: &[usize], run: impl Fn(&mut Editor)) {
+        self.buffer.begin_undo_block((self.cy, self.cx));
+        let set = self.buffer.track_lines(lines);
+        for index in 0..lines.len() {
+            // lines deleted by earlier runs are skipped
+            let Some(line) = self.buffer.tracked_line(set, index) else { continue };
+            self.set_cursor((line, 0));
+            run(self);
+            if self.should_quit {
+                break;
+            }
+        }
+        self.buffer.untrack_lines();
+        self.buffer.end_undo_block();
+    }
+
+    // `:g/pattern/command`, or `:v` with `invert`: marks the lines in range
+    // that match, or do not, then runs the command on each.
+    fn global(&mut self, range: Option<LineRange>, arg: &str, invert: bool) {
+        let (invert, arg) = match arg.strip_prefix('!') {
+            Some(arg) => (true, arg),
+            None => (invert, arg),
+        };
+        if self.in_global {
+            self.status_message = Some("Cannot do :global recursive".to_string());
+            self.failed = true;
+            return;
+        }
+        let Some(delimiter) = arg.chars().next() else {
+            self.status_message = Some("Regular expression missing from :global".to_string());
+            self.failed = true;
+            return;
+        };
+        let (text, command) = substitute::split_at_delimiter(&arg[delimiter.len_utf8()..], delimiter);
+        let text = match (text.is_empty(), &self.search) {
+            (false, _) => text,
+            (true, Some(last)) => last.text.clone(),
+            (true, None) => {
+                self.status_message = Some("No previous regular expression".to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        let pattern = match Pattern::new(&text) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        // the whole file unless a range is given
+        let range = match range {
+            Some(range) => self.resolve_range(Some(range)),
+            None => Ok((0, self.buffer.len() - 1)),
+        };
+        let (first, last) = match range {
+            Ok(range) => range,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        let lines: Vec<usize> = (first..=last)
+            .filter(|&line| self.buffer.get_line(line).is_ok_and(|text| pattern.regex().is_match(&text)) != invert)
+            .collect();
+        self.search_history.add(&text);
+        self.search = Some(pattern);
+        if lines.is_empty() {
+            self.status_message = Some(if invert {
+                format!("Pattern found in every line: {}", text)
+            } else {
+                format!("Pattern not found: {}", text)
+            });
+            self.failed = true;
+            return;
+        }
+        let command = command.unwrap_or_default().trim();
+        if command.is_empty() {
+            self.status_message = Some(format!("{} matching lines", lines.len()));
+            return;
+        }
+        self.in_global = true;
+        self.for_each_line(&lines, |editor| editor.execute_command(command));
+        self.in_global = false;
+    }
+
+    fn start_substitute(&mut self, range: Option<LineRange>, arg: &str) {
+        let parsed = Substitution::parse(arg).map_err(|e| e.to_string())
+            .and_then(|sub| Ok((sub, self.resolve_range(range).map_err(|e| e.to_string())?)));
+        let (sub, (first, last)) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.status_message = Some(e);
+                self.failed = true;
+                return;
+            }
+        };
+        // an empty pattern is the last one searched for
+        let text = match (sub.pattern.is_empty(), &self.search) {
+            (false, _) => sub.pattern.clone(),
+            (true, Some(last)) => last.text.clone(),
+            (true, None) => {
+                self.status_message = Some("No previous regular expression".to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        if sub.flags.confirm && self.in_global {
+            self.status_message = Some("Cannot ask for confirmation inside :global".to_string());
+            self.failed = true;
+            return;
+        }
+        let pattern = match Pattern::with_case(&text, sub.flags.ignore_case) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                self.failed = true;
+                return;
+            }
+        };
+        self.search_history.add(&text);
+        self.search = Some(pattern.clone());
+        self.highlight = true;
+        // every replacement is undone together
+        self.buffer.begin_undo_group((self.cy, self.cx));
+        self.substituting = Some(Substituting {
+            pattern,
+            replacement: sub.replacement,
+            flags: sub.flags,
+            line: first,
+            byte: 0,
+            last,
+            found: false,
+            count: 0,
+            lines: 0,
+            counted: None,
+        });
+        self.substitute(None);
+    }
+
+    // Goes on with the `:s` under way, up to the next match to ask about or
+    // the end of its range. `answer` is about the match it stopped at.
+    fn substitute(&mut self, mut answer: Option<Confirm>) {
+        let Some(mut sub) = self.substituting.take() else { return };
+        while sub.line <= sub.last {
+            let Ok(text) = self.buffer.get_line(sub.line).map(|text| text.into_owned()) else { break };
+            let found = (sub.byte <= text.len()).then(|| sub.pattern.regex().captures_at(&text, sub.byte));
+            let Some(captures) = found.flatten() else {
+                sub.line += 1;
+                sub.byte = 0;
+                continue;
+            };
+            sub.found = true;
+            let (start, end) = (captures.get_match().start(), captures.get_match().end());
+            let start_col = text::grapheme_col(&text, start);
+            if sub.flags.confirm && !sub.flags.count_only && answer.is_none() {
+                self.set_cursor((sub.line, start_col));
+                self.prompt = Some(Prompt::Substitute);
+                sub.byte = start;
+                self.substituting = Some(sub);
+                return;
+            }
+            let answer = answer.take();
+            if answer == Some(Confirm::Quit) {
+                break;
+            }
+            if answer == Some(Confirm::All) {
+                sub.flags.confirm = false;
+            }
+            let matched = (sub.line, start);
+            let (line, mut next, line_text) = if answer == Some(Confirm::No) || sub.flags.count_only {
+                (sub.line, end, text)
+            } else {
+                let replacement = substitute::expand(&sub.replacement, &captures);
+                let end_col = text::grapheme_col(&text, end);
+                let replaced = self.buffer.delete_text((sub.line, start_col), (sub.line, end_col))
+                    .and_then(|_| if replacement.is_empty() {
+                        Ok((sub.line, start_col))
+                    } else {
+                        self.buffer.insert_text(sub.line, start_col, &replacement)
+                    });
+                let Ok((line, col)) = replaced else { break };
+                // a line break in the replacement moves the rest of the range down
+                sub.last += line - sub.line;
+                let line_text = self.buffer.get_line(line).map(|text| text.into_owned()).unwrap_or_default();
+                let next = text::byte_offset(&line_text, col).unwrap_or(line_text.len());
+                (line, next, line_text)
+            };
+            if answer != Some(Confirm::No) {
+                sub.count += 1;
+                if sub.counted != Some(matched.0) {
+                    sub.lines += 1;
+                }
+                sub.counted = Some(matched.0);
+            }
+            // an empty match is stepped over, so it is not found again
+            if start == end {
+                next += line_text[next.min(line_text.len())..].chars().next().map_or(1, char::len_utf8);
+            }
+            if answer == Some(Confirm::Last) {
+                break;
+            }
+            (sub.line, sub.byte) = if sub.flags.global { (line, next) } else { (line + 1, 0) };
+        }
+        self.buffer.end_undo_group();
+        if !sub.found {
+            self.status_message = Some(format!("Pattern not found: {}", sub.pattern.text));
+            self.failed = true;
+            return;
+        }
+        if sub.count == 0 {
+            self.status_message = None;
+            return;
+        }
+        if let Some(line) = sub.counted
+            && !sub.flags.count_only
+        {
+            self.set_cursor((line, motion::first_non_blank(&self.buffer, line)));
+        }
+        let noun = match (sub.flags.count_only, sub.count) {
+            (true, 1) => "match",
+            (true, _) => "matches",
+            (false, 1) => "substitution",
+            (false, _) => "substitutions",
+        };
+        let lines = if sub.lines == 1 { "line" } else { "lines" };
+        self.status_message = Some(format!("{} {} on {} {}", sub.count, noun, sub.lines, lines));
+    }
+
+    /// Adds `buffer` to the buffer list without showing it, as for the second
+    /// and later files named on the command line. Returns its number.
+    pub fn add_buffer(&mut self, buffer: Buffer) -> usize {
+        let number = self.next_number;
+        self.next_number += 1;
+        let prompt = opening_prompt(&buffer);
+        self.stashed.push(Stashed {
+            number,
+            buffer,
+            cursor: (0, 0),
+            row_offset: 0,
+            marks: HashMap::new(),
+            prompt,
+            dismissed: None,
+        });
+        number
+    }
+
+    // switches to a freshly opened buffer
+    fn open(&mut self, buffer: Buffer, keep: bool, message: String) {
+        self.add_buffer(buffer);
+        self.show_buffer(self.stashed.len() - 1, keep);
+        if self.prompt.is_none() {
+            self.status_message = Some(message);
+        }
+    }
+
+    // Every buffer in the list by number: its number, the buffer, the line
+    // its cursor is on, and whether it is the current one.
+    fn buffer_list(&self) -> Vec<(usize, &Buffer, usize, bool)> {
+        let mut list: Vec<_> = self.stashed.iter()
+            .map(|stashed| (stashed.number, &stashed.buffer, stashed.cursor.0, false))
+            .collect();
+        let at = list.partition_point(|(number, ..)| *number < self.buffer_number);
+        list.insert(at, (self.buffer_number, &self.buffer, self.cy, true));
+        list
+    }
+
+    // Quits unless a buffer has unsaved changes, which are listed instead;
+    // with `force` they are dropped.
+    fn quit_all(&mut self, force: bool) {
+        let unsaved: Vec<String> = self.buffer_list().iter()
+            .filter(|(_, buffer, ..)| buffer.modified && !force)
+            .map(|(number, buffer, ..)| format!("{} \"{}\"", number, buffer.display_name()))
+            .collect();
+        match unsaved.len() {
+            0 => {
+                info!("Quit requested");
+                self.should_quit = true;
+            }
+            1 if self.buffer.modified => {
+                self.status_message = Some("No write since last change (add ! to override)".to_string());
+            }
+            1 => self.status_message = Some(format!("No write since last change for buffer {} (add ! to override)", unsaved[0])),
+            _ => self.status_message = Some(format!("No write since last change for buffers {} (add ! to override)", unsaved.join(", "))),
+        }
+    }
+
+    // Whether the current buffer may be left for another. Changes go with
+    // it into the background only when 'hidden' is set or with `!`, unless
+    // another window still shows them.
+    fn can_hide(&mut self, force: bool) -> bool {
+        let shown = self.windows_showing(self.buffer_number) > 0;
+        if self.buffer.modified && !force && !self.settings.hidden && !shown {
+            self.status_message = Some("No write since last change (add ! to override)".to_string());
+            return false;
+        }
+        true
+    }
+
+    // Makes the stashed buffer at `index` current. The one it replaces is
+    // stashed, or dropped from the list unless `keep`.
+    fn show_buffer(&mut self, index: usize, keep: bool) {
+        let next = self.stashed.remove(index);
+        let left = Stashed {
+            number: std::mem::replace(&mut self.buffer_number, next.number),
+            buffer: std::mem::replace(&mut self.buffer, next.buffer),
+            cursor: (self.cy, self.cx),
+            row_offset: self.row_offset,
+            marks: std::mem::replace(&mut self.marks, next.marks),
+            prompt: std::mem::replace(&mut self.prompt, next.prompt),
+            dismissed: std::mem::replace(&mut self.dismissed, next.dismissed),
+        };
+        (self.cy, self.cx) = next.cursor;
+        self.row_offset = next.row_offset;
+        self.col_offset = 0;
+        self.want_col = self.cx;
+        self.clamp_normal_cursor();
+        // a buffer another window shows stays in the list whatever happens
+        if keep || self.windows_showing(left.number) > 0 {
+            let at = self.stashed.partition_point(|stashed| stashed.number < left.number);
+            self.stashed.insert(at, left);
+        } else {
+            info!("Unloading buffer {}", left.number);
+        }
+    }
+
+    // what `:b` says about the buffer it switched to
+    fn buffer_message(&self) -> String {
+        let modified = if self.buffer.modified { " [Modified]" } else { "" };
+        format!("\"{}\"{}", self.buffer.display_name(), modified)
+    }
+
+    // `:b` with a number, or with part of a name that only one buffer has
+    fn find_buffer(&self, arg: &str) -> Result<usize, String> {
+        let list = self.buffer_list();
+        if let Ok(number) = arg.parse::<usize>() {
+            return list.iter()
+                .find(|(n, ..)| *n == number)
+                .map(|(n, ..)| *n)
+                .ok_or_else(|| format!("Buffer {} does not exist", number));
+        }
+        let names: Vec<(usize, String)> = list.iter().map(|(n, buffer, ..)| (*n, buffer.display_name())).collect();
+        if let Some((number, _)) = names.iter().find(|(_, name)| name == arg) {
+            return Ok(*number);
+        }
+        let matching: Vec<usize> = names.iter().filter(|(_, name)| name.contains(arg)).map(|(n, _)| *n).collect();
+        match matching[..] {
+            [number] => Ok(number),
+            [] => Err(format!("No matching buffer for {}", arg)),
+            _ => Err(format!("More than one match for {}", arg)),
+        }
+    }
+
+    fn switch_buffer(&mut self, number: usize, force: bool) {
+        let Some(index) = self.stashed.iter().position(|stashed| stashed.number == number) else {
+            // already the current one
+            return;
+        };
+        if self.can_hide(force) {
+            self.show_buffer(index, true);
+            self.status_message = Some(self.buffer_message());
+        }
+    }
+
+    // `:bn` and `:bp`, going round from the last buffer to the first
+    fn cycle_buffer(&mut self, forward: bool, force: bool) {
+        let numbers: Vec<usize> = self.buffer_list().iter().map(|(number, ..)| *number).collect();
+        let at = numbers.iter().position(|&number| number == self.buffer_number).unwrap_or(0);
+        let next = if forward { (at + 1) % numbers.len() } else { (at + numbers.len() - 1) % numbers.len() };
+        self.switch_buffer(numbers[next], force);
+    }
+
+    // `:bd`: takes a buffer off the list. The current one gives way to the
+    // next one, or to an empty buffer when it was the only one.
+    fn delete_buffer(&mut self, number: usize, force: bool) {
+        let modified = self.buffer_list().iter().any(|(n, buffer, ..)| *n == number && buffer.modified);
+        if modified && !force {
+            self.status_message = Some(format!("No write since last change for buffer {} (add ! to override)", number));
+            return;
+        }
+        info!("Deleting buffer {}", number);
+        // the windows showing it close too, as long as one is left, and
+        // other tab pages with nothing else in them
+        let showing: Vec<usize> = self.windows.iter().filter(|window| window.buffer == number).map(|window| window.id).collect();
+        for id in showing {
+            if self.layout.remove(id).is_ok() {
+                self.windows.retain(|window| window.id != id);
+            }
+        }
+        let mut index = 0;
+        while index < self.tabs.len() {
+            let tab = &mut self.tabs[index];
+            let showing: Vec<usize> = tab.windows.iter().filter(|window| window.buffer == number).map(|window| window.id).collect();
+            if showing.len() == tab.windows.len() {
+                self.tabs.remove(index);
+                if index < self.tab {
+                    self.tab -= 1;
+                }
+                continue;
+            }
+            for id in showing {
+                if tab.layout.remove(id).is_ok() {
+                    tab.windows.retain(|window| window.id != id);
+                }
+            }
+            if !tab.windows.iter().any(|window| window.id == tab.window_id) {
+                tab.window_id = tab.windows[0].id;
+            }
+            index += 1;
+        }
+        if number != self.buffer_number {
+            self.stashed.retain(|stashed| stashed.number != number);
+            return;
+        }
+        if !self.windows.is_empty() {
+            self.close_window(true);
+            self.stashed.retain(|stashed| stashed.number != number);
+            return;
+        }
+        if self.stashed.is_empty() {
+            self.add_buffer(Buffer::empty());
+        }
+        let next = self.stashed.iter().position(|stashed| stashed.number > number).unwrap_or(self.stashed.len() - 1);
+        self.show_buffer(next, false);
+        self.status_message = Some(self.buffer_message());
+    }
+
+    fn window_command(&mut self, command: WindowCommand) {
+        match command {
+            WindowCommand::Split { vertical } => self.split_window(vertical),
+            WindowCommand::Focus { direction, count } => {
+                for _ in 0..count {
+                    match self.layout.neighbour(self.window_id, direction, self.window_area(), self.screen_cursor()) {
+                        Some(id) => self.focus_window(id),
+                        None => break,
+                    }
+                }
+            }
+            WindowCommand::Cycle { forward, count } => {
+                let order = self.layout.windows();
+                let at = order.iter().position(|&id| id == self.window_id).unwrap_or(0);
+                let next = match count {
+                    Some(n) => n.clamp(1, order.len()) - 1,
+                    None if forward => (at + 1) % order.len(),
+                    None => (at + order.len() - 1) % order.len(),
+                };
+                self.focus_window(order[next]);
+            }
+            WindowCommand::Close { force } => self.close_window(force),
+            WindowCommand::Only { force } => {
+                let others: Vec<(usize, usize)> = self.windows.iter().map(|window| (window.id, window.buffer)).collect();
+                let mut kept = false;
+                for (id, number) in others {
+                    // the last window on a changed buffer only goes with `!` or 'hidden'
+                    let changed = number != self.buffer_number
+                        && self.stashed.iter().any(|stashed| stashed.number == number && stashed.buffer.modified)
+                        && self.windows_showing(number) == 1;
+                    if changed && !force && !self.settings.hidden {
+                        kept = true;
+                        continue;
+                    }
+                    if self.layout.remove(id).is_ok() {
+                        self.windows.retain(|window| window.id != id);
+                    }
+                }
+                if kept {
+                    self.status_message = Some("Other window contains changes (add ! to override)".to_string());
+                }
+            }
+            WindowCommand::Resize { vertical, delta } => self.layout.resize(self.window_id, vertical, delta, self.window_area()),
+            WindowCommand::SetSize { vertical, size } => {
+                let size = size.map_or(u16::MAX, |size| size.min(u16::MAX as usize) as u16);
+                // the count is of text rows, and the status line comes on top
+                let size = if vertical { size } else { size.saturating_add(1) };
+                self.layout.set_size(self.window_id, vertical, size, self.window_area());
+            }
+            WindowCommand::Equalize => self.layout.equalize(),
+        }
+    }
+
+    // how many windows other than the current one show buffer `number`, in
+    // every tab page
+    fn windows_showing(&self, number: usize) -> usize {
+        self.windows.iter()
+            .chain(self.tabs.iter().flat_map(|tab| &tab.windows))
+            .filter(|window| window.buffer == number)
+            .count()
+    }
+
+    // the screen below the tab line, which is only there with more than one tab page
+    fn window_area(&self) -> Rect {
+        if self.tabs.is_empty() {
+            self.screen.area()
+        } else {
+            let screen = self.screen.area();
+            Rect { y: screen.y + 1, height: screen.height.saturating_sub(1), ..screen }
+        }
+    }
+
+    // the current tab page, put away while another one is current
+    fn take_tab(&mut self) -> TabPage {
+        let mut windows = std::mem::take(&mut self.windows);
+        windows.push(self.current_window());
+        let layout = std::mem::replace(&mut self.layout, Layout::Window(self.window_id));
+        TabPage { layout, windows, window_id: self.window_id }
+    }
+
+    fn enter_tab(&mut self, tab: TabPage) {
+        self.layout = tab.layout;
+        self.windows = tab.windows;
+        let index = self.windows.iter().position(|window| window.id == tab.window_id).unwrap_or(0);
+        let window = self.windows.remove(index);
+        self.enter_window(window);
+    }
+
+    // `gt` and friends: makes tab page `index`, counted from 0, current
+    fn goto_tab(&mut self, index: usize) {
+        if index == self.tab || index > self.tabs.len() {
+            return;
+        }
+        let current = self.take_tab();
+        self.tabs.insert(self.tab, current);
+        let next = self.tabs.remove(index);
+        self.tab = index;
+        self.enter_tab(next);
+    }
+
+    // `:tabnew`: a tab page after the current one, with one window on a new
+    // empty buffer or on `path`
+    fn new_tab(&mut self, path: Option<String>) {
+        let current = self.take_tab();
+        self.tabs.insert(self.tab, current);
+        self.tab += 1;
+        self.window_id = self.next_window;
+        self.next_window += 1;
+        self.layout = Layout::Window(self.window_id);
+        match path {
+            // the new window already shows the current buffer
+            Some(path) if self.buffer.file.as_deref().is_some_and(|file| same_file(file, &path)) => {}
+            Some(path) => self.apply_action(Actions::Edit { path: Some(path), force: false }),
+            None => {
+                self.add_buffer(Buffer::empty());
+                self.show_buffer(self.stashed.len() - 1, true);
+            }
+        }
+    }
+
+    // `:tabclose`: the tab page after it, or before it when it was the last,
+    // becomes current
+    fn close_tab(&mut self, force: bool) {
+        if self.tabs.is_empty() {
+            self.status_message = Some("Cannot close last tab page".to_string());
+            return;
+        }
+        // a changed buffer left with no window on it goes only with `!` or 'hidden'
+        let elsewhere = |number: usize| self.tabs.iter().flat_map(|tab| &tab.windows).any(|window| window.buffer == number);
+        let leaving = self.windows.iter().map(|window| window.buffer).chain([self.buffer_number]);
+        let changed = leaving.filter(|&number| !elsewhere(number))
+            .any(|number| self.buffer_by_number(number).is_some_and(|buffer| buffer.modified));
+        if changed && !force && !self.settings.hidden {
+            self.status_message = Some("No write since last change (add ! to override)".to_string());
+            return;
+        }
+        self.windows.clear();
+        let index = self.tab.min(self.tabs.len() - 1);
+        let next = self.tabs.remove(index);
+        self.tab = index;
+        self.enter_tab(next);
+    }
+
+    // Numbers the tab pages along the top row, each named after the buffer
+    // in its current window, with `+` when one of its buffers has changes.
+    fn render_tab_line(&self, screen: &mut Screen) {
+        let width = screen.area().width as usize;
+        let style = Style::default().bg(Color::DarkGrey);
+        screen.fill(Rect { height: 1, ..screen.area() }, style);
+        let mut used = 0;
+        for index in 0..=self.tabs.len() {
+            let (shown, windows): (usize, Vec<usize>) = match index.cmp(&self.tab) {
+                Ordering::Equal => (self.buffer_number, self.windows.iter().map(|window| window.buffer).collect()),
+                order => {
+                    let tab = &self.tabs[if order == Ordering::Less { index } else { index - 1 }];
+                    let current = tab.windows.iter().find(|window| window.id == tab.window_id);
+                    (current.map_or(0, |window| window.buffer), tab.windows.iter().map(|window| window.buffer).collect())
+                }
+            };
+            let modified = windows.iter().chain([&shown])
+                .any(|&number| self.buffer_by_number(number).is_some_and(|buffer| buffer.modified));
+            let name = self.buffer_by_number(shown).map(Buffer::display_name).unwrap_or_default();
+            let label = format!(" {} {}{} ", index + 1, name, if modified { " +" } else { "" });
+            let label = text::truncate_to_width(&label, width.saturating_sub(used));
+            let style = if index == self.tab { style.reverse() } else { style };
+            screen.put(used as u16, 0, label, style);
+            used += text::display_width(label);
+        }
+    }
+
+    // the current window's view, kept while another window is current
+    fn current_window(&self) -> Window {
+        Window {
+            id: self.window_id,
+            buffer: self.buffer_number,
+            cursor: (self.cy, self.cx),
+            row_offset: self.row_offset,
+            col_offset: self.col_offset,
+        }
+    }
+
+    // `:split` and `Ctrl-W s`: a new window on the same buffer becomes current
+    fn split_window(&mut self, vertical: bool) {
+        let id = self.next_window;
+        if let Err(e) = self.layout.split(self.window_id, id, vertical, self.window_area()) {
+            self.status_message = Some(e.to_string());
+            return;
+        }
+        self.next_window += 1;
+        self.windows.push(self.current_window());
+        self.window_id = id;
+    }
+
+    fn focus_window(&mut self, id: usize) {
+        let Some(index) = self.windows.iter().position(|window| window.id == id) else { return };
+        let next = self.windows.remove(index);
+        self.windows.push(self.current_window());
+        self.enter_window(next);
+    }
+
+    // makes `window` current, bringing in the buffer it shows
+    fn enter_window(&mut self, window: Window) {
+        self.window_id = window.id;
+        if window.buffer != self.buffer_number
+            && let Some(index) = self.stashed.iter().position(|stashed| stashed.number == window.buffer)
+        {
+            self.show_buffer(index, true);
+        }
+        (self.cy, self.cx) = window.cursor;
+        self.row_offset = window.row_offset;
+        self.col_offset = window.col_offset;
+        self.want_col = self.cx;
+        self.clamp_normal_cursor();
+    }
+
+    // `:close` and `:q` with more than one window: the window before it in
+    // the layout takes over
+    fn close_window(&mut self, force: bool) {
+        if self.windows.is_empty() && self.tabs.is_empty() {
+            self.status_message = Some(WindowError::LastWindow.to_string());
+            return;
+        }
+        if self.windows.is_empty() {
+            // the last window in a tab page takes the page with it
+            self.close_tab(force);
+            return;
+        }
+        if !self.can_hide(force) {
+            return;
+        }
+        let order = self.layout.windows();
+        let at = order.iter().position(|&id| id == self.window_id).unwrap_or(0);
+        let next = if at > 0 { order[at - 1] } else { order[1] };
+        if let Err(e) = self.layout.remove(self.window_id) {
+            self.status_message = Some(e.to_string());
+            return;
+        }
+        if let Some(index) = self.windows.iter().position(|window| window.id == next) {
+            let next = self.windows.remove(index);
+            self.enter_window(next);
+        }
+    }
+
+    // where the cursor is on screen, for picking the window beside it
+    fn screen_cursor(&self) -> (u16, u16) {
+        let area = self.window_rect();
+        let view = (self.row_offset, self.col_offset);
+        let (x, y) = cursor_cell(&self.settings, &self.buffer, area.width as usize, (self.cy, self.cx), view);
+        let x = area.x as usize + x.min(area.width.saturating_sub(1) as usize);
+        let y = area.y as usize + y.min(area.height.saturating_sub(2) as usize);
+        (x as u16, y as u16)
+    }
+
+    fn window_rect(&self) -> Rect {
+        self.layout.rects(self.window_area()).into_iter()
+            .find(|(id, _)| *id == self.window_id)
+            .map_or(self.window_area(), |(_, rect)| rect)
+    }
+
+    /// Writes a recovery file for every buffer with unsaved changes, when
+    /// the editor is stopped by a signal or an error.
+    pub fn save_recovery(&self) {
+        self.buffer.try_save_recovery();
+        for stashed in &self.stashed {
+            stashed.buffer.try_save_recovery();
+        }
+    }
+
+    /// Housekeeping while waiting for input: refreshes the swap file and
+    /// checks the file on disk. Returns whether anything needs to be redrawn.
+    pub fn idle(&mut self) -> bool {
+        self.buffer.update_swap();
+        for stashed in &mut self.stashed {
+            stashed.buffer.update_swap();
+        }
+        let pending = !self.keys.is_empty();
+        if let Some(action) = self.keys.expire(&self.keymap) {
+            self.apply_action(action);
+        }
+        let expired = pending && self.keys.is_empty();
+        self.check_disk() || expired
+    }
+
+    /// Looks for changes made to the file by other programs. An unmodified
+    /// buffer is reloaded silently; otherwise the user is asked what to do.
+    /// Returns whether anything needs to be redrawn.
+    pub fn check_disk(&mut self) -> bool {
+        if self.prompt.is_some() || self.pager.is_some() {
+            return false;
+        }
+        let change = self.buffer.check_disk();
+        if change == DiskChange::Unchanged || self.dismissed.as_ref() == Some(&change) {
+            return false;
+        }
+        match change {
+            DiskChange::Changed(_) if !self.buffer.modified => self.apply_action(Actions::Reload),
+            DiskChange::Changed(_) => self.prompt = Some(Prompt::FileChanged),
+            _ => {
+                self.status_message = Some(format!("{} was deleted on disk", self.buffer.display_name()));
+                self.dismissed = Some(change);
+            }
+        }
+        true
+    }
+
+    fn finish_undo(&mut self, result: Result<Option<(usize, usize)>, BufferError>, at_end: &str) {
+        match result {
+            Ok(Some(cursor)) => {
+                self.set_cursor(cursor);
+                self.status_message = Some(format!("At change #{}", self.buffer.undo_seq()));
+            }
+            Ok(None) => self.status_message = Some(at_end.to_string()),
+            Err(e) => {
+                warn!("Error moving through undo history: {}", e);
+                self.status_message = Some(format!("Error moving through undo history: {}", e));
+            }
+        }
+    }
+
+    fn set_cursor(&mut self, (line, col): (usize, usize)) {
+        self.cy = line;
+        self.cx = col;
+        self.clamp_cursor();
+    }
+
+    // in Normal mode the cursor sits on a character, not after the last one
+    fn clamp_normal_cursor(&mut self) {
+        self.clamp_cursor();
+        if self.mode == Mode::Normal && self.cx > 0 && self.cx >= self.buffer.line_length(self.cy).unwrap_or(0) {
+            self.cx -= 1;
+        }
+    }
+
+    // keep the cursor inside the buffer after lines changed under it
+    fn clamp_cursor(&mut self) {
+        if self.cy >= self.buffer.len() {
+            self.cy = self.buffer.len().saturating_sub(1);
+        }
+        if let Ok(len) = self.buffer.line_length(self.cy)
+            && self.cx > len
+        {
+            self.cx = len;
+        }
+    }
+    /// The grapheme columns of `line` to highlight, where one past the end
+    /// stands for the line break.
+    fn selected_cols(&self, line: usize, text: &str) -> Option<(usize, usize)> {
+        let len = text::grapheme_count(text);
+        match self.mode {
+            Mode::VisualBlock => {
+                let block = self.block();
+                (block.first..=block.last).contains(&line).then(|| block.cols(text))
+            }
+            Mode::VisualLine => {
+                let range = self.selection();
+                (range.start.0..=range.end.0).contains(&line).then_some((0, len + 1))
+            }
+            Mode::Visual => {
+                let cursor = (self.cy, self.cx);
+                let (start, end) = if cursor < self.visual_start { (cursor, self.visual_start) } else { (self.visual_start, cursor) };
+                if !(start.0..=end.0).contains(&line) {
+                    return None;
+                }
+                let from = if line == start.0 { start.1 } else { 0 };
+                let to = if line == end.0 { end.1 + 1 } else { len + 1 };
+                Some((from.min(len), to.min(len + 1)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn render(&mut self, stdout: &mut impl Write) -> Result<()> {
+        let (w, h) = terminal::size()?;
+        self.screen.resize(w, h);
+        self.draw();
+        self.screen.flush(stdout)?;
+        Ok(())
+    }
+
+    // Draws the whole frame into the screen's back grid; `render` sends
+    // the terminal only what changed since the last one.
+    fn draw(&mut self) {
+        let view = (self.row_offset, self.col_offset);
+        (self.row_offset, self.col_offset) = scroll_view(&self.settings, &self.buffer, self.window_rect(), (self.cy, self.cx), view);
+        let cursor = self.screen_cursor();
+        let rects = self.layout.rects(self.window_area());
+        let mut screen = std::mem::take(&mut self.screen);
+        screen.clear();
+        let Rect { width: w, height: h, .. } = screen.area();
+
+        if let Some(pager) = &self.pager {
+            for (y, line) in pager.lines.iter().skip(pager.top).take(h.saturating_sub(1) as usize).enumerate() {
+                screen.put(0, y as u16, line, Style::default());
+            }
+            let full = screen.area();
+            self.render_status(&mut screen, full);
+        } else {
+            if !self.tabs.is_empty() {
+                self.render_tab_line(&mut screen);
+            }
+            for (id, rect) in rects {
+                if id == self.window_id {
+                    self.render_text(&mut screen, &self.buffer, rect, (self.row_offset, self.col_offset), true);
+                    self.render_status(&mut screen, rect);
+                } else if let Some(index) = self.windows.iter().position(|window| window.id == id) {
+                    let window = &self.windows[index];
+                    let Some(buffer) = self.buffer_by_number(window.buffer) else { continue };
+                    // edits made in another window on the same buffer may have left the cursor past the end
+                    let cursor = (window.cursor.0.min(buffer.len().saturating_sub(1)), window.cursor.1);
+                    let view = scroll_view(&self.settings, buffer, rect, cursor, (window.row_offset, window.col_offset));
+                    self.render_text(&mut screen, buffer, rect, view, false);
+                    render_inactive_status(&mut screen, buffer, cursor, rect);
+                    (self.windows[index].row_offset, self.windows[index].col_offset) = view;
+                }
+                // windows side by side are kept apart by a column of '|'
+                if rect.x + rect.width < w {
+                    for y in rect.y..rect.y + rect.height {
+                        screen.put(rect.x + rect.width, y, "|", Style::default());
+                    }
+                }
+            }
+        }
+        let prefix = match (self.mode, &self.search_prompt) {
+            (Mode::Command, _) => Some(':'),
+            (Mode::Search, Some(prompt)) => Some(if prompt.forward { '/' } else { '?' }),
+            _ => None,
+        };
+        if let Some(prefix) = prefix {
+            // the command line takes over the bottom row
+            let status_y = h.saturating_sub(1);
+            screen.fill(Rect { y: status_y, height: 1, ..screen.area() }, Style::default());
+            screen.put(0, status_y, &format!("{}{}", prefix, self.command_line.text), Style::default());
+            let col = 1 + text::display_col(&self.command_line.text, self.command_line.cursor);
+            screen.set_cursor(col.min(u16::MAX as usize) as u16, status_y);
+        } else {
+            screen.set_cursor(cursor.0, cursor.1);
+        }
+        self.screen = screen;
+    }
+
+    fn buffer_by_number(&self, number: usize) -> Option<&Buffer> {
+        if number == self.buffer_number {
+            return Some(&self.buffer);
+        }
+        self.stashed.iter().find(|stashed| stashed.number == number).map(|stashed| &stashed.buffer)
+    }
+
+    // Draws the lines of `buffer` from `row_offset` into `rect`, above its
+    // status line, wrapped or scrolled `col_offset` cells sideways. Only the
+    // current window shows the Visual selection.
+    fn render_text(&self, screen: &mut Screen, buffer: &Buffer, rect: Rect, (row_offset, col_offset): (usize, usize), current: bool) {
+        let highlight = match self.mode {
+            Mode::Search => self.preview.as_ref(),
+            _ => self.search.as_ref().filter(|_| self.highlight),
+        };
+        let width = rect.width as usize;
+        let bottom = rect.y + rect.height.saturating_sub(1);
+        let mut y = rect.y;
+        for i in row_offset..buffer.len() {
+            let Ok(line) = buffer.get_line(i) else { break };
+            if y >= bottom {
+                break;
+            }
+            let mut painted = Vec::new();
+            if let Some(pattern) = highlight {
+                let style = Style::default().fg(Color::Black).bg(Color::Yellow);
+                painted.extend(pattern.matches(&line).into_iter().map(|cols| (cols, style)));
+            }
+            if current && let Some(cols) = self.selected_cols(i, &line) {
+                painted.push((cols, Style::default().reverse()));
+            }
+            let rows = line_rows(&self.settings, &line, width, col_offset);
+            let skip = if self.settings.wrap && i == row_offset { col_offset } else { 0 };
+            for (index, row) in rows.iter().enumerate().skip(skip) {
+                if y >= bottom {
+                    break;
+                }
+                if index > 0 {
+                    let showbreak = text::truncate_to_width(&self.settings.showbreak, row.x);
+                    screen.put(rect.x, y, showbreak, Style::default().fg(Color::DarkGrey));
+                }
+                let from = text::byte_offset(&line, row.start).unwrap_or(line.len());
+                let to = text::byte_offset(&line, row.end).unwrap_or(line.len());
+                let col = text::display_col(&line, row.start);
+                screen.put_line(rect.x + row.x as u16, y, &line[from..to], col, Style::default());
+                for &(cols, style) in &painted {
+                    paint_cols(screen, &line, row, (rect.x, y), cols, width, style);
+                }
+                y += 1;
+            }
+            // a line that ends left of the view still takes its row
+            if rows.is_empty() {
+                y += 1;
+            }
+        }
+    }
+
+    // the current window's status line, along the bottom row of `rect`
+    fn render_status(&self, screen: &mut Screen, rect: Rect) {
+        let mode_name = match self.mode {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Command => "COMMAND",
+            Mode::Search => "SEARCH",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "V-LINE",
+            Mode::VisualBlock => "V-BLOCK",
+        };
+        let mode_name = match &self.macro_recording {
+            Some((name, _)) => format!("{} recording @{}", mode_name, name),
+            None => mode_name.to_string(),
+        };
+        let filename = self.buffer.display_name();
+        let modified_marker = if self.buffer.modified { "*" } else { "" };
+        let left = format!("{} > {}{} > {}", mode_name, filename, modified_marker, self.keys.pending());
+        // show status_message on right if present, otherwise show Ln/Col/percent
+        let right = if self.pager.is_some() {
+            "j/k scroll, q close".to_string()
+        } else if let Some(prompt) = self.prompt {
+            prompt.text()
+        } else if let Some(msg) = &self.status_message {
+            msg.clone()
+        } else {
+            let bom = if self.buffer.bom { " [BOM]" } else { "" };
+            format!(
+                "{} {}{}  {}",
+                self.buffer.encoding.name(), self.buffer.line_ending.name(), bom, position(&self.buffer, (self.cy, self.cx))
+            )
+        };
+        let mode_color = match self.mode {
+            Mode::Normal => Color::Magenta,
+            Mode::Insert => Color::Cyan,
+            Mode::Command | Mode::Search => Color::Yellow,
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => Color::Green,
+        };
+        print_status(screen, rect, &left, &right, mode_color);
+    }
+}
+
+// One screen row of a line: the grapheme columns `start..end` it shows,
+// from cell `x` of the window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Row {
+    start: usize,
+    end: usize,
+    x: usize,
+}
+
+// The rows `line` takes in a window `width` cells wide. With 'wrap' the
+// rows after the first leave room for 'showbreak'; otherwise the one row is
+// scrolled `col_offset` cells left, and there is none if the line ends first.
+fn line_rows(settings: &Settings, line: &str, width: usize, col_offset: usize) -> Vec<Row> {
+    if settings.wrap {
+        // a 'showbreak' as wide as the window is left out
+        let indent = Some(text::display_width(&settings.showbreak)).filter(|&indent| indent < width).unwrap_or(0);
+        return text::wrap(line, width, width - indent).into_iter().enumerate()
+            .map(|(index, (start, end))| Row { start, end, x: if index == 0 { 0 } else { indent } })
+            .collect();
+    }
+    if text::display_width(line) < col_offset {
+        return Vec::new();
+    }
+    // a wide character cut by the left edge is left out
+    let mut start = text::col_at_display(line, col_offset);
+    if text::display_col(line, start) < col_offset {
+        start += 1;
+    }
+    let x = text::display_col(line, start) - col_offset;
+    // what ends past the right edge is left out too
+    let end = text::col_at_display(line, col_offset + width).max(start);
+    vec![Row { start, end, x }]
+}
+
+// the row of `rows` that column `col` is on
+fn row_of(rows: &[Row], col: usize) -> usize {
+    rows.iter().rposition(|row| row.start <= col).unwrap_or(0)
+}
+
+// Where the cursor at `(cy, cx)` is in a window `width` cells wide showing
+// `buffer` from `(row_offset, col_offset)`, counted from its top left cell.
+fn cursor_cell(settings: &Settings, buffer: &Buffer, width: usize, (cy, cx): (usize, usize), (row_offset, col_offset): (usize, usize)) -> (usize, usize) {
+    let rows_at = |line: usize| buffer.get_line(line).map(|text| line_rows(settings, &text, width, col_offset)).unwrap_or_default();
+    let skipped = if settings.wrap { col_offset } else { 0 };
+    let above = (row_offset..cy).map(|line| rows_at(line).len().max(1)).sum::<usize>();
+    let line = buffer.get_line(cy).unwrap_or_default();
+    let rows = rows_at(cy);
+    let index = row_of(&rows, cx);
+    // wide characters take two cells, so the screen column is not cx
+    let x = rows.get(index).map_or(0, |row| {
+        row.x + text::display_col(&line, cx).saturating_sub(text::display_col(&line, row.start))
+    });
+    (x, (above + index).saturating_sub(skipped))
+}
+
+// The view `(row_offset, col_offset)` of a window on `buffer` in `rect`,
+// moved just enough to show the cursor at `(cy, cx)`.
+fn scroll_view(settings: &Settings, buffer: &Buffer, rect: Rect, (cy, cx): (usize, usize), (row_offset, col_offset): (usize, usize)) -> (usize, usize) {
+    let rows = rect.height.saturating_sub(1) as usize; // leave last line for status
+    let width = rect.width as usize;
+    if !settings.wrap {
+        let line = buffer.get_line(cy).unwrap_or_default();
+        let first = text::display_col(&line, cx);
+        let last = text::display_col(&line, cx + 1).max(first + 1) - 1;
+        return (scrolled(row_offset, cy, rows), scrolled(col_offset, last, width).min(first));
+    }
+    let rows_at = |line: usize| line_rows(settings, &buffer.get_line(line).unwrap_or_default(), width, 0);
+    // every line takes a row at least, so the top line is no further up than this
+    let lowest = cy.saturating_sub(rows.saturating_sub(1));
+    let mut view = match row_offset {
+        top if top > cy => (cy, 0),
+        top if top < lowest => (lowest, 0),
+        top => (top, col_offset.min(rows_at(top).len() - 1)),
+    };
+    while view.0 < cy && cursor_cell(settings, buffer, width, (cy, cx), view).1 >= rows {
+        view = (view.0 + 1, 0);
+    }
+    // a cursor line taller than the window has rows skipped above the cursor's
+    if view.0 == cy {
+        let index = row_of(&rows_at(cy), cx);
+        view.1 = view.1.min(index).max((index + 1).saturating_sub(rows));
+    }
+    view
+}
+
+// the first line to show so that line `cy` is in view in `rows` rows
+fn scrolled(row_offset: usize, cy: usize, rows: usize) -> usize {
+    if cy < row_offset {
+        cy
+    } else if cy >= row_offset + rows {
+        cy.saturating_sub(rows).saturating_add(1)
+    } else {
+        row_offset
+    }
+}
+
+// "Ln 3 Col 7  25%"
+fn position(buffer: &Buffer, (cy, cx): (usize, usize)) -> String {
+    let percent = if buffer.len() <= 1 {
+        100
+    } else {
+        let last = (buffer.len() - 1) as f64;
+        let pct = (cy as f64 / last) * 100.0;
+        pct.round() as u16
+    };
+    format!("Ln {} Col {}  {}%", cy + 1, cx + 1, percent)
+}
+
+// the status line of a window other than the current one, which has no mode or messages
+fn render_inactive_status(screen: &mut Screen, buffer: &Buffer, cursor: (usize, usize), rect: Rect) {
+    let modified_marker = if buffer.modified { "*" } else { "" };
+    let left = format!("{}{}", buffer.display_name(), modified_marker);
+    print_status(screen, rect, &left, &position(buffer, cursor), Color::Grey);
+}
+
+// Fills the bottom row of `rect` with `left` and `right` on a grey bar;
+// `right` is cut short when there is not room for both.
+fn print_status(screen: &mut Screen, rect: Rect, left: &str, right: &str, color: Color) {
+    let width = rect.width as usize;
+    let y = (rect.y + rect.height).saturating_sub(1);
+    let left = text::truncate_to_width(left, width);
+    let available = width.saturating_sub(text::display_width(left) + 1);
+    let right = text::truncate_to_width(right, available);
+    let style = Style::default().fg(color).bg(Color::DarkGrey);
+    screen.fill(Rect { y, height: 1, ..rect }, style);
+    screen.put(rect.x, y, left, style);
+    screen.put(rect.x + (width - text::display_width(right)) as u16, y, right, style);
+}
+
+// Colours what `row` shows of grapheme columns `start..end` of `line`, the
+// row being drawn from `(x, y)`. An end past the line is its line break,
+// shown as one cell after the line's last row.
+fn paint_cols(screen: &mut Screen, line: &str, row: &Row, (x, y): (u16, u16), (start, end): (usize, usize), width: usize, style: Style) {
+    let from = start.max(row.start);
+    let to = end.min(row.end);
+    let mut cells = text::display_col(line, to).saturating_sub(text::display_col(line, from));
+    let len = text::grapheme_count(line);
+    if row.end == len && end > len && start <= len {
+        cells += 1;
+    }
+    let col = row.x + text::display_col(line, from) - text::display_col(line, row.start);
+    if cells == 0 || col >= width {
+        return;
+    }
+    screen.paint(x + col as u16, y, cells.min(width - col) as u16, style);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyEvent, KeyModifiers};
+
+    fn key(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn lines(editor: &Editor) -> Vec<String> {
+        (0..editor.buffer.len()).map(|i| editor.buffer.get_line(i).unwrap().into_owned()).collect()
+    }
+
+    fn feed(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\x1b' => KeyCode::Esc,
+                '\x08' => KeyCode::Backspace,
+                c => KeyCode::Char(c),
+            };
+            let event = match c {
+                '\x16' => Event::Key(KeyEvent::new(KeyCode::Char('v'), KeyModifiers::CONTROL)),
+                '\x17' => Event::Key(KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL)),
+                _ => key(code),
+            };
+            if let Some(action) = editor.handle_event(event) {
+                editor.apply_action(action);
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_session_is_one_undo_step() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ihello\nworld\x08\x1b");
+        assert_eq!(lines(&editor), vec!["hello", "worl"]);
+
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), vec![""]);
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+        assert!(!editor.buffer.modified);
+
+        let redo = Event::Key(KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL));
+        let action = editor.handle_event(redo).unwrap();
+        editor.apply_action(action);
+        assert_eq!(lines(&editor), vec!["hello", "worl"]);
+    }
+
+    #[test]
+    fn test_cursor_moves_by_grapheme() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        // the two regional indicators merge into one flag as they are typed
+        feed(&mut editor, "iñandú 🇫🇷\x1b");
+        assert_eq!(editor.cx, 7);
+
+        feed(&mut editor, "hhh");
+        assert_eq!(editor.cx, 4);
+        feed(&mut editor, "i\x08\n\x1b");
+        assert_eq!(lines(&editor), vec!["ñan", "ú 🇫🇷"]);
+        assert_eq!((editor.cy, editor.cx), (1, 0));
+    }
+
+    #[test]
+    fn test_disk_changes_reload_or_prompt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.txt");
+        std::fs::write(&path, "old\n").unwrap();
+        let buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        let mut editor = Editor::with_buffer(buffer);
+
+        std::fs::write(&path, "new text\n").unwrap();
+        assert!(editor.check_disk());
+        assert_eq!(lines(&editor), vec!["new text"]);
+        assert_eq!(editor.prompt, None);
+
+        feed(&mut editor, "ix\x1b");
+        std::fs::write(&path, "newer text\n").unwrap();
+        assert!(editor.check_disk());
+        assert_eq!(editor.prompt, Some(Prompt::FileChanged));
+        feed(&mut editor, "d");
+        assert!(editor.pager.is_some());
+        feed(&mut editor, "qi");
+        assert_eq!(editor.prompt, None);
+        assert_eq!(lines(&editor), vec!["xnew text"]);
+        // an ignored change is not asked about again
+        assert!(!editor.check_disk());
+    }
+
+    #[test]
+    fn test_reload_during_insert_is_its_own_undo_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.txt");
+        std::fs::write(&path, "old\n").unwrap();
+        let buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        let mut editor = Editor::with_buffer(buffer);
+
+        feed(&mut editor, "i");
+        std::fs::write(&path, "new text\n").unwrap();
+        assert!(editor.check_disk());
+        assert_eq!(lines(&editor), vec!["new text"]);
+        feed(&mut editor, "xy\x1b");
+        assert_eq!(lines(&editor), vec!["xynew text"]);
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), vec!["new text"]);
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), vec!["old"]);
+    }
+
+    #[test]
+    fn test_ex_commands_write_and_refuse_to_quit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt").to_string_lossy().to_string();
+        let state = tempfile::tempdir().unwrap();
+        crate::buffer::TEST_STATE_DIR.set(Some(state.path().to_path_buf()));
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ihi\x1b:q\n");
+        assert!(!editor.should_quit);
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change (add ! to override)"));
+
+        feed(&mut editor, ":bogus\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Not an editor command: bogus"));
+        assert_eq!(editor.mode, Mode::Normal);
+
+        // an unnamed buffer takes the name it is first written to
+        feed(&mut editor, &format!(":w {}\n", path));
+        assert_eq!(editor.buffer.file.as_deref(), Some(path.as_str()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hi\n");
+        assert!(editor.buffer.undo_file.as_ref().is_some_and(|undo| undo.starts_with(state.path().join("undo"))));
+        feed(&mut editor, "ix\x1b:x\n");
+        assert!(editor.should_quit);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hix\n");
+    }
+
+    #[test]
+    fn test_earlier_and_later_take_counts() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ia\x1bab\x1bac\x1b:earlier 2\n");
+        assert_eq!(lines(&editor), vec!["a"]);
+        feed(&mut editor, ":lat\n");
+        assert_eq!(lines(&editor), vec!["ab"]);
+        feed(&mut editor, ":ea 1h\n");
+        assert_eq!(lines(&editor), vec![""]);
+        feed(&mut editor, ":later 10m\n");
+        assert_eq!(lines(&editor), vec!["abc"]);
+        feed(&mut editor, ":later 3x\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Trailing characters: 3x"));
+
+        feed(&mut editor, "3");
+        let alt = Event::Key(KeyEvent::new(KeyCode::Char('-'), KeyModifiers::ALT));
+        assert!(matches!(editor.handle_event(alt), Some(Actions::Earlier(Span::Seconds(180)))));
+    }
+
+    #[test]
+    fn test_set_fileformat_converts_the_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "one\r\ntwo\n").unwrap();
+        let buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        let mut editor = Editor::with_buffer(buffer);
+        feed(&mut editor, ":set ff?\n");
+        assert_eq!(editor.status_message.as_deref(), Some("ff=unix"));
+        feed(&mut editor, ":set ff=mac\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Invalid argument: ff=mac"));
+        assert!(!editor.buffer.modified);
+
+        feed(&mut editor, ":set fileformat=dos\nu");
+        assert_eq!(editor.buffer.line_ending, LineEnding::Dos);
+        assert!(editor.buffer.modified);
+        feed(&mut editor, ":w\n");
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\r\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn test_set_fileencoding_writes_in_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "café\n").unwrap();
+        let buffer = Buffer::from_file(Some(path.to_string_lossy().to_string()), None).unwrap();
+        let mut editor = Editor::with_buffer(buffer);
+        feed(&mut editor, ":set fenc=latin1 fenc?\n");
+        assert_eq!(editor.status_message.as_deref(), Some("fenc=latin1"));
+        assert!(editor.buffer.modified);
+        feed(&mut editor, ":w\n");
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9\n");
+
+        // text the encoding cannot hold leaves the file as it was
+        feed(&mut editor, "A\u{263a}\x1b:set fileencoding=cp1252\n:w\n");
+        assert!(editor.status_message.as_deref().is_some_and(|message| message.starts_with("Error saving file")));
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9\n");
+    }
+
+    #[test]
+    fn test_write_quit_stays_when_the_write_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        // a file where the directory should be
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let path = dir.path().join("file").join("out.txt").to_string_lossy().to_string();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, &format!("ihi\x1b:wq! {}\n", path));
+        assert!(!editor.should_quit);
+        assert!(editor.status_message.as_deref().is_some_and(|message| message.starts_with("Error saving file")));
+        feed(&mut editor, &format!(":x! {}\n", path));
+        assert!(!editor.should_quit);
+        assert!(editor.buffer.modified);
+    }
+
+    #[test]
+    fn test_edit_refuses_to_drop_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other.txt").to_string_lossy().to_string();
+        std::fs::write(&other, "other file\n").unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, &format!("iedit\x1b:e {}\n", other));
+        assert_eq!(lines(&editor), vec!["edit"]);
+
+        feed(&mut editor, &format!(":e! {}\n", other));
+        assert_eq!(lines(&editor), vec!["other file"]);
+        assert_eq!((editor.cy, editor.cx), (0, 0));
+        feed(&mut editor, ":q\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_buffer_list_switching_and_quitting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let open = |name: &str| {
+            std::fs::write(path(name), format!("{}\n", name)).unwrap();
+            Buffer::from_file(Some(path(name)), None).unwrap()
+        };
+        let mut editor = Editor::with_buffer(open("a.txt"));
+        editor.add_buffer(open("b.txt"));
+        feed(&mut editor, "A!\x1b:bn\n");
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change (add ! to override)"));
+        feed(&mut editor, ":bn!\n");
+        assert_eq!(lines(&editor), ["b.txt"]);
+        feed(&mut editor, ":ls\n");
+        let listed = editor.pager.as_ref().map(|pager| pager.lines.clone()).unwrap_or_default();
+        assert_eq!(listed, [format!("  1  h + \"{}\"  line 1", path("a.txt")), format!("  2 %a   \"{}\"  line 1", path("b.txt"))]);
+        feed(&mut editor, "q:q\n");
+        assert!(!editor.should_quit);
+        assert_eq!(
+            editor.status_message,
+            Some(format!("No write since last change for buffer 1 \"{}\" (add ! to override)", path("a.txt")))
+        );
+
+        // each buffer keeps its own cursor
+        feed(&mut editor, ":b a.t\n");
+        assert_eq!((lines(&editor), editor.cx), (vec!["a.txt!".to_string()], 5));
+        feed(&mut editor, ":b .txt\n");
+        assert_eq!(editor.status_message.as_deref(), Some("More than one match for .txt"));
+        std::fs::write(path("c.txt"), "c.txt\n").unwrap();
+        feed(&mut editor, &format!(":set hidden\n:e {}\n", path("c.txt")));
+        feed(&mut editor, ":bp\n");
+        assert_eq!(lines(&editor), ["b.txt"]);
+        feed(&mut editor, ":bd 1\n");
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change for buffer 1 (add ! to override)"));
+        feed(&mut editor, ":bd! 1\n:bd\n");
+        assert_eq!(lines(&editor), ["c.txt"]);
+        feed(&mut editor, ":bd\n");
+        assert_eq!((lines(&editor), editor.buffer.file.as_deref()), (vec![String::new()], None));
+        feed(&mut editor, ":q\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_quit_bang_keeps_hidden_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        std::fs::write(path("a.txt"), "a\n").unwrap();
+        std::fs::write(path("b.txt"), "b\n").unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(Some(path("a.txt")), None).unwrap());
+        feed(&mut editor, &format!(":set hidden\nA!\x1b:e {}\nA?\x1b:q!\n", path("b.txt")));
+        // b.txt is given up, a.txt is shown for its changes
+        assert!(!editor.should_quit);
+        assert_eq!(lines(&editor), ["a!"]);
+        assert_eq!(editor.buffer_list().len(), 1);
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change (add ! to override)"));
+
+        feed(&mut editor, ":qa\n");
+        assert!(!editor.should_quit);
+        feed(&mut editor, ":qa!\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_windows_split_share_buffers_and_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other.txt").to_string_lossy().to_string();
+        std::fs::write(&other, "other\n").unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione\ntwo\nthree\x1b\x17s");
+        assert_eq!(editor.layout.windows(), [2, 1]);
+        // both windows show the same buffer, each with its own cursor
+        feed(&mut editor, "ggdd\x17j");
+        assert_eq!((editor.window_id, editor.cy), (1, 1));
+        assert_eq!(lines(&editor), ["two", "three"]);
+
+        feed(&mut editor, &format!(":vsplit {}\n", other));
+        assert_eq!(lines(&editor), ["other"]);
+        assert_eq!(editor.layout.rects(editor.screen.area())[1], (3, Rect { x: 0, y: 12, width: 39, height: 12 }));
+        feed(&mut editor, "\x17l");
+        assert_eq!((editor.window_id, lines(&editor)), (1, vec!["two".to_string(), "three".to_string()]));
+        feed(&mut editor, ":ls\n");
+        let listed = editor.pager.as_ref().map(|pager| pager.lines.clone()).unwrap_or_default();
+        assert_eq!(listed, ["  1 %a + \"[No Name]\"  line 2".to_string(), format!("  2  a   \"{}\"  line 1", other)]);
+
+        feed(&mut editor, "q3\x17+:vert res -9\n");
+        assert_eq!(editor.window_rect(), Rect { x: 49, y: 9, width: 31, height: 15 });
+        assert_eq!(editor.layout.rects(editor.screen.area())[0], (2, Rect { x: 0, y: 0, width: 80, height: 9 }));
+        // a window on a changed buffer closes freely while another shows it
+        feed(&mut editor, ":q\n\x17k:q\n");
+        assert_eq!((editor.window_id, editor.layout.windows()), (2, vec![2, 3]));
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change (add ! to override)"));
+        feed(&mut editor, ":q!\n:q\n");
+        assert_eq!(editor.window_id, 3);
+        assert!(!editor.should_quit);
+        // the hidden changes to the first buffer keep `:q!` from quitting
+        feed(&mut editor, ":q!\n");
+        assert!(!editor.should_quit);
+        assert_eq!(lines(&editor), ["two", "three"]);
+        feed(&mut editor, ":q!\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_split_closes_window_when_edit_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione\x1b");
+        feed(&mut editor, &format!(":split {}\n", dir.path().to_string_lossy()));
+        assert_eq!((editor.window_id, editor.layout.windows()), (1, vec![1]));
+        assert!(editor.status_message.as_deref().is_some_and(|message| message.starts_with("Error opening")));
+        assert_eq!(lines(&editor), ["one"]);
+    }
+
+    #[test]
+    fn test_draw_windows_into_screen() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        editor.screen = Screen::new(20, 6);
+        feed(&mut editor, "ione\ntwo 中\x1b\x17v/two\n");
+        editor.draw();
+        assert_eq!(editor.screen.line(0), "one      |one       ");
+        assert_eq!(editor.screen.line(1), "two 中   |two 中    ");
+        assert_eq!(editor.screen.line(2), "         |          ");
+        assert_eq!(editor.screen.line(5), "NORMAL > |[No Name]*");
+    }
+
+    #[test]
+    fn test_long_lines_scroll_sideways_or_wrap() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        editor.screen = Screen::new(10, 5);
+        feed(&mut editor, "iabcdefghijklmnop\none two three\x1bk$");
+        editor.draw();
+        assert_eq!((editor.col_offset, editor.screen_cursor()), (6, (9, 0)));
+        assert_eq!(editor.screen.line(0), "ghijklmnop");
+        assert_eq!(editor.screen.line(1), "o three   ");
+        feed(&mut editor, "0");
+        editor.draw();
+        assert_eq!(editor.screen.line(0), "abcdefghij");
+
+        feed(&mut editor, ":set sbr=>\\  wrap\n");
+        editor.draw();
+        let rows: Vec<String> = (0..4).map(|y| editor.screen.line(y)).collect();
+        assert_eq!(rows, ["abcdefghij", "> klmnop  ", "one two   ", "> three   "]);
+        // j and k go by lines, gj and gk by rows
+        feed(&mut editor, "gj");
+        assert_eq!((editor.cy, editor.cx, editor.screen_cursor()), (0, 10, (2, 1)));
+        feed(&mut editor, "lgj");
+        assert_eq!((editor.cy, editor.cx), (1, 3));
+        feed(&mut editor, "gj");
+        assert_eq!((editor.cy, editor.cx, editor.screen_cursor()), (1, 9, (3, 3)));
+        feed(&mut editor, "kgk");
+        assert_eq!((editor.cy, editor.cx), (0, 9));
+
+        // a line taller than the window keeps it at the top, with the rows
+        // above the cursor's scrolled off
+        editor.screen = Screen::new(4, 3);
+        feed(&mut editor, "j$");
+        editor.draw();
+        assert_eq!((editor.row_offset, editor.col_offset), (1, 4));
+        assert_eq!((editor.screen.line(0), editor.screen.line(1)), ("> re".to_string(), "> e ".to_string()));
+        assert_eq!(editor.screen_cursor(), (2, 1));
+        feed(&mut editor, "0");
+        editor.draw();
+        assert_eq!((editor.screen.line(0), editor.screen.line(1)), ("one ".to_string(), "> tw".to_string()));
+        feed(&mut editor, "3gj");
+        editor.draw();
+        assert_eq!((editor.screen.line(0), editor.screen.line(1), editor.screen_cursor()), ("> o ".to_string(), "> th".to_string(), (2, 1)));
+
+        // gj keeps its column over a short row
+        editor.screen = Screen::new(10, 5);
+        feed(&mut editor, "ggdGiabcdefgh\nab\nabcdefgh\x1bgg0fggj");
+        assert_eq!((editor.cy, editor.cx), (1, 1));
+        feed(&mut editor, "gj");
+        assert_eq!((editor.cy, editor.cx), (2, 6));
+    }
+
+    #[test]
+    fn test_tab_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other.txt").to_string_lossy().to_string();
+        std::fs::write(&other, "other\n").unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione\x1b:tabnew\nitwo\x1b");
+        assert_eq!((editor.tab, editor.buffer_number), (1, 2));
+        assert_eq!(editor.window_area(), Rect { x: 0, y: 1, width: 80, height: 23 });
+        feed(&mut editor, "gt");
+        assert_eq!((editor.tab, lines(&editor)), (0, vec!["one".to_string()]));
+        feed(&mut editor, "2gt");
+        assert_eq!(lines(&editor), ["two"]);
+        feed(&mut editor, &format!("gT:tabnew {}\n", other));
+        assert_eq!((editor.tab, lines(&editor)), (1, vec!["other".to_string()]));
+
+        // each tab page keeps its own windows
+        feed(&mut editor, "\x17v3gt");
+        assert_eq!((lines(&editor), editor.layout.windows().len()), (vec!["two".to_string()], 1));
+        feed(&mut editor, ":tabclose\n");
+        assert_eq!(editor.status_message.as_deref(), Some("No write since last change (add ! to override)"));
+        feed(&mut editor, ":tabc!\n");
+        assert_eq!((editor.tab, editor.layout.windows().len()), (1, 2));
+        feed(&mut editor, ":q\n:q\n");
+        assert_eq!((editor.tab, editor.tabs.len(), lines(&editor)), (0, 0, vec!["one".to_string()]));
+        feed(&mut editor, ":q!\n");
+        assert!(!editor.should_quit);
+        feed(&mut editor, ":qa!\n");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_tabs_reach_tab_stops() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        editor.screen = Screen::new(12, 4);
+        feed(&mut editor, "i\tone\tx\x1b0");
+        editor.draw();
+        assert_eq!((editor.screen.line(0).as_str(), editor.screen_cursor()), ("        one ", (0, 0)));
+        feed(&mut editor, "l");
+        editor.draw();
+        assert_eq!(editor.screen_cursor(), (8, 0));
+        // scrolled, the tab cut by the left edge is left out
+        feed(&mut editor, "$");
+        editor.draw();
+        assert_eq!((editor.col_offset, editor.screen_cursor()), (5, (11, 0)));
+        assert_eq!(editor.screen.line(0), "   one     x");
+
+        feed(&mut editor, ":set wrap\n0");
+        editor.draw();
+        feed(&mut editor, "$");
+        editor.draw();
+        assert_eq!((editor.screen.line(0).as_str(), editor.screen.line(1).as_str()), ("            ", "one     x   "));
+        assert_eq!(editor.screen_cursor(), (8, 1));
+    }
+
+    #[test]
+    fn test_draw_tab_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other.txt").to_string_lossy().to_string();
+        std::fs::write(&other, "other\n").unwrap();
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        editor.screen = Screen::new(120, 6);
+        feed(&mut editor, &format!("ione\x1b:tabnew {}\n", other));
+        editor.draw();
+        let name = editor.buffer.display_name();
+        let tabs = format!(" 1 [No Name] +  2 {} ", name);
+        assert_eq!(editor.screen.line(0), format!("{:<120}", tabs));
+    }
+
+    #[test]
+    fn test_operators_with_counts_and_motions() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione two\nthree\nfour\nfive\x1b");
+        feed(&mut editor, "3k0d2l");
+        assert_eq!(lines(&editor)[0], "e two");
+        feed(&mut editor, "yjP");
+        assert_eq!(lines(&editor)[..3], ["e two", "three", "e two"]);
+        feed(&mut editor, "2dd3jp");
+        assert_eq!(lines(&editor), ["e two", "three", "four", "five", "e two", "three"]);
+        assert_eq!(editor.cy, 4);
+
+        feed(&mut editor, "4k>jgUU");
+        assert_eq!(lines(&editor)[..2], ["    E TWO", "    three"]);
+        feed(&mut editor, "2==");
+        assert_eq!(lines(&editor)[..2], ["E TWO", "three"]);
+        feed(&mut editor, "lc$ne\x1b");
+        assert_eq!(lines(&editor)[0], "Ene");
+        // the change and what was typed undo together
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor)[0], "E TWO");
+    }
+
+    #[test]
+    fn test_word_find_and_paragraph_motions() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ifoo bar baz\n  qux\n\nlast\x1b");
+        feed(&mut editor, "ggdw");
+        assert_eq!(lines(&editor)[0], "bar baz");
+        // cw stops at the end of the word instead of eating the blank after it
+        feed(&mut editor, "cwx\x1b");
+        assert_eq!(lines(&editor)[0], "x baz");
+
+        // after $ the cursor keeps to line ends; otherwise j and k aim for the same column
+        feed(&mut editor, "$j");
+        assert_eq!((editor.cy, editor.cx), (1, 4));
+        feed(&mut editor, "0llkj");
+        assert_eq!((editor.cy, editor.cx), (1, 2));
+        feed(&mut editor, "jj");
+        assert_eq!((editor.cy, editor.cx), (3, 2));
+
+        // ending at the start of a line makes d} linewise
+        feed(&mut editor, "ggd}");
+        assert_eq!(lines(&editor), ["", "last"]);
+
+        feed(&mut editor, "Goa.b.c\x1b0f.;");
+        assert_eq!(editor.cx, 3);
+        feed(&mut editor, ",");
+        assert_eq!(editor.cx, 1);
+        // a repeated T looks past the character it stopped after
+        feed(&mut editor, "$T.;");
+        assert_eq!(editor.cx, 2);
+        feed(&mut editor, "0dtc");
+        assert_eq!(lines(&editor)[2], "c");
+    }
+
+    #[test]
+    fn test_registers_and_put() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione two\nthree\x1b");
+        feed(&mut editor, "gg\"ayw\"Ayyj\"ap");
+        assert_eq!(lines(&editor), ["one two", "three", "one ", "one two"]);
+        // the black hole register leaves the unnamed one alone
+        feed(&mut editor, "dd\"_ddP");
+        assert_eq!(lines(&editor), ["one two", "one ", "three"]);
+        feed(&mut editor, "gg\"1p");
+        assert_eq!(lines(&editor), ["one two", "one ", "one ", "three"]);
+        feed(&mut editor, "\"xp");
+        assert_eq!(editor.status_message.as_deref(), Some("Nothing in register x"));
+
+        feed(&mut editor, "Goend\x1b\".p");
+        assert_eq!(lines(&editor).last().unwrap(), "endend");
+        // backspace takes a whole grapheme back out of the `.` register
+        feed(&mut editor, "Ae\u{301}x\x08\x08y\x1b\".p");
+        assert_eq!(lines(&editor).last().unwrap(), "endendyy");
+
+        editor.registers.store(None, Register { text: "ab\nc".to_string(), kind: RegisterKind::Blockwise }, true).unwrap();
+        feed(&mut editor, "gglP");
+        assert_eq!(lines(&editor)[..3], ["oabne two", "oc ne ", "one "]);
+    }
+
+    #[test]
+    fn test_operators_on_text_objects() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "icall(a, \"b c\") \"\"\x1b");
+        feed(&mut editor, "0fbda\"");
+        assert_eq!(lines(&editor)[0], "call(a,) \"\"");
+        feed(&mut editor, "0f,ci(x\x1b");
+        assert_eq!(lines(&editor)[0], "call(x) \"\"");
+        // an empty pair of quotes still gives somewhere to type
+        feed(&mut editor, "$ci\"y\x1b");
+        assert_eq!(lines(&editor)[0], "call(x) \"y\"");
+        feed(&mut editor, "0yiwP");
+        assert_eq!(lines(&editor)[0], "callcall(x) \"y\"");
+    }
+
+    #[test]
+    fn test_visual_modes() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione two three\nfour five six\nseven\x1b");
+        feed(&mut editor, "gg0wvjd");
+        assert_eq!(lines(&editor), ["one five six", "seven"]);
+        feed(&mut editor, "Vjy");
+        assert_eq!(editor.mode, Mode::Normal);
+        feed(&mut editor, "Gp");
+        assert_eq!(lines(&editor), ["one five six", "seven", "one five six", "seven"]);
+
+        // objects select, and `o` swaps which end moves
+        feed(&mut editor, "gg0wviwoU");
+        assert_eq!(lines(&editor)[0], "one FIVE six");
+        feed(&mut editor, "vv");
+        assert_eq!(editor.mode, Mode::Normal);
+        feed(&mut editor, "VGd");
+        assert_eq!(lines(&editor), [""]);
+
+        feed(&mut editor, "ione\ntwo\nthree\x1bgg0\x16jlx");
+        assert_eq!(lines(&editor), ["e", "o", "three"]);
+        feed(&mut editor, "\x16jIab\x1b");
+        assert_eq!(lines(&editor), ["abe", "abo", "three"]);
+        feed(&mut editor, "gg\x16jjA!\x1b");
+        assert_eq!(lines(&editor), ["a!be", "a!bo", "t!hree"]);
+        feed(&mut editor, "Gl\x16kkly");
+        feed(&mut editor, "GoX\x1bp");
+        assert_eq!(lines(&editor)[3..], ["X!b", " !b", " !h"]);
+        // the whole change to the block undoes at once
+        feed(&mut editor, "gg0\x16jcZ\x1b");
+        assert_eq!(lines(&editor)[..2], ["Z!be", "Z!bo"]);
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor)[..2], ["a!be", "a!bo"]);
+    }
+
+    #[test]
+    fn test_dot_repeats_the_last_change() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione two three four five\x1b");
+        feed(&mut editor, "0dw.");
+        assert_eq!(lines(&editor)[0], "three four five");
+        // a count given to `.` replaces the recorded one
+        feed(&mut editor, "cwX\x1bw2.");
+        assert_eq!(lines(&editor)[0], "X X");
+        feed(&mut editor, "A!\x08?\x1b.");
+        assert_eq!(lines(&editor)[0], "X X??");
+        // yanks and motions are not changes
+        feed(&mut editor, "0yl$.");
+        assert_eq!(lines(&editor)[0], "X X???");
+
+        feed(&mut editor, "oa\nb\nc\nd\x1bggjVd.");
+        assert_eq!(lines(&editor), ["X X???", "c", "d"]);
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), ["X X???", "b", "c", "d"]);
+
+        feed(&mut editor, "G\"1p..");
+        assert_eq!(lines(&editor), ["X X???", "b", "c", "d", "b", "a"]);
+        assert_eq!(editor.status_message.as_deref(), Some("Nothing in register 3"));
+    }
+
+    #[test]
+    fn test_dot_repeats_visual_changes_and_counted_inserts() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "iabcdef\nghijkl\nmnopqr\nstuvwx\x1bgg0lvjd");
+        assert_eq!(lines(&editor), ["aijkl", "mnopqr", "stuvwx"]);
+        // as many lines from the cursor, ending in the same column
+        feed(&mut editor, "j0.");
+        assert_eq!(lines(&editor), ["aijkl", "uvwx"]);
+
+        feed(&mut editor, "ggdGiabcd\nefgh\nijkl\nmnop\x1bgg0\x16jld");
+        assert_eq!(lines(&editor), ["cd", "gh", "ijkl", "mnop"]);
+        feed(&mut editor, "jj0.");
+        assert_eq!(lines(&editor), ["cd", "gh", "kl", "op"]);
+        feed(&mut editor, "gg0\x16jIX\x1bjj0.");
+        assert_eq!(lines(&editor), ["Xcd", "Xgh", "Xkl", "Xop"]);
+
+        // a failed operator leaves the last change as it was
+        feed(&mut editor, "ggdGione two three\x1b0dwdfz.");
+        assert_eq!(lines(&editor), ["three"]);
+
+        feed(&mut editor, "ddihi\x1b03.");
+        assert_eq!(lines(&editor), ["hihihihi"]);
+        feed(&mut editor, "ox\x1b2.");
+        assert_eq!(lines(&editor), ["hihihihi", "x", "x", "x"]);
+    }
+
+    #[test]
+    fn test_macros_record_replay_and_stop_on_failure() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ia1\na2\na3\na4\x1bgg");
+        feed(&mut editor, "qaA;\x1bjq");
+        assert_eq!(editor.registers.get('a'), Ok(Register::charwise("A;<Esc>j")));
+        feed(&mut editor, "@a");
+        // `j` fails on the last line, so the count runs out early
+        feed(&mut editor, "5@@");
+        assert_eq!(lines(&editor), ["a1;", "a2;", "a3;", "a4;"]);
+
+        // a macro put into the text, edited and yanked back
+        feed(&mut editor, "o\x1b\"apA!\x1b0\"ay$dd");
+        assert_eq!(editor.registers.get('a'), Ok(Register::charwise("A;<Esc>j!")));
+        feed(&mut editor, "gg@a");
+        assert_eq!(lines(&editor), ["a1;;", "a2;", "a3;", "a4;"]);
+
+        feed(&mut editor, "q\"");
+        assert_eq!(editor.status_message.as_deref(), Some(RegisterError::Invalid('"').to_string().as_str()));
+        assert!(editor.macro_recording.is_none());
+        // `q` ends a recording in Visual mode too
+        feed(&mut editor, "qbvlq");
+        assert!(editor.macro_recording.is_none());
+        assert_eq!(editor.mode, Mode::Visual);
+        assert_eq!(editor.registers.get('b'), Ok(Register::charwise("vl")));
+    }
+
+    #[test]
+    fn test_search_moves_incrementally_wraps_and_repeats() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione two\nTwo three\ntwo four\x1bgg");
+        feed(&mut editor, "/tw");
+        assert_eq!((editor.mode, editor.cy, editor.cx), (Mode::Search, 0, 4));
+        feed(&mut editor, "\x08\x08th");
+        assert_eq!((editor.cy, editor.cx), (1, 4));
+        // abandoning the search puts the cursor back
+        feed(&mut editor, "\x1b");
+        assert_eq!((editor.mode, editor.cy, editor.cx), (Mode::Normal, 0, 0));
+
+        // smart-case: all lowercase matches "Two" too
+        feed(&mut editor, "/two\nn");
+        assert_eq!((editor.cy, editor.cx), (1, 0));
+        feed(&mut editor, "nn");
+        assert_eq!((editor.cy, editor.cx), (0, 4));
+        assert_eq!(editor.status_message.as_deref(), Some("search hit BOTTOM, continuing at TOP"));
+        feed(&mut editor, "N");
+        assert_eq!((editor.cy, editor.cx), (2, 0));
+        assert_eq!(editor.status_message.as_deref(), Some("search hit TOP, continuing at BOTTOM"));
+        feed(&mut editor, "2?\n");
+        assert_eq!((editor.cy, editor.cx), (0, 4));
+        feed(&mut editor, "/nope\n");
+        assert_eq!((editor.cy, editor.cx), (0, 4));
+        assert_eq!(editor.status_message.as_deref(), Some("Pattern not found: nope"));
+
+        // Up brings back older patterns that start with what was typed
+        feed(&mut editor, "/t");
+        for code in [KeyCode::Up, KeyCode::Up] {
+            if let Some(action) = editor.handle_event(key(code)) {
+                editor.apply_action(action);
+            }
+        }
+        // "nope" does not start with "t", and there is nothing older than "two"
+        assert_eq!(editor.command_line.text, "two");
+        if let Some(action) = editor.handle_event(key(KeyCode::Down)) {
+            editor.apply_action(action);
+        }
+        assert_eq!(editor.command_line.text, "t");
+        feed(&mut editor, "\x1b");
+
+        feed(&mut editor, "j0*");
+        assert_eq!((editor.cy, editor.cx, editor.search.as_ref().unwrap().text.as_str()), (2, 0, "\\<Two\\>"));
+        feed(&mut editor, "#");
+        assert_eq!((editor.cy, editor.cx), (1, 0));
+        // `n` and `N` are motions for operators too; after `#`, `N` goes forward
+        feed(&mut editor, "gg0dN");
+        assert_eq!(lines(&editor)[0], "two");
+        assert!(editor.highlight);
+        feed(&mut editor, ":noh\n");
+        assert!(!editor.highlight);
+    }
+
+    #[test]
+    fn test_substitute_ranges_flags_and_undo() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ifoo foo\nbar foo\nfoo baz\nend\x1bgg");
+        feed(&mut editor, ":s/foo/x/\n");
+        assert_eq!(lines(&editor), ["x foo", "bar foo", "foo baz", "end"]);
+        feed(&mut editor, ":%s/\\b(\\w)(\\w*)/\\u\\1\\U\\2\\e!/g\n");
+        assert_eq!(lines(&editor), ["X! FOO!", "BAR! FOO!", "FOO! BAZ!", "END!"]);
+        assert_eq!(editor.status_message.as_deref(), Some("7 substitutions on 4 lines"));
+        // one undo step for the whole command
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), ["x foo", "bar foo", "foo baz", "end"]);
+
+        feed(&mut editor, "jma:.,$-1s/o/0/gn\n");
+        assert_eq!(editor.status_message.as_deref(), Some("4 matches on 2 lines"));
+        feed(&mut editor, "G:'a,.s/$/;/\n");
+        assert_eq!(lines(&editor), ["x foo", "bar foo;", "foo baz;", "end;"]);
+        feed(&mut editor, "ggVj:s/ /\\r/\n");
+        assert_eq!(lines(&editor), ["x", "foo", "bar", "foo;", "foo baz;", "end;"]);
+        feed(&mut editor, ":'<,'>s/q/x/\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Pattern not found: q"));
+        feed(&mut editor, ":9s/x/y/\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Invalid range"));
+    }
+
+    #[test]
+    fn test_substitute_confirm() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ia a\na\na a\x1b");
+        feed(&mut editor, ":%s/a/b/gc\n");
+        assert_eq!((editor.prompt, editor.cy, editor.cx), (Some(Prompt::Substitute), 0, 0));
+        feed(&mut editor, "yn");
+        assert_eq!((editor.cy, editor.cx), (1, 0));
+        feed(&mut editor, "a");
+        assert_eq!(editor.prompt, None);
+        assert_eq!(lines(&editor), ["b a", "b", "b b"]);
+        assert_eq!(editor.status_message.as_deref(), Some("4 substitutions on 3 lines"));
+
+        feed(&mut editor, "u:%s/a/c/c\nnl");
+        assert_eq!(lines(&editor), ["a a", "c", "a a"]);
+        feed(&mut editor, ":%s/a/d/c\nq");
+        assert_eq!(lines(&editor), ["a a", "c", "a a"]);
+        assert_eq!(editor.status_message, None);
+    }
+
+    #[test]
+    fn test_ex_line_commands() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ione\ntwo\nthree\nfour\x1b");
+        feed(&mut editor, ":1m$\n");
+        assert_eq!(lines(&editor), ["two", "three", "four", "one"]);
+        assert_eq!(editor.cy, 3);
+        feed(&mut editor, ":2,3t0\n");
+        assert_eq!(lines(&editor), ["three", "four", "two", "three", "four", "one"]);
+        feed(&mut editor, ":3,5m4\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Cannot move a range of lines into itself"));
+        assert!(editor.failed);
+        feed(&mut editor, ":1,2d a\n");
+        assert_eq!(lines(&editor), ["two", "three", "four", "one"]);
+        assert_eq!(editor.registers.get('a').map(|r| r.text), Ok("three\nfour\n".to_string()));
+        feed(&mut editor, ":%norm A!\n");
+        assert_eq!(lines(&editor), ["two!", "three!", "four!", "one!"]);
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), ["two", "three", "four", "one"]);
+
+        // a count deletes from the last line of the range
+        feed(&mut editor, ":1,2d 2\n");
+        assert_eq!(lines(&editor), ["two", "one"]);
+        feed(&mut editor, ":d b 5\n");
+        assert_eq!(lines(&editor), ["two"]);
+        assert_eq!(editor.registers.get('b').map(|r| r.text), Ok("one\n".to_string()));
+        editor.failed = false;
+        feed(&mut editor, ":d 0\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Positive count required"));
+        assert!(editor.failed);
+    }
+
+    #[test]
+    fn test_global_follows_lines_through_edits() {
+        let mut editor = Editor::with_buffer(Buffer::from_file(None, None).unwrap());
+        feed(&mut editor, "ia\n\nTODO b\n\n\nc TODO\nd\x1b");
+        feed(&mut editor, ":g/^$/d\n");
+        assert_eq!(lines(&editor), ["a", "TODO b", "c TODO", "d"]);
+        feed(&mut editor, ":g/TODO/m$\n");
+        assert_eq!(lines(&editor), ["a", "d", "TODO b", "c TODO"]);
+        // everything is undone at once
+        feed(&mut editor, "u");
+        assert_eq!(lines(&editor), ["a", "TODO b", "c TODO", "d"]);
+
+        feed(&mut editor, ":g/^/m0\n");
+        assert_eq!(lines(&editor), ["d", "c TODO", "TODO b", "a"]);
+        feed(&mut editor, ":v/TODO/normal Ax\n");
+        assert_eq!(lines(&editor), ["dx", "c TODO", "TODO b", "ax"]);
+        feed(&mut editor, ":g!/x/s/o/0/g\n");
+        assert_eq!(lines(&editor), ["dx", "c T0D0", "T0D0 b", "ax"]);
+        feed(&mut editor, ":g/x/t.\n");
+        assert_eq!(lines(&editor), ["dx", "dx", "c T0D0", "T0D0 b", "ax", "ax"]);
+        feed(&mut editor, ":2,$g/q/d\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Pattern not found: q"));
+        assert!(editor.failed);
+        feed(&mut editor, ":g/x/g/a/d\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Cannot do :global recursive"));
     }
 }
-               
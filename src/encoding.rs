@@ -0,0 +1,206 @@
+use std::borrow::Cow;
+
+use log::debug;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncodingError {
+    #[error("File is not valid {0}")]
+    InvalidData(&'static str),
+    #[error("Character {0:?} cannot be written as {1}")]
+    Unencodable(char, &'static str),
+}
+
+/// Character encodings a file can be read from and written back to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+    ShiftJis,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+            Encoding::Windows1252 => "cp1252",
+            Encoding::ShiftJis => "sjis",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16" | "utf-16le" | "utf16le" | "ucs-2le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" | "ucs-2" => Some(Encoding::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Some(Encoding::Windows1252),
+            "sjis" | "shift-jis" | "cp932" => Some(Encoding::ShiftJis),
+            _ => None,
+        }
+    }
+
+    pub fn bom(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xEF\xBB\xBF",
+            Encoding::Utf16Le => b"\xFF\xFE",
+            Encoding::Utf16Be => b"\xFE\xFF",
+            _ => b"",
+        }
+    }
+
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, str>, EncodingError> {
+        let invalid = || EncodingError::InvalidData(self.name());
+        match self {
+            Encoding::Utf8 => std::str::from_utf8(bytes).map(Cow::Borrowed).map_err(|_| invalid()),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let units = bytes.chunks_exact(2).map(|pair| match self {
+                    Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                });
+                char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .map(Cow::Owned)
+                    .map_err(|_| invalid())
+            }
+            Encoding::Latin1 => Ok(Cow::Owned(bytes.iter().map(|b| *b as char).collect())),
+            Encoding::Windows1252 => encoding_rs::WINDOWS_1252
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .ok_or_else(invalid),
+            Encoding::ShiftJis => encoding_rs::SHIFT_JIS
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .ok_or_else(invalid),
+        }
+    }
+
+    pub fn encode<'a>(&self, text: &'a str) -> Result<Cow<'a, [u8]>, EncodingError> {
+        match self {
+            Encoding::Utf8 => Ok(Cow::Borrowed(text.as_bytes())),
+            Encoding::Utf16Le => Ok(Cow::Owned(text.encode_utf16().flat_map(u16::to_le_bytes).collect())),
+            Encoding::Utf16Be => Ok(Cow::Owned(text.encode_utf16().flat_map(u16::to_be_bytes).collect())),
+            Encoding::Latin1 => text.chars()
+                .map(|c| u8::try_from(c).map_err(|_| EncodingError::Unencodable(c, self.name())))
+                .collect::<Result<Vec<u8>, _>>()
+                .map(Cow::Owned),
+            Encoding::Windows1252 | Encoding::ShiftJis => {
+                let encoding = match self {
+                    Encoding::Windows1252 => encoding_rs::WINDOWS_1252,
+                    _ => encoding_rs::SHIFT_JIS,
+                };
+                // encoding_rs would substitute HTML character references; refuse instead
+                if let Some(c) = text.chars().find(|c| {
+                    let mut buf = [0u8; 4];
+                    encoding.encode(c.encode_utf8(&mut buf)).2
+                }) {
+                    return Err(EncodingError::Unencodable(c, self.name()));
+                }
+                Ok(encoding.encode(text).0)
+            }
+        }
+    }
+}
+
+/// Guesses the encoding of `bytes`: a BOM wins, then BOM-less UTF-16 from the
+/// position of NUL bytes, then valid UTF-8, then Shift-JIS when it
+/// decodes cleanly to Japanese kana, and finally the 8-bit Windows codepages.
+/// Returns the encoding and whether a BOM was found.
+pub fn detect(bytes: &[u8]) -> (Encoding, bool) {
+    for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be] {
+        if bytes.starts_with(encoding.bom()) {
+            return (encoding, true);
+        }
+    }
+    if bytes.len().is_multiple_of(2) {
+        let zeros_at = |parity: usize| bytes.iter().skip(parity).step_by(2).filter(|b| **b == 0).count();
+        let (even, odd) = (zeros_at(0), zeros_at(1));
+        let pairs = bytes.len() / 2;
+        if odd > pairs / 4 && even == 0 && Encoding::Utf16Le.decode(bytes).is_ok() {
+            return (Encoding::Utf16Le, false);
+        }
+        if even > pairs / 4 && odd == 0 && Encoding::Utf16Be.decode(bytes).is_ok() {
+            return (Encoding::Utf16Be, false);
+        }
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        return (Encoding::Utf8, false);
+    }
+
+    if let Ok(text) = Encoding::ShiftJis.decode(bytes)
+        && text.chars().any(|c| ('\u{3041}'..='\u{30FF}').contains(&c))
+    {
+        return (Encoding::ShiftJis, false);
+    }
+
+    // 0x80-0x9F are control codes in Latin-1 but punctuation in cp1252
+    let encoding = if bytes.iter().any(|b| (0x80..0xA0).contains(b)) {
+        Encoding::Windows1252
+    } else {
+        Encoding::Latin1
+    };
+    debug!("Guessed {} for non UTF-8 file", encoding.name());
+    (encoding, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_bom() {
+        assert_eq!(detect(b"\xEF\xBB\xBFabc"), (Encoding::Utf8, true));
+        assert_eq!(detect(b"\xFF\xFEa\x00"), (Encoding::Utf16Le, true));
+        assert_eq!(detect(b"\xFE\xFF\x00a"), (Encoding::Utf16Be, true));
+    }
+
+    #[test]
+    fn test_detect_without_bom() {
+        assert_eq!(detect("plain ascii and ü".as_bytes()), (Encoding::Utf8, false));
+        assert_eq!(detect(b"k\x00e\x00y\x00=\x001\x00"), (Encoding::Utf16Le, false));
+        assert_eq!(detect(b"\x00k\x00e\x00y"), (Encoding::Utf16Be, false));
+        // "日本語のテキスト" in Shift-JIS
+        let sjis = b"\x93\xfa\x96\x7b\x8c\xea\x82\xcc\x83\x65\x83\x4c\x83\x58\x83\x67";
+        assert_eq!(detect(sjis), (Encoding::ShiftJis, false));
+        assert_eq!(detect(b"caf\xe9s"), (Encoding::Latin1, false));
+        assert_eq!(detect(b"\x93quoted\x94"), (Encoding::Windows1252, false));
+    }
+
+    #[test]
+    fn test_round_trip_each_encoding() {
+        let samples = [
+            (Encoding::Utf16Le, "naïve 日本"),
+            (Encoding::Utf16Be, "emoji 🎉"),
+            (Encoding::Latin1, "Grüße, señor"),
+            (Encoding::Windows1252, "“smart” quotes €5"),
+            (Encoding::ShiftJis, "日本語のテキスト"),
+        ];
+        for (encoding, text) in samples {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(encoding.decode(&bytes).unwrap(), text, "{}", encoding.name());
+        }
+    }
+
+    #[test]
+    fn test_unencodable_characters_are_errors() {
+        assert!(matches!(Encoding::Latin1.encode("€"), Err(EncodingError::Unencodable('€', "latin1"))));
+        assert!(matches!(Encoding::ShiftJis.encode("a😀"), Err(EncodingError::Unencodable('😀', "sjis"))));
+        assert!(Encoding::Utf8.decode(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_from_name_aliases() {
+        assert_eq!(Encoding::from_name("Shift_JIS"), Some(Encoding::ShiftJis));
+        assert_eq!(Encoding::from_name("ISO-8859-1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("windows-1252"), Some(Encoding::Windows1252));
+        assert_eq!(Encoding::from_name("klingon"), None);
+    }
+}
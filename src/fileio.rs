@@ -0,0 +1,196 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+// Same limit as the kernel's ELOOP check.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Follows `path` through any chain of symlinks to the file that should
+/// actually be written, even when the final target does not exist yet.
+pub fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut current = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let target = fs::read_link(&current)?;
+                current = match current.parent() {
+                    Some(parent) if target.is_relative() => parent.join(target),
+                    _ => target,
+                };
+            }
+            _ => return Ok(current),
+        }
+    }
+    Err(io::Error::other(format!("Too many levels of symbolic links: {}", path.display())))
+}
+
+/// Writes a file without ever leaving it half-written: the contents go to a
+/// temporary file in the same directory, which is fsynced, given the original
+/// mode and owner, and renamed over the target. Symlinks are followed so the
+/// link itself survives. Falls back to truncating and writing in place when
+/// the directory is not writable, the file has other hard links, or its owner
+/// cannot be kept.
+pub fn write_atomic<T, E: From<io::Error>>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<T, E>,
+) -> Result<T, E> {
+    let target = resolve_symlinks(path)?;
+    if target != path {
+        debug!("Writing through symlink {:?} to {:?}", path, target);
+    }
+    let original = match fs::metadata(&target) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // nothing to lose yet, and creating it directly keeps the umask-derived mode
+            let mut file = File::create(&target)?;
+            let result = write(&mut file)?;
+            file.sync_all()?;
+            sync_parent(&target);
+            return Ok(result);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if has_other_links(&original) {
+        info!("{:?} has other hard links, writing in place", target);
+        return write_in_place(&target, write);
+    }
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let temp = match tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(".vix-tmp")
+        .tempfile_in(dir)
+    {
+        Ok(temp) => temp,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            info!("Directory of {:?} is not writable, writing in place", target);
+            return write_in_place(&target, write);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Err(e) = copy_ownership(temp.as_file(), &original) {
+        info!("Cannot keep owner of {:?} ({}), writing in place", target, e);
+        return write_in_place(&target, write);
+    }
+    fs::set_permissions(temp.path(), original.permissions())?;
+
+    let mut file = temp.reopen()?;
+    let result = write(&mut file)?;
+    file.sync_all()?;
+    temp.persist(&target).map_err(|e| e.error)?;
+    sync_parent(&target);
+    debug!("Atomically replaced {:?}", target);
+    Ok(result)
+}
+
+fn write_in_place<T, E: From<io::Error>>(
+    target: &Path,
+    write: impl FnOnce(&mut File) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut file = OpenOptions::new().write(true).truncate(true).open(target)?;
+    let result = write(&mut file)?;
+    file.sync_all()?;
+    Ok(result)
+}
+
+// makes the rename itself durable
+fn sync_parent(target: &Path) {
+    if let Some(dir) = target.parent().filter(|d| !d.as_os_str().is_empty())
+        && let Err(e) = File::open(dir).and_then(|d| d.sync_all())
+    {
+        warn!("Could not sync directory {:?}: {}", dir, e);
+    }
+}
+
+#[cfg(unix)]
+fn has_other_links(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_other_links(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn copy_ownership(file: &File, original: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::{fchown, MetadataExt};
+    let current = file.metadata()?;
+    if current.uid() == original.uid() && current.gid() == original.gid() {
+        return Ok(());
+    }
+    fchown(file, Some(original.uid()), Some(original.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_file: &File, _original: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn write_text(path: &Path, text: &str) -> io::Result<()> {
+        write_atomic(path, |file| file.write_all(text.as_bytes()))
+    }
+
+    #[test]
+    fn test_replaces_contents_and_leaves_no_temp_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        write_text(&path, "new file").unwrap();
+        fs::write(&path, "a much longer original text").unwrap();
+        write_text(&path, "short").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "short");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_failed_write_keeps_original() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "original").unwrap();
+        let result: io::Result<()> = write_atomic(&path, |file| {
+            file.write_all(b"partial")?;
+            Err(io::Error::other("disk full"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keeps_mode_symlinks_and_hard_links() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = tempdir().unwrap();
+        let real = dir.path().join("real.sh");
+        fs::write(&real, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&real, fs::Permissions::from_mode(0o750)).unwrap();
+        let link = dir.path().join("link.sh");
+        symlink("real.sh", &link).unwrap();
+
+        write_text(&link, "#!/bin/sh\necho hi\n").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&real).unwrap(), "#!/bin/sh\necho hi\n");
+        assert_eq!(fs::metadata(&real).unwrap().permissions().mode() & 0o777, 0o750);
+
+        let other = dir.path().join("hard.sh");
+        fs::hard_link(&real, &other).unwrap();
+        write_text(&real, "linked").unwrap();
+        assert_eq!(fs::read_to_string(&other).unwrap(), "linked");
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+const UNDO_FILE_MAGIC: &str = "vix-undo 1";
+
+/// A single primitive change to the buffer text. Positions are `(line, byte
+/// offset)` and `text` may span several lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit {
+    Insert { line: usize, col: usize, text: String },
+    Delete { line: usize, col: usize, text: String },
+}
+
+impl Edit {
+    pub fn start(&self) -> (usize, usize) {
+        match self {
+            Edit::Insert { line, col, .. } | Edit::Delete { line, col, .. } => (*line, *col),
+        }
+    }
+
+    /// Position just past the text of this edit once it is present in the buffer.
+    pub fn end(&self) -> (usize, usize) {
+        match self {
+            Edit::Insert { line, col, text } | Edit::Delete { line, col, text } => {
+                text_end(*line, *col, text)
+            }
+        }
+    }
+}
+
+pub fn text_end(line: usize, col: usize, text: &str) -> (usize, usize) {
+    match text.rfind('\n') {
+        Some(idx) => (line + text.matches('\n').count(), text.len() - idx - 1),
+        None => (line, col + text.len()),
+    }
+}
+
+pub const HASH_SEED: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, used to tie an undo file to the exact file contents it was written for.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    extend_hash(HASH_SEED, bytes)
+}
+
+/// Continues `content_hash` over more bytes, for contents written in pieces.
+pub fn extend_hash(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// One undo step: every edit made between `begin` and `end`, plus the cursor
+/// position the step should return to. `parent` is 0 for the original text.
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub seq: usize,
+    pub parent: usize,
+    pub time: u64,
+    pub edits: Vec<Edit>,
+    pub cursor: (usize, usize),
+}
+
+/// How far `earlier` and `later` move: a number of changes, in the order
+/// they were made whatever the branch, or a span of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Span {
+    Changes(usize),
+    Seconds(u64),
+}
+
+/// A leaf of the undo tree, as listed by `History::branches`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Branch {
+    pub seq: usize,
+    pub changes: usize,
+    pub time: u64,
+}
+
+/// The edit history as a tree: undoing and then editing starts a new branch
+/// instead of discarding the undone changes.
+pub struct History {
+    // changes[seq - 1] is the change with sequence number `seq`
+    changes: Vec<Change>,
+    current: usize,
+    // per state (index 0 is the original text), the child redo should follow
+    redo_child: Vec<Option<usize>>,
+    open: Option<Change>,
+    // how many `hold`s keep the open change open through `end`
+    held: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self { changes: Vec::new(), current: 0, redo_child: vec![None], open: None, held: 0 }
+    }
+
+    /// Starts grouping edits into a single undo step. Nested calls are ignored.
+    pub fn begin(&mut self, cursor: (usize, usize)) {
+        if self.open.is_none() {
+            self.open = Some(Change { seq: 0, parent: 0, time: 0, edits: Vec::new(), cursor });
+        }
+    }
+
+    /// Like `begin`, but the change stays open through calls to `end` until
+    /// the matching `release`, for commands made of other commands.
+    pub fn hold(&mut self, cursor: (usize, usize)) {
+        self.begin(cursor);
+        self.held += 1;
+    }
+
+    pub fn release(&mut self) {
+        self.held = self.held.saturating_sub(1);
+        self.end();
+    }
+
+    pub fn end(&mut self) {
+        if self.held > 0 {
+            return;
+        }
+        if let Some(change) = self.open.take()
+            && !change.edits.is_empty()
+        {
+            self.commit(change);
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        match &mut self.open {
+            Some(change) => change.edits.push(edit),
+            None => {
+                let cursor = edit.start();
+                self.commit(Change { seq: 0, parent: 0, time: 0, edits: vec![edit], cursor });
+            }
+        }
+    }
+
+    fn commit(&mut self, mut change: Change) {
+        change.seq = self.changes.len() + 1;
+        change.parent = self.current;
+        change.time = now();
+        debug!("Recorded change #{} on #{} with {} edits", change.seq, change.parent, change.edits.len());
+        self.redo_child[self.current] = Some(change.seq);
+        self.current = change.seq;
+        self.changes.push(change);
+        self.redo_child.push(None);
+    }
+
+    pub fn change(&self, seq: usize) -> &Change {
+        &self.changes[seq - 1]
+    }
+
+    /// Sequence number of the state the buffer is in, 0 for the original text.
+    pub fn current_seq(&self) -> usize {
+        self.current
+    }
+
+    pub fn latest_seq(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// The change `undo` would revert, closing any open group first.
+    pub fn undo_target(&mut self) -> Option<usize> {
+        self.end();
+        (self.current != 0).then_some(self.current)
+    }
+
+    /// The change `redo` would reapply: the branch most recently undone from.
+    pub fn redo_target(&mut self) -> Option<usize> {
+        self.end();
+        self.redo_child[self.current]
+    }
+
+    /// Marks `seq` as reverted, moving to its parent state.
+    pub fn undone(&mut self, seq: usize) {
+        let parent = self.change(seq).parent;
+        self.redo_child[parent] = Some(seq);
+        self.current = parent;
+    }
+
+    /// Marks `seq` as applied on top of its parent state.
+    pub fn redone(&mut self, seq: usize) {
+        let parent = self.change(seq).parent;
+        self.redo_child[parent] = Some(seq);
+        self.current = seq;
+    }
+
+    /// Steps needed to move from the current state to `target`: the changes to
+    /// revert (newest first) and then the changes to apply (oldest first).
+    pub fn path_to(&self, target: usize) -> (Vec<usize>, Vec<usize>) {
+        let ancestors = |mut seq: usize| {
+            let mut chain = vec![seq];
+            while seq != 0 {
+                seq = self.change(seq).parent;
+                chain.push(seq);
+            }
+            chain
+        };
+        let from = ancestors(self.current);
+        let to = ancestors(target);
+        let shared: HashSet<usize> = from.iter().copied().collect();
+        let common = to.iter().copied().find(|seq| shared.contains(seq)).unwrap_or(0);
+
+        let revert = from.into_iter().take_while(|seq| *seq != common).collect();
+        let mut apply: Vec<usize> = to.into_iter().take_while(|seq| *seq != common).collect();
+        apply.reverse();
+        (revert, apply)
+    }
+
+    fn time_of(&self, seq: usize) -> u64 {
+        match seq {
+            0 => self.changes.first().map(|c| c.time).unwrap_or(0),
+            seq => self.change(seq).time,
+        }
+    }
+
+    /// The state the text was in `span` before the current one.
+    pub fn earlier(&self, span: Span) -> usize {
+        let secs = match span {
+            Span::Changes(n) => return self.current.saturating_sub(n),
+            Span::Seconds(secs) => secs,
+        };
+        let target = self.time_of(self.current).saturating_sub(secs);
+        let seq = self.changes.iter().rev()
+            .find(|c| c.time <= target)
+            .map(|c| c.seq)
+            .unwrap_or(0);
+        if seq >= self.current { self.current.saturating_sub(1) } else { seq }
+    }
+
+    /// The state the text was in `span` after the current one.
+    pub fn later(&self, span: Span) -> usize {
+        let secs = match span {
+            Span::Changes(n) => return self.current.saturating_add(n).min(self.latest_seq()),
+            Span::Seconds(secs) => secs,
+        };
+        let target = self.time_of(self.current).saturating_add(secs);
+        let seq = self.changes.iter().rev()
+            .find(|c| c.time <= target)
+            .map(|c| c.seq)
+            .unwrap_or(0);
+        if seq <= self.current { (self.current + 1).min(self.latest_seq()) } else { seq }
+    }
+
+    /// Every leaf of the tree, oldest first.
+    pub fn branches(&self) -> Vec<Branch> {
+        let parents: HashSet<usize> = self.changes.iter().map(|c| c.parent).collect();
+        self.changes.iter()
+            .filter(|c| !parents.contains(&c.seq))
+            .map(|c| {
+                let mut changes = 1;
+                let mut seq = c.parent;
+                while seq != 0 {
+                    changes += 1;
+                    seq = self.change(seq).parent;
+                }
+                Branch { seq: c.seq, changes, time: c.time }
+            })
+            .collect()
+    }
+
+    /// Writes the whole tree to `path`, tagged with the hash of the saved file contents.
+    pub fn save_to(&mut self, path: &Path, hash: u64) -> std::io::Result<()> {
+        self.end();
+        let mut out = format!("{}\nhash {:016x}\ncurrent {}\n", UNDO_FILE_MAGIC, hash, self.current);
+        for (state, child) in self.redo_child.iter().enumerate() {
+            if let Some(child) = child {
+                let _ = writeln!(out, "redo {} {}", state, child);
+            }
+        }
+        for change in &self.changes {
+            let _ = writeln!(
+                out,
+                "change {} {} {} {} {} {}",
+                change.seq, change.parent, change.time, change.cursor.0, change.cursor.1, change.edits.len()
+            );
+            for edit in &change.edits {
+                let (kind, line, col, text) = match edit {
+                    Edit::Insert { line, col, text } => ("insert", line, col, text),
+                    Edit::Delete { line, col, text } => ("delete", line, col, text),
+                };
+                let _ = writeln!(out, "{} {} {} {}", kind, line, col, text.len());
+                out.push_str(text);
+                out.push('\n');
+            }
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, out)?;
+        debug!("Wrote undo file {:?} ({} changes)", path, self.changes.len());
+        Ok(())
+    }
+
+    /// Reads a tree written by `save_to`. Returns `None` when there is no undo
+    /// file, it is unreadable, or it was written for different file contents.
+    pub fn load_from(path: &Path, hash: u64) -> Option<Self> {
+        let data = std::fs::read_to_string(path).ok()?;
+        let history = Self::parse(&data, hash);
+        if history.is_none() {
+            warn!("Ignoring stale or invalid undo file {:?}", path);
+        }
+        history
+    }
+
+    fn parse(data: &str, hash: u64) -> Option<Self> {
+        let mut reader = UndoFileReader { data, pos: 0 };
+        if reader.line()? != UNDO_FILE_MAGIC {
+            return None;
+        }
+        let stored_hash = reader.line()?.strip_prefix("hash ")?;
+        if u64::from_str_radix(stored_hash, 16).ok()? != hash {
+            return None;
+        }
+        let current = reader.line()?.strip_prefix("current ")?.parse().ok()?;
+
+        let mut history = Self::new();
+        let mut redo: Vec<(usize, usize)> = Vec::new();
+        while reader.pos < data.len() {
+            let fields: Vec<&str> = reader.line()?.split(' ').collect();
+            match fields.as_slice() {
+                ["redo", state, child] => redo.push((state.parse().ok()?, child.parse().ok()?)),
+                ["change", seq, parent, time, line, col, count] => {
+                    let mut change = Change {
+                        seq: seq.parse().ok()?,
+                        parent: parent.parse().ok()?,
+                        time: time.parse().ok()?,
+                        edits: Vec::new(),
+                        cursor: (line.parse().ok()?, col.parse().ok()?),
+                    };
+                    if change.seq != history.changes.len() + 1 || change.parent >= change.seq {
+                        return None;
+                    }
+                    for _ in 0..count.parse::<usize>().ok()? {
+                        let fields: Vec<&str> = reader.line()?.split(' ').collect();
+                        let [kind, line, col, len] = fields.as_slice() else { return None };
+                        let (line, col) = (line.parse().ok()?, col.parse().ok()?);
+                        let text = reader.take(len.parse().ok()?)?.to_string();
+                        change.edits.push(match *kind {
+                            "insert" => Edit::Insert { line, col, text },
+                            "delete" => Edit::Delete { line, col, text },
+                            _ => return None,
+                        });
+                    }
+                    history.changes.push(change);
+                    history.redo_child.push(None);
+                }
+                _ => return None,
+            }
+        }
+        if current > history.changes.len() {
+            return None;
+        }
+        for (state, child) in redo {
+            // a redo step has to lead from `state` to one of its own children
+            if state > history.changes.len()
+                || child == 0
+                || child > history.changes.len()
+                || history.changes[child - 1].parent != state
+            {
+                return None;
+            }
+            history.redo_child[state] = Some(child);
+        }
+        history.current = current;
+        Some(history)
+    }
+}
+
+struct UndoFileReader<'a> {
+    data: &'a str,
+    pos: usize,
+}
+
+impl<'a> UndoFileReader<'a> {
+    fn line(&mut self) -> Option<&'a str> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.find('\n')?;
+        self.pos += len + 1;
+        Some(&rest[..len])
+    }
+
+    // `len` bytes of raw text followed by a newline
+    fn take(&mut self, len: usize) -> Option<&'a str> {
+        let text = self.data.get(self.pos..self.pos + len)?;
+        if self.data.as_bytes().get(self.pos + len) != Some(&b'\n') {
+            return None;
+        }
+        self.pos += len + 1;
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn insert(line: usize, col: usize, text: &str) -> Edit {
+        Edit::Insert { line, col, text: text.to_string() }
+    }
+
+    #[test]
+    fn test_edit_end_spans_lines() {
+        assert_eq!(insert(2, 3, "abc").end(), (2, 6));
+        assert_eq!(insert(2, 3, "ab\ncd\nxyz").end(), (4, 3));
+        assert_eq!(insert(0, 5, "\n").end(), (1, 0));
+    }
+
+    #[test]
+    fn test_group_collects_edits() {
+        let mut history = History::new();
+        history.begin((1, 1));
+        history.record(insert(1, 1, "a"));
+        history.record(insert(1, 2, "b"));
+        history.end();
+        history.record(insert(0, 0, "c"));
+
+        assert_eq!(history.undo_target(), Some(2));
+        assert_eq!(history.change(2).edits.len(), 1);
+        history.undone(2);
+        assert_eq!(history.undo_target(), Some(1));
+        assert_eq!(history.change(1).edits.len(), 2);
+        assert_eq!(history.change(1).cursor, (1, 1));
+        history.undone(1);
+        assert_eq!(history.undo_target(), None);
+        assert_eq!(history.redo_target(), Some(1));
+    }
+
+    #[test]
+    fn test_edit_after_undo_starts_branch() {
+        let mut history = History::new();
+        history.record(insert(0, 0, "a"));
+        history.record(insert(0, 1, "b"));
+        history.undone(2);
+        history.record(insert(0, 1, "c"));
+        assert_eq!(history.current_seq(), 3);
+        assert_eq!(history.change(3).parent, 1);
+        assert_eq!(history.redo_target(), None);
+
+        let branches = history.branches();
+        assert_eq!(branches.iter().map(|b| (b.seq, b.changes)).collect::<Vec<_>>(), vec![(2, 2), (3, 2)]);
+        assert_eq!(history.path_to(2), (vec![3], vec![2]));
+        assert_eq!(history.path_to(0), (vec![3, 1], vec![]));
+    }
+
+    #[test]
+    fn test_empty_group_is_dropped() {
+        let mut history = History::new();
+        history.begin((0, 0));
+        history.end();
+        assert_eq!(history.current_seq(), 0);
+        assert_eq!(history.undo_target(), None);
+    }
+
+    #[test]
+    fn test_earlier_and_later_by_time() {
+        let mut history = History::new();
+        for (i, time) in [100, 160, 400].into_iter().enumerate() {
+            history.record(insert(0, i, "x"));
+            history.changes[i].time = time;
+        }
+        assert_eq!(history.earlier(Span::Seconds(60)), 2);
+        assert_eq!(history.earlier(Span::Seconds(600)), 0);
+        history.current = 1;
+        assert_eq!(history.later(Span::Seconds(60)), 2);
+        assert_eq!(history.later(Span::Seconds(10)), 2);
+        assert_eq!(history.later(Span::Seconds(1000)), 3);
+
+        assert_eq!(history.later(Span::Changes(5)), 3);
+        history.current = 3;
+        assert_eq!(history.earlier(Span::Changes(2)), 1);
+        assert_eq!(history.earlier(Span::Changes(5)), 0);
+    }
+
+    #[test]
+    fn test_undo_file_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("undo").join("file");
+        let mut history = History::new();
+        history.record(insert(0, 0, "two\nlines"));
+        history.record(Edit::Delete { line: 0, col: 1, text: "w".to_string() });
+        history.undone(2);
+        history.record(insert(1, 0, " with spaces\n"));
+        history.save_to(&path, 42).unwrap();
+
+        assert!(History::load_from(&path, 41).is_none());
+        let loaded = History::load_from(&path, 42).unwrap();
+        assert_eq!(loaded.current_seq(), 3);
+        assert_eq!(loaded.latest_seq(), 3);
+        assert_eq!(loaded.change(2).edits, history.change(2).edits);
+        assert_eq!(loaded.change(3).edits, history.change(3).edits);
+        assert_eq!(loaded.change(3).parent, 1);
+        assert_eq!(loaded.redo_child, history.redo_child);
+    }
+
+    #[test]
+    fn test_undo_file_with_bad_redo_steps_is_ignored() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+        let mut history = History::new();
+        history.record(insert(0, 0, "one"));
+        history.record(insert(0, 3, "two"));
+        history.undone(2);
+        history.undone(1);
+        history.save_to(&path, 42).unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(History::parse(&data, 42).is_some());
+        // no change 0 to redo into, and change 2 does not follow state 0
+        assert!(History::parse(&data.replace("redo 0 1", "redo 0 0"), 42).is_none());
+        assert!(History::parse(&data.replace("redo 0 1", "redo 0 2"), 42).is_none());
+    }
+}
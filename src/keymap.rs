@@ -0,0 +1,461 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::editor::Actions;
+use crate::motion::Motion;
+use crate::operator::{Operator, Target};
+use crate::textobject::TextObject;
+
+/// How long a partly typed key sequence waits for its next key, like vim's 'timeoutlen'.
+pub const TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// A key press with Shift folded into the character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Key {
+    pub fn char(c: char) -> Self {
+        Key { code: KeyCode::Char(c), ctrl: false, alt: false }
+    }
+}
+
+impl From<KeyEvent> for Key {
+    fn from(key: KeyEvent) -> Self {
+        Key {
+            code: key.code,
+            ctrl: key.modifiers.contains(KeyModifiers::CONTROL),
+            alt: key.modifiers.contains(KeyModifiers::ALT),
+        }
+    }
+}
+
+impl From<Key> for KeyEvent {
+    fn from(key: Key) -> Self {
+        let mut modifiers = KeyModifiers::NONE;
+        modifiers.set(KeyModifiers::CONTROL, key.ctrl);
+        modifiers.set(KeyModifiers::ALT, key.alt);
+        KeyEvent::new(key.code, modifiers)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.code {
+            KeyCode::Char(c) if !self.ctrl && !self.alt => return write!(f, "{}", c),
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::Esc => "Esc".to_string(),
+            KeyCode::Enter => "CR".to_string(),
+            KeyCode::Backspace => "BS".to_string(),
+            KeyCode::Tab => "Tab".to_string(),
+            KeyCode::Delete => "Del".to_string(),
+            code => format!("{:?}", code),
+        };
+        let modifier = if self.ctrl { "C-" } else if self.alt { "A-" } else { "" };
+        write!(f, "<{}{}>", modifier, name)
+    }
+}
+
+/// Writes keys in the notation `keys` reads back, with `<lt>` for '<'.
+pub fn notation(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| if *key == Key::char('<') { "<lt>".to_string() } else { key.to_string() })
+        .collect()
+}
+
+/// Parses key notation such as `gU`, `<C-r>` or `<A-->` into keys. A line
+/// break is Enter.
+pub fn keys(notation: &str) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        // try each '>' in turn so that "<A->>" is Alt with '>'
+        let special = (c == '<').then(|| {
+            rest.match_indices('>').skip_while(|(i, _)| *i < 2)
+                .find_map(|(i, _)| special_key(&rest[1..i]).map(|key| (key, i + 1)))
+        }).flatten();
+        let plain = if c == '\n' { Key { code: KeyCode::Enter, ctrl: false, alt: false } } else { Key::char(c) };
+        let (key, len) = special.unwrap_or((plain, c.len_utf8()));
+        keys.push(key);
+        rest = &rest[len..];
+    }
+    keys
+}
+
+fn special_key(name: &str) -> Option<Key> {
+    let (ctrl, alt, name) = if let Some(name) = name.strip_prefix("C-") {
+        (true, false, name)
+    } else if let Some(name) = name.strip_prefix("A-") {
+        (false, true, name)
+    } else {
+        (false, false, name)
+    };
+    let code = match name {
+        "Esc" => KeyCode::Esc,
+        "CR" => KeyCode::Enter,
+        "BS" => KeyCode::Backspace,
+        "Tab" => KeyCode::Tab,
+        "Del" => KeyCode::Delete,
+        "Left" => KeyCode::Left,
+        "Right" => KeyCode::Right,
+        "Up" => KeyCode::Up,
+        "Down" => KeyCode::Down,
+        "Home" => KeyCode::Home,
+        "End" => KeyCode::End,
+        "PageUp" => KeyCode::PageUp,
+        "PageDown" => KeyCode::PageDown,
+        "lt" => KeyCode::Char('<'),
+        name if (ctrl || alt) && name.chars().count() == 1 => KeyCode::Char(name.chars().next()?),
+        _ => return None,
+    };
+    Some(Key { code, ctrl, alt })
+}
+
+/// Makes the action for a command from the count typed before it.
+pub type ActionBuilder = fn(Option<usize>) -> Actions;
+
+/// Makes the motion for `f`, `t` and friends from the character typed after them.
+pub type MotionBuilder = fn(char) -> Motion;
+
+/// Makes the action for a command like `q` from the character typed after it and the count.
+pub type CharActionBuilder = fn(char, Option<usize>) -> Actions;
+
+/// What a key sequence does in Normal mode.
+#[derive(Clone, Copy)]
+pub enum Binding {
+    Motion(Motion),
+    /// A motion completed by the character typed after it, like `f`.
+    CharMotion(MotionBuilder),
+    Operator(Operator),
+    /// A command completed by the character typed after it, like `q`.
+    CharAction(CharActionBuilder),
+    /// A text object like `iw`, only complete after an operator.
+    Object(TextObject),
+    /// Any other command.
+    Action(ActionBuilder),
+}
+
+/// Key sequences and what they are bound to. The parser only knows the
+/// shape of a command, so new motions and operators are added with `bind`.
+#[derive(Default)]
+pub struct Keymap {
+    bindings: Vec<(Vec<Key>, Binding)>,
+    selecting: bool,
+}
+
+struct Lookup {
+    exact: Option<Binding>,
+    longer: bool,
+}
+
+impl Keymap {
+    /// A keymap for Visual mode, where operators act on the selection at
+    /// once and text objects select text by themselves.
+    pub fn visual() -> Self {
+        Keymap { bindings: Vec::new(), selecting: true }
+    }
+
+    pub fn bind(&mut self, notation: &str, binding: Binding) {
+        let keys = keys(notation);
+        self.bindings.retain(|(bound, _)| *bound != keys);
+        self.bindings.push((keys, binding));
+    }
+
+    fn is_target(binding: &Binding) -> bool {
+        matches!(binding, Binding::Motion(_) | Binding::CharMotion(_) | Binding::Object(_))
+    }
+
+    // `i` and `a` start text objects only after an operator
+    fn is_command(binding: &Binding) -> bool {
+        !matches!(binding, Binding::Object(_))
+    }
+
+    fn lookup(&self, keys: &[Key], filter: impl Fn(&Binding) -> bool) -> Lookup {
+        let mut lookup = Lookup { exact: None, longer: false };
+        for (bound, binding) in self.bindings.iter().filter(|(_, binding)| filter(binding)) {
+            if bound.as_slice() == keys {
+                lookup.exact = Some(*binding);
+            } else if bound.starts_with(keys) {
+                lookup.longer = true;
+            }
+        }
+        lookup
+    }
+}
+
+/// Builds Normal-mode commands out of single key presses:
+/// `[count] [operator [count]] motion`, or `[count] command`.
+#[derive(Default)]
+pub struct KeyParser {
+    count: Option<usize>,
+    operator: Option<(Operator, Vec<Key>)>,
+    op_count: Option<usize>,
+    keys: Vec<Key>,
+    awaiting_char: Option<Binding>,
+    // `"` was typed and the register name comes next
+    awaiting_register: bool,
+    register: Option<char>,
+    last_key: Option<Instant>,
+}
+
+impl KeyParser {
+    /// Feeds one key, returning the command once it is complete. Keys that
+    /// do not form a command are dropped.
+    pub fn feed(&mut self, keymap: &Keymap, key: Key) -> Option<Actions> {
+        if self.last_key.is_some_and(|at| at.elapsed() > TIMEOUT) {
+            self.reset();
+        }
+        self.last_key = Some(Instant::now());
+
+        if let Some(binding) = self.awaiting_char.take() {
+            return match (binding, key.code) {
+                (_, KeyCode::Char(_)) if key.ctrl || key.alt => self.reset(),
+                (Binding::CharMotion(make), KeyCode::Char(c)) => self.resolve(keymap, Binding::Motion(make(c))),
+                (Binding::CharAction(make), KeyCode::Char(c)) => {
+                    let action = make(c, self.total_count());
+                    self.reset();
+                    Some(action)
+                }
+                _ => self.reset(),
+            };
+        }
+        if self.awaiting_register {
+            self.awaiting_register = false;
+            return match key.code {
+                KeyCode::Char(c) if !key.ctrl && !key.alt => {
+                    self.register = Some(c);
+                    Some(Actions::SelectRegister(c))
+                }
+                _ => self.reset(),
+            };
+        }
+        if self.keys.is_empty() && self.operator.is_none() && key == Key::char('"') {
+            self.awaiting_register = true;
+            return None;
+        }
+
+        if self.keys.is_empty()
+            && !key.ctrl
+            && !key.alt
+            && let KeyCode::Char(c) = key.code
+            && let Some(digit) = c.to_digit(10)
+        {
+            let count = if self.operator.is_some() { &mut self.op_count } else { &mut self.count };
+            // a leading 0 is the motion, not a count
+            if digit != 0 || count.is_some() {
+                *count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+                return None;
+            }
+        }
+        self.keys.push(key);
+
+        let Some((_, op_keys)) = &self.operator else {
+            let lookup = keymap.lookup(&self.keys, |binding| keymap.selecting || Keymap::is_command(binding));
+            return match lookup.exact {
+                Some(binding) if !lookup.longer => self.resolve(keymap, binding),
+                _ if lookup.longer => None,
+                _ => self.reset(),
+            };
+        };
+
+        // a doubled operator (`dd`, `gUgU`, `gUU`) works on whole lines
+        let last = &op_keys[op_keys.len() - 1..];
+        if self.keys == *op_keys || (op_keys.len() > 1 && self.keys == last) {
+            return self.operate(Target::Lines);
+        }
+        let doubling = op_keys.starts_with(&self.keys);
+        let lookup = keymap.lookup(&self.keys, Keymap::is_target);
+        match lookup.exact {
+            Some(binding) if !lookup.longer && !doubling => self.resolve(keymap, binding),
+            _ if lookup.longer || doubling => None,
+            _ => self.reset(),
+        }
+    }
+
+    /// Gives up on a sequence nobody finished typing, running it if it is
+    /// complete on its own but was waiting for a longer binding.
+    pub fn expire(&mut self, keymap: &Keymap) -> Option<Actions> {
+        if self.is_empty() || self.last_key.is_none_or(|at| at.elapsed() <= TIMEOUT) {
+            return None;
+        }
+        let filter = |binding: &Binding| match self.operator {
+            Some(_) => Keymap::is_target(binding),
+            None => keymap.selecting || Keymap::is_command(binding),
+        };
+        match keymap.lookup(&self.keys, filter).exact {
+            Some(binding) if !self.keys.is_empty() => self.resolve(keymap, binding),
+            _ => self.reset(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count.is_none() && self.operator.is_none() && self.keys.is_empty()
+            && self.awaiting_char.is_none() && !self.awaiting_register && self.register.is_none()
+    }
+
+    /// The keys typed so far, as vim's 'showcmd' shows them.
+    pub fn pending(&self) -> String {
+        let mut shown = self.register.map(|c| format!("\"{}", c)).unwrap_or_default();
+        if self.awaiting_register {
+            shown.push('"');
+        }
+        shown.extend(self.count.map(|n| n.to_string()));
+        if let Some((_, keys)) = &self.operator {
+            shown.extend(keys.iter().map(Key::to_string));
+        }
+        shown.extend(self.op_count.map(|n| n.to_string()));
+        shown.extend(self.keys.iter().map(Key::to_string));
+        shown
+    }
+
+    // counts before and after the operator multiply, as in `2d3w`
+    fn total_count(&self) -> Option<usize> {
+        match (self.count, self.op_count) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+        }
+    }
+
+    fn resolve(&mut self, keymap: &Keymap, binding: Binding) -> Option<Actions> {
+        match binding {
+            Binding::Motion(motion) if self.operator.is_some() => self.operate(Target::Motion(motion)),
+            Binding::Motion(motion) => {
+                let action = Actions::Move(motion, self.total_count());
+                self.reset();
+                Some(action)
+            }
+            Binding::CharMotion(_) | Binding::CharAction(_) => {
+                self.awaiting_char = Some(binding);
+                None
+            }
+            Binding::Object(object) if self.operator.is_some() => self.operate(Target::Object(object)),
+            Binding::Object(object) if keymap.selecting => {
+                let action = Actions::SelectObject(object, self.total_count());
+                self.reset();
+                Some(action)
+            }
+            Binding::Object(_) => self.reset(),
+            Binding::Operator(op) if keymap.selecting => {
+                self.operator = Some((op, Vec::new()));
+                self.operate(Target::Selection)
+            }
+            Binding::Operator(op) => {
+                self.operator = Some((op, std::mem::take(&mut self.keys)));
+                None
+            }
+            Binding::Action(build) => {
+                let action = build(self.total_count());
+                self.reset();
+                Some(action)
+            }
+        }
+    }
+
+    fn operate(&mut self, target: Target) -> Option<Actions> {
+        let (op, _) = self.operator.take()?;
+        let action = Actions::Operate(op, target, self.total_count());
+        self.reset();
+        Some(action)
+    }
+
+    fn reset(&mut self) -> Option<Actions> {
+        *self = KeyParser::default();
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::InsertAt;
+    use crate::textobject::ObjectKind;
+
+    fn keymap() -> Keymap {
+        let mut keymap = Keymap::default();
+        keymap.bind("l", Binding::Motion(Motion::Right));
+        keymap.bind("0", Binding::Motion(Motion::LineStart));
+        keymap.bind("d", Binding::Operator(Operator::Delete));
+        keymap.bind("gU", Binding::Operator(Operator::Uppercase));
+        keymap.bind("u", Binding::Action(|_| Actions::Undo));
+        keymap.bind("i", Binding::Action(|_| Actions::Insert(InsertAt::Cursor)));
+        keymap.bind("iw", Binding::Object(TextObject { kind: ObjectKind::Word { big: false }, inner: true }));
+        keymap
+    }
+
+    fn parse(keymap: &Keymap, notation: &str) -> Vec<String> {
+        let mut parser = KeyParser::default();
+        keys(notation).into_iter()
+            .filter_map(|key| parser.feed(keymap, key))
+            .map(|action| format!("{:?}", action))
+            .collect()
+    }
+
+    #[test]
+    fn test_key_notation() {
+        assert_eq!(keys("gU"), vec![Key::char('g'), Key::char('U')]);
+        assert_eq!(keys("<C-r>"), vec![Key { code: KeyCode::Char('r'), ctrl: true, alt: false }]);
+        assert_eq!(keys("<A-->")[0].code, KeyCode::Char('-'));
+        assert_eq!(keys("<<"), vec![Key::char('<'), Key::char('<')]);
+        assert_eq!(keys("<A->>")[0].code, KeyCode::Char('>'));
+        assert_eq!(keys("<Esc>")[0].to_string(), "<Esc>");
+    }
+
+    #[test]
+    fn test_notation_round_trip() {
+        let typed = keys("i<lt>a>\n<Esc><C-r><Left>");
+        assert_eq!(typed[1], Key::char('<'));
+        assert_eq!(typed[4].code, KeyCode::Enter);
+        assert_eq!(notation(&typed), "i<lt>a><CR><Esc><C-r><Left>");
+        assert_eq!(keys(&notation(&typed)), typed);
+    }
+
+    #[test]
+    fn test_counts_operators_and_motions() {
+        let keymap = keymap();
+        assert_eq!(parse(&keymap, "3l"), ["Move(Right, Some(3))"]);
+        assert_eq!(parse(&keymap, "10l0"), ["Move(Right, Some(10))", "Move(LineStart, None)"]);
+        assert_eq!(parse(&keymap, "2d3l"), ["Operate(Delete, Motion(Right), Some(6))"]);
+        assert_eq!(parse(&keymap, "dd"), ["Operate(Delete, Lines, None)"]);
+        assert_eq!(parse(&keymap, "gUgUgUU"), ["Operate(Uppercase, Lines, None)", "Operate(Uppercase, Lines, None)"]);
+        // operators only take motions; anything else cancels
+        assert_eq!(parse(&keymap, "dul"), ["Move(Right, None)"]);
+        assert_eq!(parse(&keymap, "gxu"), ["Undo"]);
+        // text objects only follow an operator, so `i` alone does not wait for more keys
+        assert_eq!(parse(&keymap, "i"), ["Insert(Cursor)"]);
+        // the register comes first, and the count may go either side of it
+        assert_eq!(parse(&keymap, "2\"ad"), ["SelectRegister('a')"]);
+        assert_eq!(parse(&keymap, "2\"add"), ["SelectRegister('a')", "Operate(Delete, Lines, Some(2))"]);
+        assert_eq!(parse(&keymap, "d2iw"), ["Operate(Delete, Object(TextObject { kind: Word { big: false }, inner: true }), Some(2))"]);
+    }
+
+    #[test]
+    fn test_visual_operators_act_at_once() {
+        let mut keymap = keymap();
+        keymap.selecting = true;
+        assert_eq!(parse(&keymap, "d"), ["Operate(Delete, Selection, None)"]);
+        assert_eq!(parse(&keymap, "2iwl"), [
+            "SelectObject(TextObject { kind: Word { big: false }, inner: true }, Some(2))",
+            "Move(Right, None)",
+        ]);
+    }
+
+    #[test]
+    fn test_pending_keys_time_out() {
+        let keymap = keymap();
+        let mut parser = KeyParser::default();
+        assert!(parser.feed(&keymap, Key::char('2')).is_none());
+        assert!(parser.feed(&keymap, Key::char('g')).is_none());
+        assert_eq!(parser.pending(), "2g");
+        assert!(parser.expire(&keymap).is_none());
+        assert!(!parser.is_empty());
+
+        parser.last_key = Some(Instant::now() - TIMEOUT * 2);
+        assert!(parser.expire(&keymap).is_none());
+        assert!(parser.is_empty());
+    }
+}
@@ -21,7 +21,6 @@ impl FileLogger {
         let logger = FileLogger {
             log_file: OpenOptions::new()
                 .create(true)
-                .write(true)
                 .append(true)
                 .open(&path)?,
         };
@@ -1,19 +1,44 @@
-use std::io::{self, stdout};
-use std::panic;
+use std::io::{self, stdout, Stdout};
+use std::panic::{self, AssertUnwindSafe};
 use std::process;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::Result;
-use crossterm::event::{read, Event, KeyCode};
+use std::time::Duration;
+
+use crossterm::event::{poll, read, Event};
 use crossterm::{terminal, ExecutableCommand};
 use log::{debug, error, info, warn};
-use dirs::home_dir;
 
 mod editor;
-use editor::{Editor, Mode};
+use editor::Editor;
 
 mod buffer;
+use buffer::{Buffer, BufferError, LineEnding};
+
+mod encoding;
+use encoding::Encoding;
+
+mod clipboard;
+use clipboard::Clipboard;
+mod command;
+mod diff;
+mod fileio;
+mod history;
+mod keymap;
 mod logger;
+mod motion;
+mod operator;
+mod register;
+mod screen;
+mod search;
+mod settings;
+mod substitute;
+mod swap;
+mod text;
+mod textobject;
+mod window;
 
 static PANIC_CLEANUP: AtomicBool = AtomicBool::new(false);
 
@@ -29,25 +54,77 @@ fn cleanup() -> io::Result<()> {
     Ok(())
 }
 
+/// Command-line arguments:
+/// `vix [--encoding=ENC] [--fileencoding=ENC] [--fileformat=unix|dos] [file...]`.
+/// `--encoding` decodes the file as ENC instead of guessing; `--fileencoding`
+/// and `--fileformat` convert it when it is next written. Every file is
+/// opened in a buffer of its own, and the first one is shown.
+struct Options {
+    files: Vec<String>,
+    encoding: Option<Encoding>,
+    fileencoding: Option<Encoding>,
+    fileformat: Option<LineEnding>,
+}
+
+fn parse_encoding(value: &str) -> Result<Encoding> {
+    Encoding::from_name(value).ok_or_else(|| anyhow::anyhow!("Unknown encoding: {}", value))
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options { files: Vec::new(), encoding: None, fileencoding: None, fileformat: None };
+        for arg in args {
+            if let Some(value) = arg.strip_prefix("--encoding=").or_else(|| arg.strip_prefix("--enc=")) {
+                options.encoding = Some(parse_encoding(value)?);
+            } else if let Some(value) = arg.strip_prefix("--fileencoding=").or_else(|| arg.strip_prefix("--fenc=")) {
+                options.fileencoding = Some(parse_encoding(value)?);
+            } else if let Some(value) = arg.strip_prefix("--fileformat=").or_else(|| arg.strip_prefix("--ff=")) {
+                let line_ending = LineEnding::from_name(value)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown fileformat: {}", value))?;
+                options.fileformat = Some(line_ending);
+            } else {
+                options.files.push(arg);
+            }
+        }
+        Ok(options)
+    }
+}
+
 fn main() -> Result<()> {
-    // Initialize logger with log file in user's home directory
-    let log_path = home_dir()
+    // Initialize logger with log file in the state directory
+    let log_path = buffer::state_dir()
         .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?
-        .join(".vix")
         .join("vix.log");
     
     logger::FileLogger::init(log_path)?;
     info!("Starting vix editor");
+    let options = Options::parse(std::env::args().skip(1))?;
     
     let mut stdout = stdout();
     debug!("Initializing terminal in raw mode");
     terminal::enable_raw_mode()?;
     stdout.execute(terminal::EnterAlternateScreen)?;
 
-    let file = std::env::args().nth(1);
-    debug!("Opening file: {:?}", file);
-    let buffer = buffer::Buffer::from_file(file)?;
-    let mut editor = Editor::with_buffer(buffer);
+    let mut buffers = Vec::new();
+    for file in &options.files {
+        buffers.push(open(Some(file.clone()), &options)?);
+    }
+    if buffers.is_empty() {
+        buffers.push(open(None, &options)?);
+    }
+    let mut buffers = buffers.into_iter();
+    let mut editor = Editor::with_buffer(buffers.next().expect("at least one buffer"));
+    for buffer in buffers {
+        editor.add_buffer(buffer);
+    }
+    editor.registers.clipboard = Clipboard::detect();
+
+    // SIGTERM and SIGHUP (the terminal going away) are noticed by the event loop
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGHUP] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate))?;
+    }
+
     let original_hook = panic::take_hook();
     panic::set_hook(Box::new(move |panic_info| {
         error!("Panic occurred: {}", panic_info);
@@ -55,27 +132,71 @@ fn main() -> Result<()> {
             error!("Error during cleanup: {}", e);
         }
         original_hook(panic_info);
-        process::exit(1);
     }));
 
-    editor.render(&mut stdout)?;
+    // unwinding back here gives the panic path the editor to rescue changes from
+    match panic::catch_unwind(AssertUnwindSafe(|| run(&mut editor, &mut stdout, &terminate))) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("Editor stopped with an error: {}", e);
+            editor.save_recovery();
+            cleanup()?;
+            return Err(e);
+        }
+        Err(_) => {
+            editor.save_recovery();
+            process::exit(1);
+        }
+    }
+
+    cleanup()?;
+    Ok(())
+}
+
+fn open(file: Option<String>, options: &Options) -> Result<Buffer> {
+    debug!("Opening file: {:?}", file);
+    let mut buffer = match Buffer::from_file(file.clone(), options.encoding) {
+        Err(BufferError::FileNotFound(_)) if let Some(file) = file => Buffer::new_file(file),
+        result => result?,
+    };
+    if let Some(encoding) = options.fileencoding {
+        buffer.set_encoding(encoding);
+    }
+    if let Some(line_ending) = options.fileformat {
+        buffer.set_line_ending(line_ending);
+    }
+    Ok(buffer)
+}
+
+fn run(editor: &mut Editor, stdout: &mut Stdout, terminate: &AtomicBool) -> Result<()> {
+    editor.render(stdout)?;
 
     'outer: loop {
+        if terminate.load(Ordering::SeqCst) {
+            warn!("Terminated by signal, saving recovery file");
+            editor.save_recovery();
+            break 'outer;
+        }
+        // wake up now and then to write the swap file and notice files
+        // changed by other programs
+        if !poll(Duration::from_millis(500))? {
+            if editor.idle() {
+                editor.render(stdout)?;
+            }
+            continue;
+        }
         let ev = read()?;
         match ev {
+            Event::Resize(..) => editor.render(stdout)?,
             Event::Key(key) => {
                 debug!("Key event received: {:?}", key);
-                if editor.mode == Mode::Normal {
-                    if let KeyCode::Char('q') = key.code {
-                        info!("Quit command received, exiting editor");
-                        break 'outer;
-                    }
-                }
-
                 if let Some(action) = editor.handle_event(ev) {
                     debug!("Applying editor action");
                     editor.apply_action(action);
-                    editor.render(&mut stdout)?;
+                    if editor.should_quit {
+                        break 'outer;
+                    }
+                    editor.render(stdout)?;
                 }
             }
             _ => {
@@ -83,7 +204,5 @@ fn main() -> Result<()> {
             }
         }
     }
-
-    cleanup()?;
     Ok(())
 }
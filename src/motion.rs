@@ -0,0 +1,427 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+
+/// How much text a motion covers when an operator uses it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MotionKind {
+    /// Up to but not including the target, like `h` and `w`.
+    Exclusive,
+    /// Including the character at the target, like `$` and `e`.
+    Inclusive,
+    /// Every line from the cursor's to the target's, like `j` and `G`.
+    Linewise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `w` and `W`; a big word is anything between blanks.
+    WordForward { big: bool },
+    WordBackward { big: bool },
+    WordEnd { big: bool },
+    /// `gg`, or line `count` when one is given.
+    FirstLine,
+    /// `G`, or line `count` when one is given.
+    LastLine,
+    ParagraphForward,
+    ParagraphBackward,
+    /// `f`, `F`, `t` and `T`: to (or just before) a character on the line.
+    Find { target: char, forward: bool, till: bool },
+    /// `;` and `,`, resolved by the editor into the last `Find`.
+    RepeatFind { reverse: bool },
+    /// `n` and `N`, searched for by the editor with the last pattern.
+    SearchNext { reverse: bool },
+    /// `*` and `#`: searches for the word under the cursor.
+    SearchWord { forward: bool },
+    MatchBracket,
+    /// `gj` and `gk`: a screen row down or up, resolved by the editor, which
+    /// knows how lines wrap.
+    ScreenLine { down: bool },
+}
+
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match self {
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine => MotionKind::Linewise,
+            Motion::LineEnd | Motion::WordEnd { .. } | Motion::Find { forward: true, .. } | Motion::MatchBracket => {
+                MotionKind::Inclusive
+            }
+            _ => MotionKind::Exclusive,
+        }
+    }
+
+    /// Where `count` repetitions of the motion take the cursor from `pos`, or
+    /// `None` when it cannot move at all. `past_end` lets the cursor land just
+    /// after the last character, which operators need to reach it.
+    pub fn apply(&self, buffer: &Buffer, (line, col): (usize, usize), count: Option<usize>, past_end: bool) -> Option<(usize, usize)> {
+        let n = count.unwrap_or(1).max(1);
+        let last_line = buffer.len() - 1;
+        let last_col = |line: usize| {
+            let len = buffer.line_length(line).unwrap_or(0);
+            if past_end { len } else { len.saturating_sub(1) }
+        };
+        let target = match *self {
+            Motion::Left if col > 0 => Some((line, col.saturating_sub(n))),
+            Motion::Right if col < last_col(line) => Some((line, (col + n).min(last_col(line)))),
+            Motion::Up if line > 0 => {
+                let target = line.saturating_sub(n);
+                Some((target, col.min(last_col(target))))
+            }
+            Motion::Down if line < last_line => {
+                let target = (line + n).min(last_line);
+                Some((target, col.min(last_col(target))))
+            }
+            Motion::LineStart => Some((line, 0)),
+            Motion::FirstNonBlank => Some((line, first_non_blank(buffer, line))),
+            Motion::LineEnd => {
+                let target = (line + n - 1).min(last_line);
+                Some((target, buffer.line_length(target).unwrap_or(0).saturating_sub(1)))
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let default = if *self == Motion::FirstLine { 0 } else { last_line };
+                let target = count.map_or(default, |n| n.clamp(1, last_line + 1) - 1);
+                Some((target, first_non_blank(buffer, target)))
+            }
+            Motion::WordForward { big } => {
+                let mut stream = Stream::new(buffer, (line, col), big);
+                for _ in 0..n {
+                    word_forward(&mut stream);
+                }
+                let (line, col) = stream.pos();
+                Some((line, col.min(last_col(line))))
+            }
+            Motion::WordBackward { big } => {
+                let mut stream = Stream::new(buffer, (line, col), big);
+                for _ in 0..n {
+                    word_backward(&mut stream);
+                }
+                Some(stream.pos())
+            }
+            Motion::WordEnd { big } => {
+                let mut stream = Stream::new(buffer, (line, col), big);
+                for _ in 0..n {
+                    word_end(&mut stream);
+                }
+                // running out of words leaves the stream on the final line break
+                let (line, col) = stream.pos();
+                Some((line, col.min(last_col(line))))
+            }
+            Motion::ParagraphForward => {
+                let mut target = line;
+                for _ in 0..n {
+                    while target < last_line && is_empty(buffer, target) {
+                        target += 1;
+                    }
+                    while target < last_line && !is_empty(buffer, target) {
+                        target += 1;
+                    }
+                }
+                if is_empty(buffer, target) { Some((target, 0)) } else { Some((target, last_col(target))) }
+            }
+            Motion::ParagraphBackward => {
+                let mut target = line;
+                for _ in 0..n {
+                    while target > 0 && is_empty(buffer, target) {
+                        target -= 1;
+                    }
+                    while target > 0 && !is_empty(buffer, target) {
+                        target -= 1;
+                    }
+                }
+                Some((target, 0))
+            }
+            Motion::Find { target, forward, till } => {
+                let text = buffer.get_line(line).ok()?;
+                let graphemes: Vec<&str> = text.graphemes(true).collect();
+                let mut buf = [0u8; 4];
+                let wanted: &str = target.encode_utf8(&mut buf);
+                let found = if forward {
+                    (col + 1..graphemes.len()).filter(|i| graphemes[*i] == wanted).nth(n - 1)
+                } else {
+                    (0..col).rev().filter(|i| graphemes[*i] == wanted).nth(n - 1)
+                }?;
+                let found = match (till, forward) {
+                    (false, _) => found,
+                    (true, true) => found - 1,
+                    (true, false) => found + 1,
+                };
+                Some((line, found))
+            }
+            Motion::RepeatFind { .. } | Motion::SearchNext { .. } | Motion::SearchWord { .. } | Motion::ScreenLine { .. } => None,
+            Motion::MatchBracket => match_bracket(buffer, (line, col)),
+            _ => None,
+        }?;
+        (target != (line, col) || matches!(self, Motion::LineStart | Motion::FirstNonBlank | Motion::LineEnd
+            | Motion::FirstLine | Motion::LastLine)).then_some(target)
+    }
+}
+
+/// Where `cw` changes to. It acts like `ce`, except that a cursor already
+/// on the last character of a word changes just that word. `None` when the
+/// cursor is on a blank, where `cw` is an ordinary `w`.
+pub fn change_word_target(buffer: &Buffer, pos: (usize, usize), big: bool, count: Option<usize>) -> Option<(usize, usize)> {
+    let stream = Stream::new(buffer, pos, big);
+    let class = stream.class();
+    if matches!(class, Class::Blank | Class::EmptyLine) {
+        return None;
+    }
+    let mut next = Stream::new(buffer, pos, big);
+    let at_word_end = !next.next() || next.class() != class;
+    let end = Motion::WordEnd { big };
+    match count.unwrap_or(1).max(1) {
+        1 if at_word_end => Some(pos),
+        n if at_word_end => end.apply(buffer, pos, Some(n - 1), true),
+        n => end.apply(buffer, pos, Some(n), true),
+    }
+}
+
+/// Column of the first character on `line` that is not a space or tab.
+pub fn first_non_blank(buffer: &Buffer, line: usize) -> usize {
+    buffer.get_line(line)
+        .map(|text| {
+            let indent = text.len() - text.trim_start_matches([' ', '\t']).len();
+            indent.min(text.len().saturating_sub(1))
+        })
+        .unwrap_or(0)
+}
+
+fn is_empty(buffer: &Buffer, line: usize) -> bool {
+    buffer.line_length(line).is_ok_and(|len| len == 0)
+}
+
+// Word motions see the buffer as a stream of classified characters, with a
+// blank after each line and an empty line counting as a word of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Blank,
+    Punctuation,
+    Word,
+    EmptyLine,
+}
+
+pub fn classify(grapheme: &str, big: bool) -> Class {
+    let c = grapheme.chars().next().unwrap_or(' ');
+    if c.is_whitespace() {
+        Class::Blank
+    } else if big || c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else {
+        Class::Punctuation
+    }
+}
+
+struct Stream<'a> {
+    buffer: &'a Buffer,
+    big: bool,
+    line: usize,
+    col: usize,
+    classes: Vec<Class>,
+}
+
+impl<'a> Stream<'a> {
+    fn new(buffer: &'a Buffer, (line, col): (usize, usize), big: bool) -> Self {
+        let mut stream = Stream { buffer, big, line, col, classes: Vec::new() };
+        stream.load(line);
+        stream.col = col.min(stream.classes.len() - 1);
+        stream
+    }
+
+    fn load(&mut self, line: usize) {
+        self.line = line;
+        let text = self.buffer.get_line(line).unwrap_or_default();
+        self.classes = text.graphemes(true).map(|g| classify(g, self.big)).collect();
+        // the line break, or the whole of an empty line
+        self.classes.push(if self.classes.is_empty() { Class::EmptyLine } else { Class::Blank });
+    }
+
+    fn pos(&self) -> (usize, usize) {
+        (self.line, self.col)
+    }
+
+    fn class(&self) -> Class {
+        self.classes[self.col]
+    }
+
+    fn next(&mut self) -> bool {
+        if self.col + 1 < self.classes.len() {
+            self.col += 1;
+        } else if self.line + 1 < self.buffer.len() {
+            self.load(self.line + 1);
+            self.col = 0;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn prev(&mut self) -> bool {
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.line > 0 {
+            self.load(self.line - 1);
+            // land on the last character, not the line break
+            self.col = self.classes.len().saturating_sub(2);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+fn word_forward(stream: &mut Stream) {
+    let start = stream.class();
+    if start != Class::Blank {
+        while stream.class() == start {
+            if !stream.next() {
+                return;
+            }
+        }
+    }
+    while stream.class() == Class::Blank {
+        if !stream.next() {
+            return;
+        }
+    }
+}
+
+fn word_end(stream: &mut Stream) {
+    if !stream.next() {
+        return;
+    }
+    while matches!(stream.class(), Class::Blank | Class::EmptyLine) {
+        if !stream.next() {
+            return;
+        }
+    }
+    let class = stream.class();
+    while stream.next() {
+        if stream.class() != class {
+            stream.prev();
+            return;
+        }
+    }
+}
+
+fn word_backward(stream: &mut Stream) {
+    if !stream.prev() {
+        return;
+    }
+    while stream.class() == Class::Blank {
+        if !stream.prev() {
+            return;
+        }
+    }
+    let class = stream.class();
+    if class == Class::EmptyLine {
+        return;
+    }
+    while stream.prev() {
+        if stream.class() != class {
+            stream.next();
+            return;
+        }
+    }
+}
+
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+// `%`: the first bracket at or after the cursor on its line, to its partner
+fn match_bracket(buffer: &Buffer, (line, col): (usize, usize)) -> Option<(usize, usize)> {
+    let text = buffer.get_line(line).ok()?;
+    let (start, c) = text.graphemes(true).enumerate().skip(col)
+        .filter_map(|(i, g)| Some((i, g.chars().next()?)))
+        .find(|(_, c)| BRACKETS.iter().any(|(open, close)| c == open || c == close))?;
+    let (open, close) = *BRACKETS.iter().find(|(open, close)| c == *open || c == *close)?;
+    let forward = c == open;
+
+    let mut depth = 0usize;
+    let mut row = line;
+    let mut graphemes: Vec<char> = text.graphemes(true).map(|g| g.chars().next().unwrap_or(' ')).collect();
+    let mut i = start as isize;
+    loop {
+        while (0..graphemes.len() as isize).contains(&i) {
+            let g = graphemes[i as usize];
+            if g == open || g == close {
+                if (g == open) == forward {
+                    depth += 1;
+                } else {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((row, i as usize));
+                    }
+                }
+            }
+            i += if forward { 1 } else { -1 };
+        }
+        if forward && row + 1 < buffer.len() {
+            row += 1;
+        } else if !forward && row > 0 {
+            row -= 1;
+        } else {
+            return None;
+        }
+        graphemes = buffer.get_line(row).ok()?.graphemes(true).map(|g| g.chars().next().unwrap_or(' ')).collect();
+        i = if forward { 0 } else { graphemes.len() as isize - 1 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(text: &str) -> Buffer {
+        let mut buffer = Buffer::from_file(None, None).unwrap();
+        buffer.insert_text(0, 0, text).unwrap();
+        buffer
+    }
+
+    fn walk(buffer: &Buffer, motion: Motion, mut pos: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut stops = Vec::new();
+        while let Some(next) = motion.apply(buffer, pos, None, false) {
+            stops.push(next);
+            pos = next;
+        }
+        stops
+    }
+
+    #[test]
+    fn test_word_motions() {
+        let buffer = buffer_with("foo.bar  baz\n\n  x-y");
+        let w = Motion::WordForward { big: false };
+        assert_eq!(walk(&buffer, w, (0, 0)), [(0, 3), (0, 4), (0, 9), (1, 0), (2, 2), (2, 3), (2, 4)]);
+        let big_w = Motion::WordForward { big: true };
+        assert_eq!(walk(&buffer, big_w, (0, 0)), [(0, 9), (1, 0), (2, 2), (2, 4)]);
+        let e = Motion::WordEnd { big: false };
+        assert_eq!(walk(&buffer, e, (0, 0)), [(0, 2), (0, 3), (0, 6), (0, 11), (2, 2), (2, 3), (2, 4)]);
+        let b = Motion::WordBackward { big: true };
+        assert_eq!(walk(&buffer, b, (2, 4)), [(2, 2), (1, 0), (0, 9), (0, 0)]);
+        assert_eq!(w.apply(&buffer, (2, 2), Some(5), true), Some((2, 5)));
+    }
+
+    #[test]
+    fn test_line_paragraph_and_find_motions() {
+        let buffer = buffer_with("  one (two)\nthree\n\nfour [\n]");
+        assert_eq!(Motion::FirstNonBlank.apply(&buffer, (0, 5), None, false), Some((0, 2)));
+        assert_eq!(Motion::LastLine.apply(&buffer, (0, 5), None, false), Some((4, 0)));
+        assert_eq!(Motion::FirstLine.apply(&buffer, (4, 0), Some(2), false), Some((1, 0)));
+        assert_eq!(walk(&buffer, Motion::ParagraphForward, (0, 0)), [(2, 0), (4, 0)]);
+        assert_eq!(walk(&buffer, Motion::ParagraphBackward, (4, 0)), [(2, 0), (0, 0)]);
+
+        let find = |target, forward, till| Motion::Find { target, forward, till };
+        assert_eq!(find('o', true, false).apply(&buffer, (0, 0), Some(2), false), Some((0, 9)));
+        assert_eq!(find('(', true, true).apply(&buffer, (0, 0), None, false), Some((0, 5)));
+        assert_eq!(find('n', false, true).apply(&buffer, (0, 10), None, false), Some((0, 4)));
+        assert_eq!(find('z', true, false).apply(&buffer, (0, 0), None, false), None);
+
+        assert_eq!(Motion::MatchBracket.apply(&buffer, (0, 0), None, false), Some((0, 10)));
+        assert_eq!(Motion::MatchBracket.apply(&buffer, (0, 10), None, false), Some((0, 6)));
+        assert_eq!(Motion::MatchBracket.apply(&buffer, (3, 0), None, false), Some((4, 0)));
+    }
+}
@@ -0,0 +1,236 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::{Buffer, BufferError};
+use crate::motion::{self, Motion, MotionKind};
+use crate::text;
+use crate::textobject::TextObject;
+
+/// Columns added or removed by `>` and `<`, and one level of `=`.
+pub const SHIFT_WIDTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+    Reindent,
+}
+
+impl Operator {
+    /// Operators that always work on whole lines, whatever the motion.
+    pub fn is_linewise(&self) -> bool {
+        matches!(self, Operator::ShiftRight | Operator::ShiftLeft | Operator::Reindent)
+    }
+}
+
+/// What an operator works on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Motion(Motion),
+    Object(TextObject),
+    /// `count` lines from the cursor down, for doubled operators like `dd`.
+    Lines,
+    /// The Visual mode selection.
+    Selection,
+}
+
+/// Text covered by an operator. A linewise range spans whole lines
+/// `start.0..=end.0`; otherwise `end` is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub linewise: bool,
+}
+
+impl Range {
+    pub fn lines(first: usize, last: usize) -> Self {
+        Range { start: (first, 0), end: (last, 0), linewise: true }
+    }
+
+    /// The range between the cursor and where a motion took it.
+    pub fn from_motion(buffer: &Buffer, from: (usize, usize), to: (usize, usize), kind: MotionKind) -> Self {
+        let (start, end) = if to < from { (to, from) } else { (from, to) };
+        match kind {
+            MotionKind::Linewise => Range::lines(start.0, end.0),
+            // ending at the start of a line leaves that line out, see `:h exclusive-linewise`
+            MotionKind::Exclusive if end.1 == 0 && end.0 > start.0 => {
+                let line = end.0 - 1;
+                if start.1 <= motion::first_non_blank(buffer, start.0) {
+                    Range::lines(start.0, line)
+                } else {
+                    Range { start, end: (line, buffer.line_length(line).unwrap_or(0)), linewise: false }
+                }
+            }
+            MotionKind::Exclusive => Range { start, end, linewise: false },
+            MotionKind::Inclusive => {
+                let len = buffer.line_length(end.0).unwrap_or(0);
+                Range { start, end: (end.0, (end.1 + 1).min(len)), linewise: false }
+            }
+        }
+    }
+
+    /// The same range as a charwise one, from the start of its first line to
+    /// the end of its last.
+    pub fn charwise(&self, buffer: &Buffer) -> Result<Range, BufferError> {
+        if self.linewise {
+            let end = (self.end.0, buffer.line_length(self.end.0)?);
+            Ok(Range { start: (self.start.0, 0), end, linewise: false })
+        } else {
+            Ok(*self)
+        }
+    }
+
+    /// The covered text; linewise text ends every line with a newline.
+    pub fn text(&self, buffer: &Buffer) -> Result<String, BufferError> {
+        let Range { start, end, .. } = self.charwise(buffer)?;
+        let text = buffer.text_range(start, end)?;
+        Ok(if self.linewise { text + "\n" } else { text })
+    }
+}
+
+/// A Visual-Block selection: lines `first..=last`, screen columns `left..right`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub first: usize,
+    pub last: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Block {
+    /// The grapheme columns of `line` inside the block. A wide character
+    /// partly inside counts as inside; a line too short gives an empty range.
+    pub fn cols(&self, line: &str) -> (usize, usize) {
+        let mut cols: Option<(usize, usize)> = None;
+        let mut x = 0;
+        for (col, grapheme) in line.graphemes(true).enumerate() {
+            let width = text::grapheme_width(grapheme, x);
+            if x + width > self.left && x < self.right {
+                cols = Some((cols.map_or(col, |(start, _)| start), col + 1));
+            }
+            x += width;
+        }
+        cols.unwrap_or_else(|| {
+            let col = text::col_at_display(line, self.left);
+            (col, col)
+        })
+    }
+}
+
+fn indent_width(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .map(|c| if c == '\t' { SHIFT_WIDTH } else { 1 })
+        .sum()
+}
+
+fn set_indent(buffer: &mut Buffer, line: usize, width: usize) -> Result<(), BufferError> {
+    let text = buffer.get_line(line)?;
+    let old = text.len() - text.trim_start_matches([' ', '\t']).len();
+    let blank = old == text.len();
+    drop(text);
+    buffer.delete_text((line, 0), (line, old))?;
+    // blank lines are left empty rather than filled with indentation
+    if !blank {
+        buffer.insert_text(line, 0, &" ".repeat(width))?;
+    }
+    Ok(())
+}
+
+/// Moves lines `first..=last` one shift width right or left. Empty lines
+/// are not indented.
+pub fn shift(buffer: &mut Buffer, first: usize, last: usize, right: bool) -> Result<(), BufferError> {
+    for line in first..=last {
+        let width = indent_width(&buffer.get_line(line)?);
+        let width = if right { width + SHIFT_WIDTH } else { width.saturating_sub(SHIFT_WIDTH) };
+        set_indent(buffer, line, width)?;
+    }
+    Ok(())
+}
+
+/// Reindents lines `first..=last` following brackets: a level deeper after a
+/// line ending in an opening bracket, a level shallower for a line starting
+/// with a closing one. The first line takes its cue from the line above.
+pub fn reindent(buffer: &mut Buffer, first: usize, last: usize) -> Result<(), BufferError> {
+    let opens = |text: &str| text.trim_end().ends_with(['{', '(', '[']);
+    let mut indent = 0;
+    for line in (0..first).rev() {
+        let text = buffer.get_line(line)?;
+        if !text.trim().is_empty() {
+            indent = indent_width(&text) + if opens(&text) { SHIFT_WIDTH } else { 0 };
+            break;
+        }
+    }
+    for line in first..=last {
+        let text = buffer.get_line(line)?.into_owned();
+        let trimmed = text.trim_start();
+        if trimmed.is_empty() {
+            set_indent(buffer, line, 0)?;
+            continue;
+        }
+        let width = if trimmed.starts_with(['}', ')', ']']) { indent.saturating_sub(SHIFT_WIDTH) } else { indent };
+        set_indent(buffer, line, width)?;
+        indent = width + if opens(trimmed) { SHIFT_WIDTH } else { 0 };
+    }
+    Ok(())
+}
+
+/// Replaces the text in `range` with its upper or lower case form.
+pub fn change_case(buffer: &mut Buffer, range: &Range, upper: bool) -> Result<(), BufferError> {
+    let Range { start, end, .. } = range.charwise(buffer)?;
+    let text = buffer.text_range(start, end)?;
+    let changed = if upper { text.to_uppercase() } else { text.to_lowercase() };
+    if changed != text {
+        buffer.delete_text(start, end)?;
+        buffer.insert_text(start.0, start.1, &changed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(text: &str) -> Buffer {
+        let mut buffer = Buffer::from_file(None, None).unwrap();
+        buffer.insert_text(0, 0, text).unwrap();
+        buffer
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        (0..buffer.len()).map(|i| buffer.get_line(i).unwrap().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_shift_and_reindent() {
+        let mut buffer = buffer_with("fn main() {\nlet x = [\n1,\n];\n\n  }");
+        reindent(&mut buffer, 1, 5).unwrap();
+        assert_eq!(lines(&buffer), ["fn main() {", "    let x = [", "        1,", "    ];", "", "}"]);
+
+        shift(&mut buffer, 0, 4, false).unwrap();
+        assert_eq!(lines(&buffer)[..3], ["fn main() {", "let x = [", "    1,"]);
+        shift(&mut buffer, 2, 4, true).unwrap();
+        assert_eq!(lines(&buffer)[2..5], ["        1,", "    ];", ""]);
+    }
+
+    #[test]
+    fn test_ranges_and_case() {
+        let mut buffer = buffer_with("straße\nnext");
+        let inclusive = Range::from_motion(&buffer, (0, 3), (0, 1), MotionKind::Inclusive);
+        assert_eq!(inclusive.text(&buffer).unwrap(), "tra");
+        assert_eq!(Range::lines(0, 1).text(&buffer).unwrap(), "straße\nnext\n");
+
+        change_case(&mut buffer, &Range { start: (0, 2), end: (0, 6), linewise: false }, true).unwrap();
+        assert_eq!(lines(&buffer), ["stRASSE", "next"]);
+
+        let block = Block { first: 0, last: 1, left: 2, right: 4 };
+        assert_eq!(block.cols("a中bc"), (1, 3));
+        assert_eq!(block.cols("abcdef"), (2, 4));
+        assert_eq!(block.cols("a"), (1, 1));
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::clipboard::{Clipboard, Selection};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("Invalid register name: '{0}'")]
+    Invalid(char),
+    #[error("Nothing in register {0}")]
+    Empty(char),
+}
+
+/// How put places a register's text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterKind {
+    /// Inside the cursor line.
+    Charwise,
+    /// Whole lines, above or below the cursor line; the text ends in a newline.
+    Linewise,
+    /// A rectangle, one line of text per screen line, lined up under the cursor.
+    Blockwise,
+}
+
+/// Text yanked or deleted by an operator, ready to be put back with `p`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+
+impl Register {
+    pub fn charwise(text: impl Into<String>) -> Self {
+        Register { text: text.into(), kind: RegisterKind::Charwise }
+    }
+
+    fn append(&mut self, other: Register) {
+        if self.kind == RegisterKind::Charwise && other.kind == RegisterKind::Charwise {
+            self.text.push_str(&other.text);
+            return;
+        }
+        // anything appended to or from whole lines starts a line of its own
+        if self.kind != RegisterKind::Linewise || !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        self.text.push_str(&other.text);
+        if other.kind != RegisterKind::Linewise {
+            self.text.push('\n');
+        }
+        self.kind = RegisterKind::Linewise;
+    }
+}
+
+const UNNAMED: char = '"';
+
+/// Every register but `%`, which is the current file's name and so kept by
+/// the editor. See `:h registers`.
+#[derive(Default)]
+pub struct Registers {
+    slots: HashMap<char, Register>,
+    pub clipboard: Clipboard,
+}
+
+fn selection(name: char) -> Option<Selection> {
+    match name {
+        '+' => Some(Selection::Clipboard),
+        '*' => Some(Selection::Primary),
+        _ => None,
+    }
+}
+
+impl Registers {
+    pub fn get(&self, name: char) -> Result<Register, RegisterError> {
+        let name = name.to_ascii_lowercase();
+        if let Some(selection) = selection(name)
+            && let Some(text) = self.clipboard.paste(selection)
+        {
+            // text we copied ourselves keeps its kind
+            return Ok(match self.slots.get(&name) {
+                Some(ours) if ours.text == text => ours.clone(),
+                _ if text.ends_with('\n') => Register { text, kind: RegisterKind::Linewise },
+                _ => Register::charwise(text),
+            });
+        }
+        if !(is_writable(name) || matches!(name, ':' | '.')) {
+            return Err(RegisterError::Invalid(name));
+        }
+        self.slots.get(&name).cloned().ok_or(RegisterError::Empty(name))
+    }
+
+    /// Stores yanked or deleted text in register `name`, or an uppercase
+    /// name's lowercase register to append. Without a name a yank also goes
+    /// to `0`, and a delete to `1`, moving older deletes up to `9`, or to
+    /// `-` when it is less than a line.
+    pub fn store(&mut self, name: Option<char>, register: Register, yank: bool) -> Result<(), RegisterError> {
+        let register = match name {
+            Some('_') => return Ok(()),
+            None | Some(UNNAMED) => {
+                if yank {
+                    self.slots.insert('0', register.clone());
+                } else if register.kind == RegisterKind::Linewise || register.text.contains('\n') {
+                    for n in (1..9).rev() {
+                        if let Some(older) = self.slots.remove(&digit(n)) {
+                            self.slots.insert(digit(n + 1), older);
+                        }
+                    }
+                    self.slots.insert('1', register.clone());
+                } else {
+                    self.slots.insert('-', register.clone());
+                }
+                register
+            }
+            Some(name) if name.is_ascii_uppercase() => {
+                let name = name.to_ascii_lowercase();
+                let appended = match self.slots.remove(&name) {
+                    Some(mut old) => {
+                        old.append(register);
+                        old
+                    }
+                    None => register,
+                };
+                self.slots.insert(name, appended.clone());
+                appended
+            }
+            Some(name) if is_writable(name) => {
+                if let Some(selection) = selection(name) {
+                    self.clipboard.copy(selection, &register.text);
+                }
+                self.slots.insert(name, register.clone());
+                register
+            }
+            Some(name) => return Err(RegisterError::Invalid(name)),
+        };
+        self.slots.insert(UNNAMED, register);
+        Ok(())
+    }
+
+    /// Stores a recorded macro in register `name`. Unlike a yank this leaves
+    /// the unnamed register alone.
+    pub fn store_macro(&mut self, name: char, text: String) -> Result<(), RegisterError> {
+        let unnamed = self.slots.get(&UNNAMED).cloned();
+        self.store(Some(name), Register::charwise(text), true)?;
+        match unnamed {
+            Some(register) => self.slots.insert(UNNAMED, register),
+            None => self.slots.remove(&UNNAMED),
+        };
+        Ok(())
+    }
+
+    /// Sets one of the read-only registers, `:` for the last command line
+    /// and `.` for the last inserted text.
+    pub fn set_read_only(&mut self, name: char, text: String) {
+        self.slots.insert(name, Register::charwise(text));
+    }
+}
+
+fn is_writable(name: char) -> bool {
+    name.is_ascii_lowercase() || name.is_ascii_digit() || matches!(name, UNNAMED | '-' | '+' | '*')
+}
+
+fn digit(n: u32) -> char {
+    char::from_digit(n, 10).unwrap_or('9')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linewise(text: &str) -> Register {
+        Register { text: text.to_string(), kind: RegisterKind::Linewise }
+    }
+
+    #[test]
+    fn test_unnamed_numbered_and_small_delete_registers() {
+        let mut registers = Registers::default();
+        registers.store(None, Register::charwise("yanked"), true).unwrap();
+        registers.store(None, linewise("first\n"), false).unwrap();
+        registers.store(None, linewise("second\n"), false).unwrap();
+        registers.store(None, Register::charwise("word"), false).unwrap();
+        registers.store(Some('_'), linewise("gone\n"), false).unwrap();
+
+        assert_eq!(registers.get('"'), Ok(Register::charwise("word")));
+        assert_eq!(registers.get('0'), Ok(Register::charwise("yanked")));
+        assert_eq!(registers.get('1'), Ok(linewise("second\n")));
+        assert_eq!(registers.get('2'), Ok(linewise("first\n")));
+        assert_eq!(registers.get('-'), Ok(Register::charwise("word")));
+        assert_eq!(registers.get('3'), Err(RegisterError::Empty('3')));
+    }
+
+    #[test]
+    fn test_named_registers_append_and_read_only() {
+        let mut registers = Registers::default();
+        registers.store(Some('a'), Register::charwise("one"), true).unwrap();
+        registers.store(Some('A'), Register::charwise(" two"), true).unwrap();
+        assert_eq!(registers.get('a'), Ok(Register::charwise("one two")));
+        registers.store(Some('A'), linewise("three\n"), true).unwrap();
+        assert_eq!(registers.get('A'), Ok(linewise("one two\nthree\n")));
+        assert_eq!(registers.get('"'), Ok(linewise("one two\nthree\n")));
+        // a named register leaves "0 alone
+        assert_eq!(registers.get('0'), Err(RegisterError::Empty('0')));
+
+        assert_eq!(registers.store(Some('.'), Register::charwise("x"), true), Err(RegisterError::Invalid('.')));
+        registers.set_read_only('.', "typed".to_string());
+        assert_eq!(registers.get('.'), Ok(Register::charwise("typed")));
+        registers.store(Some('B'), linewise("new\n"), false).unwrap();
+        assert_eq!(registers.get('b'), Ok(linewise("new\n")));
+        assert_eq!(registers.get('!'), Err(RegisterError::Invalid('!')));
+    }
+}
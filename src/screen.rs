@@ -0,0 +1,350 @@
+use crossterm::cursor::MoveTo;
+use crossterm::style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, BeginSynchronizedUpdate, EndSynchronizedUpdate};
+use crossterm::QueueableCommand;
+use std::io::{self, Write};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::text;
+use crate::window::Rect;
+
+/// How a cell is drawn. `None` leaves the terminal's own colour.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub fn fg(self, color: Color) -> Self {
+        Self { fg: Some(color), ..self }
+    }
+
+    pub fn bg(self, color: Color) -> Self {
+        Self { bg: Some(color), ..self }
+    }
+
+    pub fn reverse(self) -> Self {
+        Self { reverse: true, ..self }
+    }
+}
+
+// One terminal cell. The cells a wide character covers after its first
+// have an empty symbol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cell {
+    symbol: String,
+    style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self { symbol: " ".to_string(), style: Style::default() }
+    }
+}
+
+impl Cell {
+    fn set(&mut self, symbol: &str, style: Style) {
+        self.symbol.clear();
+        self.symbol.push_str(symbol);
+        self.style = style;
+    }
+
+    fn continuation(&self) -> bool {
+        self.symbol.is_empty()
+    }
+}
+
+/// The terminal as two grids of cells: `front` is what it shows, `back` the
+/// frame being drawn. `flush` sends only the cells that differ, inside a
+/// synchronized update so the terminal shows the whole frame at once;
+/// terminals without mode 2026 ignore the sequences.
+#[derive(Debug, Default)]
+pub struct Screen {
+    width: u16,
+    height: u16,
+    front: Vec<Cell>,
+    back: Vec<Cell>,
+    // the terminal no longer matches `front`, as after a resize
+    stale: bool,
+    cursor: (u16, u16),
+}
+
+impl Screen {
+    pub fn new(width: u16, height: u16) -> Self {
+        let cells = vec![Cell::default(); width as usize * height as usize];
+        Self { width, height, front: cells.clone(), back: cells, stale: true, cursor: (0, 0) }
+    }
+
+    pub fn area(&self) -> Rect {
+        Rect { x: 0, y: 0, width: self.width, height: self.height }
+    }
+
+    /// Starts over with blank grids when the terminal changed size, so the
+    /// next flush redraws everything.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        if (width, height) != (self.width, self.height) {
+            *self = Self::new(width, height);
+        }
+    }
+
+    /// Blanks the back grid for a new frame.
+    pub fn clear(&mut self) {
+        for cell in &mut self.back {
+            cell.set(" ", Style::default());
+        }
+    }
+
+    /// Writes `text` from `(x, y)` until the end of the row, returning the
+    /// column after it. ASCII control characters show as `^M` and the like,
+    /// any others, tabs among them, as blanks.
+    pub fn put(&mut self, x: u16, y: u16, text: &str, style: Style) -> u16 {
+        self.put_line(x, y, text, 0, style)
+    }
+
+    /// Like `put`, for part of a line that starts at its screen column
+    /// `col`, so its tabs reach the line's tab stops.
+    pub fn put_line(&mut self, x: u16, y: u16, text: &str, col: usize, style: Style) -> u16 {
+        let mut x = x;
+        if y >= self.height {
+            return x;
+        }
+        let mut col = col;
+        for grapheme in text.graphemes(true) {
+            let width = text::grapheme_width(grapheme, col) as u16;
+            col += width as usize;
+            if width == 0 {
+                continue;
+            }
+            if x + width > self.width {
+                break;
+            }
+            if let Some(c) = text::caret(grapheme) {
+                self.set(x, y, "^", 1, style);
+                self.set(x + 1, y, c.encode_utf8(&mut [0; 4]), 1, style);
+                x += 2;
+            } else if grapheme.chars().any(char::is_control) {
+                for _ in 0..width {
+                    self.set(x, y, " ", 1, style);
+                    x += 1;
+                }
+            } else {
+                self.set(x, y, grapheme, width, style);
+                x += width;
+            }
+        }
+        x
+    }
+
+    /// Blanks `rect` in `style`.
+    pub fn fill(&mut self, rect: Rect, style: Style) {
+        let right = (rect.x + rect.width).min(self.width);
+        for y in rect.y..(rect.y + rect.height).min(self.height) {
+            for x in rect.x..right {
+                self.set(x, y, " ", 1, style);
+            }
+        }
+    }
+
+    /// Restyles `width` cells from `(x, y)`, keeping what they show. Wide
+    /// characters are restyled whole.
+    pub fn paint(&mut self, x: u16, y: u16, width: u16, style: Style) {
+        if y >= self.height || x >= self.width {
+            return;
+        }
+        let row = y as usize * self.width as usize;
+        let mut start = row + x as usize;
+        let mut end = row + (x + width).min(self.width) as usize;
+        while start > row && self.back[start].continuation() {
+            start -= 1;
+        }
+        while end < row + self.width as usize && self.back[end].continuation() {
+            end += 1;
+        }
+        for cell in &mut self.back[start..end] {
+            cell.style = style;
+        }
+    }
+
+    /// Where the terminal's cursor is left after the next flush.
+    pub fn set_cursor(&mut self, x: u16, y: u16) {
+        self.cursor = (x.min(self.width.saturating_sub(1)), y.min(self.height.saturating_sub(1)));
+    }
+
+    // Puts `symbol`, `width` cells wide, at `(x, y)`. A wide character it
+    // overlaps in part is blanked, so no half of one is left behind.
+    fn set(&mut self, x: u16, y: u16, symbol: &str, width: u16, style: Style) {
+        let row = y as usize * self.width as usize;
+        let start = row + x as usize;
+        let end = start + width as usize;
+        if self.back[start].continuation() {
+            let mut lead = start;
+            while lead > row && self.back[lead].continuation() {
+                lead -= 1;
+                let style = self.back[lead].style;
+                self.back[lead].set(" ", style);
+            }
+        }
+        let mut after = end;
+        while after < row + self.width as usize && self.back[after].continuation() {
+            let style = self.back[after].style;
+            self.back[after].set(" ", style);
+            after += 1;
+        }
+        self.back[start].set(symbol, style);
+        for cell in &mut self.back[start + 1..end] {
+            cell.set("", style);
+        }
+    }
+
+    /// Sends the cells of the back grid that differ from the front one, then
+    /// takes the back grid as what the terminal shows.
+    pub fn flush(&mut self, out: &mut impl Write) -> io::Result<()> {
+        out.queue(BeginSynchronizedUpdate)?;
+        if self.stale {
+            out.queue(SetAttribute(Attribute::Reset))?;
+            out.queue(terminal::Clear(terminal::ClearType::All))?;
+            for cell in &mut self.front {
+                cell.set(" ", Style::default());
+            }
+            self.stale = false;
+        }
+        let width = self.width as usize;
+        let mut style = Style::default();
+        for y in 0..self.height {
+            let row = y as usize * width;
+            // where the terminal's cursor is along this row, when known
+            let mut at = None;
+            let mut x = 0;
+            while x < width {
+                if self.back[row + x] == self.front[row + x] {
+                    x += 1;
+                    continue;
+                }
+                // a change to part of a wide character sends all of it
+                while x > 0 && self.back[row + x].continuation() {
+                    x -= 1;
+                }
+                if at != Some(x) {
+                    out.queue(MoveTo(x as u16, y))?;
+                }
+                let start = x;
+                while x < width && (x == start || self.back[row + x] != self.front[row + x] || self.back[row + x].continuation()) {
+                    let cell = &self.back[row + x];
+                    if !cell.continuation() {
+                        change_style(out, style, cell.style)?;
+                        style = cell.style;
+                        out.queue(Print(&cell.symbol))?;
+                    }
+                    x += 1;
+                }
+                at = Some(x);
+            }
+        }
+        change_style(out, style, Style::default())?;
+        out.queue(MoveTo(self.cursor.0, self.cursor.1))?;
+        out.queue(EndSynchronizedUpdate)?;
+        out.flush()?;
+        self.front.clone_from(&self.back);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn line(&self, y: u16) -> String {
+        let row = y as usize * self.width as usize;
+        self.back[row..row + self.width as usize].iter().map(|cell| cell.symbol.as_str()).collect()
+    }
+}
+
+fn change_style(out: &mut impl Write, from: Style, to: Style) -> io::Result<()> {
+    if from.reverse != to.reverse {
+        out.queue(SetAttribute(if to.reverse { Attribute::Reverse } else { Attribute::NoReverse }))?;
+    }
+    if from.fg != to.fg {
+        out.queue(SetForegroundColor(to.fg.unwrap_or(Color::Reset)))?;
+    }
+    if from.bg != to.bg {
+        out.queue(SetBackgroundColor(to.bg.unwrap_or(Color::Reset)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flushed(screen: &mut Screen) -> Vec<u8> {
+        let mut out = Vec::new();
+        screen.flush(&mut out).unwrap();
+        out
+    }
+
+    // what `flush` sends around the changes made in `changes`
+    fn expected(changes: impl FnOnce(&mut Vec<u8>) -> io::Result<()>, cursor: (u16, u16)) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.queue(BeginSynchronizedUpdate).unwrap();
+        changes(&mut out).unwrap();
+        out.queue(MoveTo(cursor.0, cursor.1)).unwrap();
+        out.queue(EndSynchronizedUpdate).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_flush_sends_only_changed_cells() {
+        let mut screen = Screen::new(10, 2);
+        screen.put(0, 0, "hello", Style::default());
+        let first = String::from_utf8(flushed(&mut screen)).unwrap();
+        assert!(first.contains("\x1b[2J"));
+        assert!(first.contains("hello"));
+
+        screen.clear();
+        screen.put(0, 0, "help", Style::default());
+        screen.put(2, 1, "x", Style::default().fg(Color::Red));
+        screen.set_cursor(4, 0);
+        assert_eq!(flushed(&mut screen), expected(|out| {
+            out.queue(MoveTo(3, 0))?.queue(Print("p "))?;
+            out.queue(MoveTo(2, 1))?.queue(SetForegroundColor(Color::Red))?.queue(Print("x"))?;
+            out.queue(SetForegroundColor(Color::Reset))?;
+            Ok(())
+        }, (4, 0)));
+
+        // nothing changed, nothing but the cursor is sent
+        assert_eq!(flushed(&mut screen), expected(|_| Ok(()), (4, 0)));
+
+        screen.resize(10, 3);
+        assert!(String::from_utf8(flushed(&mut screen)).unwrap().contains("\x1b[2J"));
+    }
+
+    #[test]
+    fn test_wide_characters_are_kept_whole() {
+        let mut screen = Screen::new(6, 1);
+        assert_eq!(screen.put(0, 0, "a中文bc", Style::default()), 6);
+        assert_eq!(screen.line(0), "a中文b");
+        flushed(&mut screen);
+
+        // writing over the second half of 中 blanks its first half
+        screen.put(2, 0, "x", Style::default());
+        assert_eq!(screen.line(0), "a x文b");
+        assert_eq!(flushed(&mut screen), expected(|out| {
+            out.queue(MoveTo(1, 0))?.queue(Print(" "))?.queue(Print("x"))?;
+            Ok(())
+        }, (0, 0)));
+
+        // a changed colour resends the whole of 文
+        screen.paint(4, 0, 1, Style::default().reverse());
+        assert_eq!(flushed(&mut screen), expected(|out| {
+            out.queue(MoveTo(3, 0))?.queue(SetAttribute(Attribute::Reverse))?.queue(Print("文"))?;
+            out.queue(SetAttribute(Attribute::NoReverse))?;
+            Ok(())
+        }, (0, 0)));
+
+        // control characters never reach the terminal, and a tab is blank
+        // up to the line's next tab stop
+        assert_eq!(screen.put_line(0, 0, "\t\x1b", 7, Style::default()), 3);
+        assert_eq!(screen.line(0), " ^[文b");
+
+        // too wide for what is left of the row
+        assert_eq!(screen.put(5, 0, "中", Style::default()), 5);
+    }
+}
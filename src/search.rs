@@ -0,0 +1,261 @@
+use regex::{Regex, RegexBuilder};
+use thiserror::Error;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::motion::{self, Class};
+use crate::text;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SearchError {
+    #[error("Invalid pattern: {0}")]
+    Invalid(String),
+    #[error("Pattern not found: {0}")]
+    NotFound(String),
+}
+
+/// A search pattern in the regex crate's syntax, plus vim's `\<` and `\>`
+/// for the start and end of a word. Smart-case: case is ignored unless the
+/// pattern has an uppercase letter.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub text: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(text: &str) -> Result<Self, SearchError> {
+        Pattern::with_case(text, None)
+    }
+
+    /// Like `new`, but `ignore_case`, when given, overrides smart-case.
+    pub fn with_case(text: &str, ignore_case: Option<bool>) -> Result<Self, SearchError> {
+        let regex = RegexBuilder::new(&translate(text))
+            .case_insensitive(ignore_case.unwrap_or(!has_uppercase(text)))
+            .build()
+            .map_err(|_| SearchError::Invalid(text.to_string()))?;
+        Ok(Pattern { text: text.to_string(), regex })
+    }
+
+    /// `*` and `#`: the whole word, and nothing else. As in vim, case is
+    /// ignored even when the word has capitals.
+    pub fn word(word: &str) -> Self {
+        let text = format!("\\<{}\\>", regex::escape(word));
+        Pattern::with_case(&text, Some(true)).expect("escaped word is a valid pattern")
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    /// The grapheme columns where matches start in `line`, overlapping ones
+    /// included, so that `n` can stop on each.
+    fn starts(&self, line: &str) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut byte = 0;
+        while let Some(m) = self.regex.find_at(line, byte) {
+            let col = text::grapheme_col(line, m.start());
+            if starts.last() != Some(&col) {
+                starts.push(col);
+            }
+            match line[m.start()..].chars().next() {
+                Some(c) => byte = m.start() + c.len_utf8(),
+                None => break,
+            }
+        }
+        starts
+    }
+
+    /// The grapheme column ranges of the matches in `line`, for highlighting.
+    pub fn matches(&self, line: &str) -> Vec<(usize, usize)> {
+        self.regex.find_iter(line)
+            .filter(|m| !m.is_empty())
+            .map(|m| (text::grapheme_col(line, m.start()), text::grapheme_col(line, m.end())))
+            .collect()
+    }
+
+    /// Where the `count`th match after `pos` (before it, when not `forward`)
+    /// starts, searching past the end of the buffer from the other end. Also
+    /// says whether the search wrapped around. Lines are read one at a time.
+    pub fn find(&self, buffer: &Buffer, pos: (usize, usize), forward: bool, count: Option<usize>) -> Result<((usize, usize), bool), SearchError> {
+        let mut pos = pos;
+        let mut wrapped = false;
+        for _ in 0..count.unwrap_or(1).max(1) {
+            let (next, wrap) = self.find_next(buffer, pos, forward)
+                .ok_or_else(|| SearchError::NotFound(self.text.clone()))?;
+            pos = next;
+            wrapped |= wrap;
+        }
+        Ok((pos, wrapped))
+    }
+
+    fn find_next(&self, buffer: &Buffer, (line, col): (usize, usize), forward: bool) -> Option<((usize, usize), bool)> {
+        let len = buffer.len();
+        // the cursor's line comes first and, for the part behind the cursor, last
+        for step in 0..=len {
+            let at = if forward { (line + step) % len } else { (line + len - step % len) % len };
+            let Ok(text) = buffer.get_line(at) else { continue };
+            let starts = self.starts(&text);
+            let found = match (forward, step) {
+                (true, 0) => starts.iter().find(|&&c| c > col),
+                (false, 0) => starts.iter().rev().find(|&&c| c < col),
+                (true, _) if step == len => starts.iter().find(|&&c| c <= col),
+                (false, _) if step == len => starts.iter().rev().find(|&&c| c >= col),
+                (true, _) => starts.first(),
+                (false, _) => starts.last(),
+            };
+            if let Some(&found) = found {
+                let wrapped = step == len || if forward { line + step >= len } else { step > line };
+                return Some(((at, found), wrapped));
+            }
+        }
+        None
+    }
+}
+
+// `\<` and `\>` become the regex crate's `\b{start}` and `\b{end}`
+fn translate(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('<') => out.push_str("\\b{start}"),
+            Some('>') => out.push_str("\\b{end}"),
+            Some(next) => {
+                out.push('\\');
+                out.push(next);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// a letter right after a backslash, as in `\S`, is part of an escape
+fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+/// The word `*` searches for: the one under the cursor, or the next one on
+/// the line, with the column it starts at.
+pub fn word_at(line: &str, col: usize) -> Option<(usize, String)> {
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    let is_word = |i: usize| motion::classify(graphemes[i], false) == Class::Word;
+    let mut start = (col..graphemes.len()).find(|&i| is_word(i))?;
+    if start == col {
+        while start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+    }
+    let end = (start..graphemes.len()).find(|&i| !is_word(i)).unwrap_or(graphemes.len());
+    Some((start, graphemes[start..end].concat()))
+}
+
+/// Patterns searched for, oldest first, without duplicates.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+}
+
+const HISTORY_SIZE: usize = 50;
+
+impl History {
+    pub fn add(&mut self, entry: &str) {
+        if entry.is_empty() {
+            return;
+        }
+        self.entries.retain(|old| old != entry);
+        self.entries.push(entry.to_string());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.remove(0);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The newest entry before `index` that starts with `prefix`, as Up
+    /// finds in vim.
+    pub fn older(&self, index: usize, prefix: &str) -> Option<usize> {
+        (0..index.min(self.entries.len())).rev().find(|&i| self.entries[i].starts_with(prefix))
+    }
+
+    /// The oldest entry after `index` that starts with `prefix`.
+    pub fn newer(&self, index: usize, prefix: &str) -> Option<usize> {
+        (index + 1..self.entries.len()).find(|&i| self.entries[i].starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        let mut buffer = Buffer::from_file(None, None).unwrap();
+        buffer.insert_text(0, 0, text).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_find_wraps_and_counts() {
+        let buffer = buffer("foo bar\nbaz foo\nfoo");
+        let pattern = Pattern::new("foo").unwrap();
+        assert_eq!(pattern.find(&buffer, (0, 0), true, None), Ok(((1, 4), false)));
+        assert_eq!(pattern.find(&buffer, (1, 4), true, Some(2)), Ok(((0, 0), true)));
+        assert_eq!(pattern.find(&buffer, (0, 0), false, None), Ok(((2, 0), true)));
+        assert_eq!(pattern.find(&buffer, (1, 5), false, None), Ok(((1, 4), false)));
+        // the only match is found again from itself
+        let pattern = Pattern::new("bar").unwrap();
+        assert_eq!(pattern.find(&buffer, (0, 4), true, None), Ok(((0, 4), true)));
+        assert_eq!(
+            Pattern::new("nope").unwrap().find(&buffer, (0, 0), true, None),
+            Err(SearchError::NotFound("nope".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_smart_case_word_boundaries_and_graphemes() {
+        let line = "Café cafés CAFÉ";
+        assert_eq!(Pattern::new("café").unwrap().matches(line), [(0, 4), (5, 9), (11, 15)]);
+        assert_eq!(Pattern::new("Café").unwrap().matches(line), [(0, 4)]);
+        assert_eq!(Pattern::new("\\<caf\\w\\>").unwrap().matches(line), [(0, 4), (11, 15)]);
+        // `\S` is not an uppercase letter
+        assert_eq!(Pattern::new("\\Sé").unwrap().matches("fÉ"), [(0, 2)]);
+        assert_eq!(Pattern::word("a.b").text, "\\<a\\.b\\>");
+        assert_eq!(Pattern::new("(").unwrap_err(), SearchError::Invalid("(".to_string()));
+        assert_eq!(word_at("  foo_1 bar", 0), Some((2, "foo_1".to_string())));
+        assert_eq!(word_at("  foo_1 bar", 4), Some((2, "foo_1".to_string())));
+        assert_eq!(word_at("foo ..", 3), None);
+    }
+
+    #[test]
+    fn test_history_by_prefix() {
+        let mut history = History::default();
+        for entry in ["foo", "bar", "fob", "foo"] {
+            history.add(entry);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.older(3, "fo"), Some(2));
+        assert_eq!(history.get(2), Some("foo"));
+        assert_eq!(history.older(2, "fo"), Some(1));
+        assert_eq!(history.older(1, "fo"), None);
+        assert_eq!(history.newer(1, ""), Some(2));
+    }
+}
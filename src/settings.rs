@@ -0,0 +1,177 @@
+use thiserror::Error;
+
+use crate::buffer::LineEnding;
+use crate::encoding::Encoding;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SettingsError {
+    #[error("Unknown option: {0}")]
+    Unknown(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+/// Options changed with `:set`, `:h options`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// Buffers with unsaved changes may be left for another without `!`;
+    /// they stay in the buffer list until written or deleted.
+    pub hidden: bool,
+    /// Lines longer than the window continue on the rows below, broken
+    /// between words, instead of scrolling sideways.
+    pub wrap: bool,
+    /// Shown at the start of the rows a wrapped line continues on.
+    pub showbreak: String,
+    /// The current buffer's line ending, `unix` or `dos`. The editor copies
+    /// it in before `:set` and applies it to the buffer after.
+    pub fileformat: String,
+    /// The current buffer's encoding, such as `utf-8` or `latin1`, kept in
+    /// step with the buffer like `fileformat`.
+    pub fileencoding: String,
+}
+
+// Full name, short name, as in `:h 'hidden'`.
+const BOOLEANS: &[(&str, &str)] = &[("hidden", "hid"), ("wrap", "wrap")];
+const STRINGS: &[(&str, &str)] = &[("showbreak", "sbr"), ("fileformat", "ff"), ("fileencoding", "fenc")];
+
+impl Settings {
+    fn boolean(&mut self, name: &str) -> Option<&mut bool> {
+        let &(full, _) = BOOLEANS.iter().find(|(full, short)| name == *full || name == *short)?;
+        match full {
+            "hidden" => Some(&mut self.hidden),
+            "wrap" => Some(&mut self.wrap),
+            _ => None,
+        }
+    }
+
+    fn string(&mut self, name: &str) -> Option<&mut String> {
+        let &(full, _) = STRINGS.iter().find(|(full, short)| name == *full || name == *short)?;
+        match full {
+            "showbreak" => Some(&mut self.showbreak),
+            "fileformat" => Some(&mut self.fileformat),
+            "fileencoding" => Some(&mut self.fileencoding),
+            _ => None,
+        }
+    }
+
+    // whether string option `name` can be set to `value`
+    fn valid(name: &str, value: &str) -> bool {
+        match STRINGS.iter().find(|(full, short)| name == *full || name == *short) {
+            Some(("fileformat", _)) => LineEnding::from_name(value).is_some(),
+            Some(("fileencoding", _)) => Encoding::from_name(value).is_some(),
+            _ => true,
+        }
+    }
+
+    // `name` as `:set name?` shows it, like `nohidden` or `showbreak=>`
+    fn describe(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.string(name) {
+            return Some(format!("{}={}", name, value));
+        }
+        let value = *self.boolean(name)?;
+        Some(format!("{}{}", if value { "" } else { "no" }, name))
+    }
+
+    /// Applies the argument of `:set`: `name` turns an option on, `noname`
+    /// off, and `invname` or `name!` flips it. `name=value` sets a string
+    /// option, with `\ ` for a space in it. `name?`, or just `name` for a
+    /// string option, changes nothing. Returns what `:set` shows afterwards,
+    /// which is empty unless something was asked.
+    pub fn set(&mut self, arg: &str) -> Result<String, SettingsError> {
+        let mut shown = Vec::new();
+        for item in items(arg) {
+            let item = item.as_str();
+            let fail = |settings: &mut Self, name: &str| match settings.describe(name) {
+                Some(_) => SettingsError::InvalidArgument(item.to_string()),
+                None => SettingsError::Unknown(item.to_string()),
+            };
+            if let Some((name, value)) = item.split_once('=') {
+                let Some(option) = self.string(name) else { return Err(fail(self, name)) };
+                if !Self::valid(name, value) {
+                    return Err(SettingsError::InvalidArgument(item.to_string()));
+                }
+                *option = value.to_string();
+            } else if let Some(name) = item.strip_suffix('?') {
+                shown.push(self.describe(name).ok_or_else(|| SettingsError::Unknown(item.to_string()))?);
+            } else if self.string(item).is_some() {
+                shown.extend(self.describe(item));
+            } else if let Some(name) = item.strip_prefix("inv").or_else(|| item.strip_suffix('!')) {
+                let Some(value) = self.boolean(name) else { return Err(fail(self, name)) };
+                *value = !*value;
+            } else if let Some(value) = self.boolean(item) {
+                *value = true;
+            } else if let Some(name) = item.strip_prefix("no") {
+                let Some(value) = self.boolean(name) else { return Err(fail(self, name)) };
+                *value = false;
+            } else {
+                return Err(SettingsError::Unknown(item.to_string()));
+            }
+        }
+        Ok(shown.join(" "))
+    }
+
+    /// What a bare `:set` shows: every option, as it would be set.
+    pub fn show(&mut self) -> String {
+        let names = BOOLEANS.iter().chain(STRINGS).map(|(full, _)| full);
+        let shown: Vec<String> = names.filter_map(|full| self.describe(full)).collect();
+        shown.join(" ")
+    }
+}
+
+// the items of a `:set` argument, split at blanks not escaped with `\`
+fn items(arg: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => item.extend(chars.next()),
+            c if c.is_whitespace() => {
+                if !item.is_empty() {
+                    items.push(std::mem::take(&mut item));
+                }
+            }
+            c => item.push(c),
+        }
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_booleans() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.set("hid"), Ok(String::new()));
+        assert!(settings.hidden);
+        assert_eq!(settings.set("invhidden hidden?"), Ok("nohidden".to_string()));
+        assert_eq!(settings.set("hidden! nohid? "), Err(SettingsError::Unknown("nohid?".to_string())));
+        assert!(settings.hidden);
+        assert_eq!(settings.set("nohid"), Ok(String::new()));
+        assert_eq!(settings.show(), "nohidden nowrap showbreak= fileformat= fileencoding=");
+        assert_eq!(settings.set("wrapscan"), Err(SettingsError::Unknown("wrapscan".to_string())));
+    }
+
+    #[test]
+    fn test_set_strings() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.set("wrap sbr=>\\\\\\ "), Ok(String::new()));
+        assert!(settings.wrap);
+        assert_eq!(settings.showbreak, ">\\ ");
+        assert_eq!(settings.set("showbreak"), Ok("showbreak=>\\ ".to_string()));
+        assert_eq!(settings.set("sbr=+++ sbr?"), Ok("sbr=+++".to_string()));
+        assert_eq!(settings.set("noshowbreak"), Err(SettingsError::InvalidArgument("noshowbreak".to_string())));
+        assert_eq!(settings.set("wrap=1"), Err(SettingsError::InvalidArgument("wrap=1".to_string())));
+        assert_eq!(settings.set("nosuch=1"), Err(SettingsError::Unknown("nosuch=1".to_string())));
+        assert_eq!(settings.set("ff=dos ff?"), Ok("ff=dos".to_string()));
+        assert_eq!(settings.set("ff=mac"), Err(SettingsError::InvalidArgument("ff=mac".to_string())));
+        assert_eq!(settings.fileformat, "dos");
+        assert_eq!(settings.set("fenc=latin1"), Ok(String::new()));
+        assert_eq!(settings.set("fenc=ebcdic"), Err(SettingsError::InvalidArgument("fenc=ebcdic".to_string())));
+    }
+}
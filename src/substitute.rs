@@ -0,0 +1,199 @@
+use regex::Captures;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SubstituteError {
+    #[error("Regular expression missing from :substitute")]
+    MissingPattern,
+    #[error("Invalid delimiter: {0}")]
+    InvalidDelimiter(char),
+    #[error("Trailing characters: {0}")]
+    TrailingCharacters(String),
+}
+
+/// `[gcinI]` after the replacement, `:h s_flags`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// `g`: every match in the line, not just the first.
+    pub global: bool,
+    /// `c`: asks before each replacement.
+    pub confirm: bool,
+    /// `i` ignores case and `I` does not, whatever the pattern holds.
+    pub ignore_case: Option<bool>,
+    /// `n`: only counts the matches.
+    pub count_only: bool,
+}
+
+/// The argument of `:s`, as in `/pattern/replacement/flags`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Substitution {
+    /// Empty for the last search pattern.
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: Flags,
+}
+
+/// An answer to the `c` flag's question about one match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Confirm {
+    Yes,
+    No,
+    /// Replaces this match and all the rest without asking.
+    All,
+    Quit,
+    /// Replaces this match and stops.
+    Last,
+}
+
+impl Substitution {
+    pub fn parse(arg: &str) -> Result<Self, SubstituteError> {
+        let mut chars = arg.chars();
+        let delimiter = chars.next().ok_or(SubstituteError::MissingPattern)?;
+        if delimiter.is_alphanumeric() || matches!(delimiter, '\\' | '"' | '|') || delimiter.is_whitespace() {
+            return Err(SubstituteError::InvalidDelimiter(delimiter));
+        }
+        let rest = chars.as_str();
+        let (pattern, rest) = split_at_delimiter(rest, delimiter);
+        let (replacement, flags) = match rest {
+            Some(rest) => split_at_delimiter(rest, delimiter),
+            None => (String::new(), None),
+        };
+        let mut parsed = Flags::default();
+        for flag in flags.unwrap_or_default().trim_end().chars() {
+            match flag {
+                'g' => parsed.global = true,
+                'c' => parsed.confirm = true,
+                'i' => parsed.ignore_case = Some(true),
+                'I' => parsed.ignore_case = Some(false),
+                'n' => parsed.count_only = true,
+                _ => return Err(SubstituteError::TrailingCharacters(flags.unwrap_or_default().to_string())),
+            }
+        }
+        Ok(Substitution { pattern, replacement, flags: parsed })
+    }
+}
+
+/// Everything up to the first `delimiter` not escaped with a backslash, with
+/// the backslash dropped from escaped delimiters, and what follows it. `:g`
+/// splits its pattern off the same way.
+pub fn split_at_delimiter(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (part, Some(&text[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => part.push(next),
+                Some((_, next)) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            }
+        } else {
+            part.push(c);
+        }
+    }
+    (part, None)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+fn push_cased(out: &mut String, text: &str, one: &mut Option<Case>, all: Option<Case>) {
+    for c in text.chars() {
+        match one.take().or(all) {
+            Some(Case::Upper) => out.extend(c.to_uppercase()),
+            Some(Case::Lower) => out.extend(c.to_lowercase()),
+            None => out.push(c),
+        }
+    }
+}
+
+/// The text `replacement` stands for at one match: `&` and `\0` are the
+/// whole match, `\1` to `\9` its groups, `\r` and `\n` a line break, and
+/// `\u`, `\l`, `\U`, `\L` and `\e` change the case of what follows.
+pub fn expand(replacement: &str, captures: &Captures) -> String {
+    let mut out = String::new();
+    // `\u` and `\l` change one character, `\U` and `\L` everything up to `\e`
+    let mut one = None;
+    let mut all = None;
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        let text = match c {
+            '&' => captures.get(0).map_or("", |m| m.as_str()),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => {
+                    let group = digit.to_digit(10).unwrap_or(0) as usize;
+                    captures.get(group).map_or("", |m| m.as_str())
+                }
+                Some('r' | 'n') => "\n",
+                Some('t') => "\t",
+                Some('u') => {
+                    one = Some(Case::Upper);
+                    continue;
+                }
+                Some('l') => {
+                    one = Some(Case::Lower);
+                    continue;
+                }
+                Some('U') => {
+                    all = Some(Case::Upper);
+                    continue;
+                }
+                Some('L') => {
+                    all = Some(Case::Lower);
+                    continue;
+                }
+                Some('e' | 'E') => {
+                    all = None;
+                    continue;
+                }
+                Some(other) => {
+                    push_cased(&mut out, other.encode_utf8(&mut [0; 4]), &mut one, all);
+                    continue;
+                }
+                None => "\\",
+            },
+            _ => {
+                push_cased(&mut out, c.encode_utf8(&mut [0; 4]), &mut one, all);
+                continue;
+            }
+        };
+        push_cased(&mut out, text, &mut one, all);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn test_parse_delimiters_and_flags() {
+        let sub = Substitution::parse("/a\\/b/c/gi").unwrap();
+        assert_eq!((sub.pattern.as_str(), sub.replacement.as_str()), ("a/b", "c"));
+        assert_eq!(sub.flags, Flags { global: true, ignore_case: Some(true), ..Flags::default() });
+        let sub = Substitution::parse("#x\\d#y").unwrap();
+        assert_eq!((sub.pattern.as_str(), sub.replacement.as_str()), ("x\\d", "y"));
+        assert_eq!(Substitution::parse("/gone").unwrap().replacement, "");
+        assert_eq!(Substitution::parse("/a/b/gx"), Err(SubstituteError::TrailingCharacters("gx".to_string())));
+        assert_eq!(Substitution::parse("xaxbx"), Err(SubstituteError::InvalidDelimiter('x')));
+    }
+
+    #[test]
+    fn test_expand_groups_and_case() {
+        let regex = Regex::new("(\\w+) (\\w+)").unwrap();
+        let captures = regex.captures("hello wide world").unwrap();
+        assert_eq!(expand("\\2 \\1", &captures), "wide hello");
+        assert_eq!(expand("[&]\\&", &captures), "[hello wide]&");
+        assert_eq!(expand("\\u\\1 \\U\\2\\e!", &captures), "Hello WIDE!");
+        assert_eq!(expand("\\L\\uABC\\r", &captures), "Abc\n");
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+// A lock file next to each swap file holds the pid of the vix editing that
+// file. A lock whose process is gone is stale, and the swap file beside it
+// was left behind by a crash.
+
+/// The pid of another live vix holding the lock at `path`, if any.
+pub fn holder(path: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    (pid != std::process::id() && pid_alive(pid)).then_some(pid)
+}
+
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Takes the lock at `path`, replacing a stale one. Check `holder` first.
+    pub fn acquire(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, std::process::id().to_string())?;
+        debug!("Acquired lock {:?}", path);
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => info!("Released lock {:?}", self.path),
+            Err(e) => warn!("Could not remove lock {:?}: {}", self.path, e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks that the process exists; EPERM means it does but is not ours
+    unsafe { libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(not(unix))]
+fn pid_alive(_pid: u32) -> bool {
+    // no cheap check; assume the other editor is still running
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_lock_holder_must_be_alive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.lock");
+        assert_eq!(holder(&path), None);
+
+        let lock = Lock::acquire(&path).unwrap();
+        // our own lock does not count
+        assert_eq!(holder(&path), None);
+        drop(lock);
+        assert!(!path.exists());
+
+        let mut sleeper = Command::new("sleep").arg("10").spawn().unwrap();
+        fs::write(&path, sleeper.id().to_string()).unwrap();
+        assert_eq!(holder(&path), Some(sleeper.id()));
+        sleeper.kill().unwrap();
+        sleeper.wait().unwrap();
+        assert_eq!(holder(&path), None);
+    }
+}
@@ -0,0 +1,172 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Cursor columns are counted in grapheme clusters, so "é" written as
+// `e` + U+0301 or a ZWJ emoji sequence is a single column to step over.
+
+pub fn grapheme_count(line: &str) -> usize {
+    line.graphemes(true).count()
+}
+
+/// Byte offset of grapheme column `col`, or `None` when the line is shorter.
+pub fn byte_offset(line: &str, col: usize) -> Option<usize> {
+    if col == 0 {
+        return Some(0);
+    }
+    match line.grapheme_indices(true).nth(col) {
+        Some((idx, _)) => Some(idx),
+        None if grapheme_count(line) == col => Some(line.len()),
+        None => None,
+    }
+}
+
+/// Grapheme column of byte offset `byte`, rounding up when it falls inside a cluster.
+pub fn grapheme_col(line: &str, byte: usize) -> usize {
+    line.grapheme_indices(true).take_while(|(idx, _)| *idx < byte).count()
+}
+
+/// Columns between tab stops.
+pub const TAB_STOP: usize = 8;
+
+/// Terminal cells taken by one grapheme starting at screen column `at` of
+/// its line: 2 for East Asian wide characters, emoji and control characters
+/// shown as `^M`, up to the next tab stop for a tab, 0 for lone zero-width
+/// characters.
+pub fn grapheme_width(grapheme: &str, at: usize) -> usize {
+    if grapheme == "\t" {
+        return TAB_STOP - at % TAB_STOP;
+    }
+    if caret(grapheme).is_some() {
+        return 2;
+    }
+    grapheme.width()
+}
+
+/// The character shown after `^` for an ASCII control character other than
+/// tab, as vim shows a stray carriage return as `^M`.
+pub fn caret(grapheme: &str) -> Option<char> {
+    let mut chars = grapheme.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '\t' && (c < ' ' || c == '\x7f') => Some((c as u8 ^ 0x40) as char),
+        _ => None,
+    }
+}
+
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).fold(0, |at, grapheme| at + grapheme_width(grapheme, at))
+}
+
+/// Screen column of grapheme column `col`.
+pub fn display_col(line: &str, col: usize) -> usize {
+    line.graphemes(true).take(col).fold(0, |at, grapheme| at + grapheme_width(grapheme, at))
+}
+
+/// Grapheme column under screen column `display`, or the line's length when
+/// it is shorter.
+pub fn col_at_display(line: &str, display: usize) -> usize {
+    let mut used = 0;
+    for (col, grapheme) in line.graphemes(true).enumerate() {
+        used += grapheme_width(grapheme, used);
+        if used > display {
+            return col;
+        }
+    }
+    grapheme_count(line)
+}
+
+/// The longest prefix of `line` that fits in `width` terminal cells, never
+/// splitting a wide character.
+pub fn truncate_to_width(line: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (idx, grapheme) in line.grapheme_indices(true) {
+        used += grapheme_width(grapheme, used);
+        if used > width {
+            return &line[..idx];
+        }
+    }
+    line
+}
+
+/// Splits `line` into the grapheme column ranges it takes on screen rows
+/// `first` cells wide, then `rest` cells for the rows after. A row breaks
+/// after its last blank when it can, so words are kept whole.
+pub fn wrap(line: &str, first: usize, rest: usize) -> Vec<(usize, usize)> {
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    let mut rows = Vec::new();
+    // tabs reach the tab stops of the whole line, wherever its rows break
+    let (mut start, mut limit, mut at) = (0, first, 0);
+    while start < graphemes.len() {
+        let (mut end, mut used, mut blank) = (start, 0, None);
+        while end < graphemes.len() && used + grapheme_width(graphemes[end], at + used) <= limit {
+            used += grapheme_width(graphemes[end], at + used);
+            if graphemes[end].chars().all(char::is_whitespace) {
+                blank = Some(end + 1);
+            }
+            end += 1;
+        }
+        if end < graphemes.len() && let Some(blank) = blank {
+            end = blank;
+        }
+        // a character wider than the row still takes one
+        let end = end.max(start + 1);
+        rows.push((start, end));
+        at = graphemes[start..end].iter().fold(at, |at, grapheme| at + grapheme_width(grapheme, at));
+        (start, limit) = (end, rest);
+    }
+    if rows.is_empty() {
+        rows.push((0, 0));
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_columns_step_over_clusters() {
+        let line = "ae\u{301}👨\u{200d}👩\u{200d}👧b";
+        assert_eq!(grapheme_count(line), 4);
+        assert_eq!(byte_offset(line, 1), Some(1));
+        assert_eq!(byte_offset(line, 2), Some(4));
+        assert_eq!(byte_offset(line, 4), Some(line.len()));
+        assert_eq!(byte_offset(line, 5), None);
+        assert_eq!(grapheme_col(line, 4), 2);
+        // inside the family emoji rounds up past it
+        assert_eq!(grapheme_col(line, 8), 3);
+    }
+
+    #[test]
+    fn test_display_width_of_wide_text() {
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("🇫🇷 ok"), 5);
+        assert_eq!(display_col("中a文", 2), 3);
+        assert_eq!(col_at_display("中a文", 1), 0);
+        assert_eq!(col_at_display("中a文", 3), 2);
+        assert_eq!(col_at_display("中a文", 9), 3);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("a\r\t"), 8);
+        assert_eq!(display_col("\tx\t", 2), 9);
+        assert_eq!(display_width("ab\t中\t"), 16);
+        assert_eq!(col_at_display("\tx", 7), 0);
+        assert_eq!(col_at_display("\tx", 8), 1);
+        assert_eq!(truncate_to_width("a\tb", 8), "a\t");
+        assert_eq!(caret("\r"), Some('M'));
+        assert_eq!(caret("\x7f"), Some('?'));
+        assert_eq!(truncate_to_width("中文字", 5), "中文");
+        assert_eq!(truncate_to_width("abc", 5), "abc");
+    }
+
+    #[test]
+    fn test_wrap_at_blanks() {
+        assert_eq!(wrap("", 5, 5), [(0, 0)]);
+        assert_eq!(wrap("one two three", 6, 6), [(0, 4), (4, 8), (8, 13)]);
+        assert_eq!(wrap("one two three", 8, 4), [(0, 8), (8, 12), (12, 13)]);
+        // no blank to break at, or a word longer than the row
+        assert_eq!(wrap("abcdefgh", 3, 3), [(0, 3), (3, 6), (6, 8)]);
+        assert_eq!(wrap("a 中文", 3, 3), [(0, 2), (2, 3), (3, 4)]);
+        assert_eq!(wrap("中", 1, 1), [(0, 1)]);
+        // a tab reaches the next tab stop of the line, not of the row
+        assert_eq!(wrap("abcdefghi x\ty", 10, 6), [(0, 10), (10, 12), (12, 13)]);
+    }
+}
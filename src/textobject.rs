@@ -0,0 +1,498 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::motion::{self, Class};
+use crate::operator::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    Word { big: bool },
+    Sentence,
+    Paragraph,
+    /// Text between two of the same quote on one line.
+    Quote(char),
+    Bracket { open: char, close: char },
+    /// An XML or HTML element, from `<name ...>` to `</name>`.
+    Tag,
+}
+
+/// A text object such as `iw` or `a(`. The inner form leaves out the blanks,
+/// quotes, brackets or tags around the text that the `a` form takes in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextObject {
+    pub kind: ObjectKind,
+    pub inner: bool,
+}
+
+impl TextObject {
+    /// The text the object covers around `cursor`. A count widens it: more
+    /// words, sentences or paragraphs, or brackets and tags further out.
+    pub fn range(&self, buffer: &Buffer, cursor: (usize, usize), count: Option<usize>) -> Option<Range> {
+        let count = count.unwrap_or(1).max(1);
+        match self.kind {
+            ObjectKind::Word { big } => word(buffer, cursor, big, self.inner, count),
+            ObjectKind::Sentence => sentence(buffer, cursor, self.inner, count),
+            ObjectKind::Paragraph => paragraph(buffer, cursor.0, self.inner, count),
+            ObjectKind::Quote(quote) => quoted(buffer, cursor, quote, self.inner),
+            ObjectKind::Bracket { open, close } => bracketed(buffer, cursor, open, close, self.inner, count),
+            ObjectKind::Tag => tagged(buffer, cursor, self.inner, count),
+        }
+    }
+}
+
+// Words, sentences and paragraphs are all runs of text separated by runs of
+// blanks, and pick their range the same way.
+struct Run {
+    start: usize,
+    end: usize,
+    blank: bool,
+}
+
+fn runs<T: PartialEq>(items: &[T], is_blank: impl Fn(&T) -> bool) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if items[run.start] == *item => run.end = i + 1,
+            _ => runs.push(Run { start: i, end: i + 1, blank: is_blank(item) }),
+        }
+    }
+    runs
+}
+
+/// `count` runs from the one at `at`. The `a` form also takes the blanks
+/// after each, or the blanks before when there are none after.
+fn select(runs: &[Run], at: usize, inner: bool, count: usize) -> (usize, usize) {
+    let mut next = at;
+    let mut end = runs[at].start;
+    for _ in 0..count {
+        let Some(run) = runs.get(next) else { break };
+        end = run.end;
+        next += 1;
+        if !inner && let Some(after) = runs.get(next) && after.blank != run.blank {
+            end = after.end;
+            next += 1;
+        }
+    }
+    let mut start = runs[at].start;
+    if !inner && !runs[at].blank && !runs[next - 1].blank && at > 0 && runs[at - 1].blank {
+        start = runs[at - 1].start;
+    }
+    (start, end)
+}
+
+fn run_at(runs: &[Run], i: usize) -> usize {
+    runs.iter().position(|run| i < run.end).unwrap_or(runs.len() - 1)
+}
+
+fn word(buffer: &Buffer, (line, col): (usize, usize), big: bool, inner: bool, count: usize) -> Option<Range> {
+    let text = buffer.get_line(line).ok()?;
+    let classes: Vec<Class> = text.graphemes(true).map(|g| motion::classify(g, big)).collect();
+    if classes.is_empty() {
+        return None;
+    }
+    let runs = runs(&classes, |class| *class == Class::Blank);
+    let (start, end) = select(&runs, run_at(&runs, col), inner, count);
+    Some(Range { start: (line, start), end: (line, end), linewise: false })
+}
+
+fn is_blank_line(buffer: &Buffer, line: usize) -> bool {
+    buffer.get_line(line).is_ok_and(|text| text.trim().is_empty())
+}
+
+// The run of blank or non-blank lines that `line` is in.
+fn line_run(buffer: &Buffer, line: usize) -> Run {
+    let blank = is_blank_line(buffer, line);
+    let start = (0..line).rev().find(|&l| is_blank_line(buffer, l) != blank).map_or(0, |l| l + 1);
+    let end = (line..buffer.len()).find(|&l| is_blank_line(buffer, l) != blank).unwrap_or(buffer.len());
+    Run { start, end, blank }
+}
+
+fn paragraph(buffer: &Buffer, line: usize, inner: bool, count: usize) -> Option<Range> {
+    let here = line_run(buffer, line);
+    let mut runs = Vec::new();
+    if here.start > 0 {
+        runs.push(line_run(buffer, here.start - 1));
+    }
+    let at = runs.len();
+    let mut end = here.end;
+    runs.push(here);
+    // `count` paragraphs and the blanks after each are at most `2 * count` runs
+    while runs.len() < at + 2 * count && end < buffer.len() {
+        let run = line_run(buffer, end);
+        end = run.end;
+        runs.push(run);
+    }
+    let (first, end) = select(&runs, at, inner, count);
+    Some(Range::lines(first, end - 1))
+}
+
+// A paragraph as one run of characters with a '\n' ending every line, for
+// sentences that span lines.
+struct Flat {
+    chars: Vec<char>,
+    positions: Vec<(usize, usize)>,
+}
+
+impl Flat {
+    fn new(buffer: &Buffer, lines: std::ops::Range<usize>) -> Self {
+        let mut flat = Flat { chars: Vec::new(), positions: Vec::new() };
+        for line in lines {
+            let text = buffer.get_line(line).unwrap_or_default();
+            let mut len = 0;
+            for (col, grapheme) in text.graphemes(true).enumerate() {
+                flat.chars.push(grapheme.chars().next().unwrap_or(' '));
+                flat.positions.push((line, col));
+                len = col + 1;
+            }
+            flat.chars.push('\n');
+            flat.positions.push((line, len));
+        }
+        flat
+    }
+
+    fn index(&self, pos: (usize, usize)) -> usize {
+        self.positions.binary_search(&pos).unwrap_or_else(|i| i).min(self.chars.len().saturating_sub(1))
+    }
+
+    /// Position of character `i`; one past the end is the end of the last line.
+    fn pos(&self, i: usize) -> (usize, usize) {
+        self.positions.get(i).or(self.positions.last()).copied().unwrap_or((0, 0))
+    }
+
+    fn range(&self, start: usize, end: usize) -> Range {
+        Range { start: self.pos(start), end: self.pos(end), linewise: false }
+    }
+}
+
+// A sentence ends at '.', '!' or '?', maybe followed by closing quotes or
+// brackets, then white space. Paragraph breaks end sentences too.
+fn sentence(buffer: &Buffer, cursor: (usize, usize), inner: bool, count: usize) -> Option<Range> {
+    if is_blank_line(buffer, cursor.0) {
+        return paragraph(buffer, cursor.0, inner, count);
+    }
+    let first = (0..cursor.0).rev().find(|&l| is_blank_line(buffer, l)).map_or(0, |l| l + 1);
+    let end = (cursor.0..buffer.len()).find(|&l| is_blank_line(buffer, l)).unwrap_or(buffer.len());
+    let mut flat = Flat::new(buffer, first..end);
+    // the paragraph's last line break is not space after its last sentence
+    flat.chars.pop();
+
+    let chars = &flat.chars;
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        if chars[i].is_whitespace() {
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            units.push(Run { start, end: i, blank: true });
+            continue;
+        }
+        while i < chars.len() {
+            let ends = matches!(chars[i], '.' | '!' | '?');
+            i += 1;
+            if ends {
+                while i < chars.len() && matches!(chars[i], ')' | ']' | '"' | '\'') {
+                    i += 1;
+                }
+                if i == chars.len() || chars[i].is_whitespace() {
+                    break;
+                }
+            }
+        }
+        units.push(Run { start, end: i, blank: false });
+    }
+    let (start, end) = select(&units, run_at(&units, flat.index(cursor)), inner, count);
+    Some(flat.range(start, end))
+}
+
+fn quoted(buffer: &Buffer, (line, col): (usize, usize), quote: char, inner: bool) -> Option<Range> {
+    let text = buffer.get_line(line).ok()?;
+    let chars: Vec<char> = text.graphemes(true).map(|g| g.chars().next().unwrap_or(' ')).collect();
+    let quotes: Vec<usize> = (0..chars.len())
+        .filter(|&i| chars[i] == quote && (i == 0 || chars[i - 1] != '\\'))
+        .collect();
+    // quotes pair up from the start of the line; from outside a pair, take the next one
+    let (open, close) = quotes.chunks_exact(2).map(|pair| (pair[0], pair[1])).find(|&(_, close)| col <= close)?;
+    if inner {
+        return Some(Range { start: (line, open + 1), end: (line, close), linewise: false });
+    }
+    let blank = |c: char| c == ' ' || c == '\t';
+    let mut end = close + 1;
+    while end < chars.len() && blank(chars[end]) {
+        end += 1;
+    }
+    let mut start = open;
+    if end == close + 1 {
+        while start > 0 && blank(chars[start - 1]) {
+            start -= 1;
+        }
+    }
+    Some(Range { start: (line, start), end: (line, end), linewise: false })
+}
+
+// The first character of each grapheme on `line`, then a '\n'.
+fn line_chars(buffer: &Buffer, line: usize) -> Vec<char> {
+    let text = buffer.get_line(line).unwrap_or_default();
+    text.graphemes(true).map(|g| g.chars().next().unwrap_or(' ')).chain(['\n']).collect()
+}
+
+// Characters from `pos` to the end of the buffer, read a line at a time.
+fn chars_from(buffer: &Buffer, (line, col): (usize, usize)) -> impl Iterator<Item = ((usize, usize), char)> + '_ {
+    (line..buffer.len()).flat_map(move |l| {
+        let skip = if l == line { col } else { 0 };
+        line_chars(buffer, l).into_iter().enumerate().skip(skip).map(move |(c, ch)| ((l, c), ch))
+    })
+}
+
+// Characters before `pos` back to the start of the buffer, nearest first.
+fn chars_before(buffer: &Buffer, (line, col): (usize, usize)) -> impl Iterator<Item = ((usize, usize), char)> + '_ {
+    (0..=line.min(buffer.len().saturating_sub(1))).rev().flat_map(move |l| {
+        let chars = line_chars(buffer, l);
+        let take = if l == line { col.min(chars.len()) } else { chars.len() };
+        chars.into_iter().enumerate().take(take).rev().map(move |(c, ch)| ((l, c), ch))
+    })
+}
+
+fn bracketed(buffer: &Buffer, cursor: (usize, usize), open: char, close: char, inner: bool, count: usize) -> Option<Range> {
+    // a cursor on the closing bracket belongs to the pair it closes
+    let on_close = line_chars(buffer, cursor.0).get(cursor.1) == Some(&close);
+    let mut before = chars_before(buffer, if on_close { cursor } else { (cursor.0, cursor.1 + 1) });
+    let mut start = None;
+    for _ in 0..count {
+        start = Some(enclosing_open(&mut before, open, close)?);
+    }
+    let (open_line, open_col) = start?;
+    let mut depth = 0usize;
+    let (close_line, close_col) = chars_from(buffer, (open_line, open_col + 1)).find(|&(_, c)| {
+        match c {
+            c if c == close && depth == 0 => return true,
+            c if c == close => depth -= 1,
+            c if c == open => depth += 1,
+            _ => {}
+        }
+        false
+    })?.0;
+
+    if !inner {
+        return Some(Range { start: (open_line, open_col), end: (close_line, close_col + 1), linewise: false });
+    }
+    // `i{` around a block of lines takes just the lines between the brackets
+    let close_indented = buffer.get_line(close_line).ok()?
+        .graphemes(true).take(close_col).all(|g| g.trim().is_empty());
+    let open_ends_line = line_chars(buffer, open_line).get(open_col + 1) == Some(&'\n');
+    if open_ends_line && close_indented && close_line > open_line + 1 {
+        return Some(Range::lines(open_line + 1, close_line - 1));
+    }
+    Some(Range { start: (open_line, open_col + 1), end: (close_line, close_col), linewise: false })
+}
+
+fn enclosing_open(
+    chars: &mut impl Iterator<Item = ((usize, usize), char)>,
+    open: char,
+    close: char,
+) -> Option<(usize, usize)> {
+    let mut depth = 0usize;
+    for (pos, c) in chars {
+        match c {
+            c if c == open && depth == 0 => return Some(pos),
+            c if c == open => depth -= 1,
+            c if c == close => depth += 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// A tag from its `<` up to just past its `>`.
+struct Tag {
+    start: (usize, usize),
+    end: (usize, usize),
+    name: String,
+    closing: bool,
+}
+
+impl Tag {
+    fn new(start: (usize, usize), last: (usize, usize), body: &[char]) -> Option<Self> {
+        let closing = body.first() == Some(&'/');
+        let name: String = body.iter()
+            .skip(usize::from(closing))
+            .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+        // comments, declarations and self-closing tags have no contents
+        if name.is_empty() || body.last() == Some(&'/') {
+            return None;
+        }
+        Some(Tag { start, end: (last.0, last.1 + 1), name, closing })
+    }
+}
+
+fn tags_from(buffer: &Buffer, pos: (usize, usize)) -> impl Iterator<Item = Tag> + '_ {
+    let mut chars = chars_from(buffer, pos);
+    std::iter::from_fn(move || loop {
+        let (start, c) = chars.next()?;
+        if c != '<' {
+            continue;
+        }
+        let mut body = Vec::new();
+        let last = loop {
+            match chars.next()? {
+                (last, '>') => break last,
+                (_, c) => body.push(c),
+            }
+        };
+        if let Some(tag) = Tag::new(start, last, &body) {
+            return Some(tag);
+        }
+    })
+}
+
+fn tags_before(buffer: &Buffer, pos: (usize, usize)) -> impl Iterator<Item = Tag> + '_ {
+    let mut chars = chars_before(buffer, pos);
+    std::iter::from_fn(move || loop {
+        let (last, c) = chars.next()?;
+        if c != '>' {
+            continue;
+        }
+        let mut body = Vec::new();
+        let start = loop {
+            match chars.next()? {
+                (start, '<') => break start,
+                (_, c) => body.push(c),
+            }
+        };
+        body.reverse();
+        if let Some(tag) = Tag::new(start, last, &body) {
+            return Some(tag);
+        }
+    })
+}
+
+// The tag the cursor is on, if any.
+fn tag_at(buffer: &Buffer, cursor: (usize, usize)) -> Option<Tag> {
+    let (open, _) = chars_before(buffer, (cursor.0, cursor.1 + 1))
+        .find(|&(pos, c)| c == '<' || (c == '>' && pos != cursor))
+        .filter(|&(_, c)| c == '<')?;
+    tags_from(buffer, open).next().filter(|tag| tag.start == open && cursor < tag.end)
+}
+
+// Works outward from the cursor: each closing tag after it that closes
+// nothing opened after it ends an element around the cursor, which starts at
+// the nearest opening tag before the cursor that nothing there closes.
+fn tagged(buffer: &Buffer, cursor: (usize, usize), inner: bool, count: usize) -> Option<Range> {
+    // a cursor on a tag is inside the element it opens or closes
+    let split = match tag_at(buffer, cursor) {
+        Some(tag) if tag.closing => tag.start,
+        Some(tag) => tag.end,
+        None => cursor,
+    };
+    let (mut before, mut after) = (tags_before(buffer, split), tags_from(buffer, split));
+    let (mut opened, mut closed) = (Vec::new(), Vec::new());
+    let mut around = None;
+    for _ in 0..count {
+        let end = loop {
+            let tag = after.next()?;
+            if !tag.closing {
+                opened.push(tag.name);
+                continue;
+            }
+            match opened.iter().rposition(|name| *name == tag.name) {
+                // tags left open inside this element, like HTML's <br>, are dropped
+                Some(depth) => opened.truncate(depth),
+                None => break tag,
+            }
+        };
+        let start = loop {
+            let tag = before.next()?;
+            if tag.closing {
+                closed.push(tag.name);
+                continue;
+            }
+            match closed.iter().rposition(|name| *name == tag.name) {
+                Some(depth) => closed.truncate(depth),
+                None if tag.name == end.name => break tag,
+                None => {}
+            }
+        };
+        around = Some((start, end));
+    }
+    let (start, end) = around?;
+    Some(if inner {
+        Range { start: start.end, end: end.start, linewise: false }
+    } else {
+        Range { start: start.start, end: end.end, linewise: false }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(text: &str) -> Buffer {
+        let mut buffer = Buffer::from_file(None, None).unwrap();
+        buffer.insert_text(0, 0, text).unwrap();
+        buffer
+    }
+
+    fn text(buffer: &Buffer, kind: ObjectKind, inner: bool, cursor: (usize, usize), count: usize) -> Option<String> {
+        let range = TextObject { kind, inner }.range(buffer, cursor, Some(count))?;
+        Some(range.text(buffer).unwrap())
+    }
+
+    #[test]
+    fn test_words_sentences_and_paragraphs() {
+        let buffer = buffer_with("foo.bar baz  qux\n\nOne. Two!  Three\nfour.\n\n\nlast");
+        let word = ObjectKind::Word { big: false };
+        assert_eq!(text(&buffer, word, true, (0, 5), 1).as_deref(), Some("bar"));
+        assert_eq!(text(&buffer, word, false, (0, 5), 1).as_deref(), Some("bar "));
+        assert_eq!(text(&buffer, word, true, (0, 5), 3).as_deref(), Some("bar baz"));
+        // no blanks after the last word, so `aw` takes those before it
+        assert_eq!(text(&buffer, word, false, (0, 13), 1).as_deref(), Some("  qux"));
+        assert_eq!(text(&buffer, ObjectKind::Word { big: true }, true, (0, 1), 1).as_deref(), Some("foo.bar"));
+
+        assert_eq!(text(&buffer, ObjectKind::Sentence, true, (2, 6), 1).as_deref(), Some("Two!"));
+        assert_eq!(text(&buffer, ObjectKind::Sentence, false, (2, 6), 1).as_deref(), Some("Two!  "));
+        assert_eq!(text(&buffer, ObjectKind::Sentence, true, (3, 0), 1).as_deref(), Some("Three\nfour."));
+
+        assert_eq!(text(&buffer, ObjectKind::Paragraph, true, (3, 0), 1).as_deref(), Some("One. Two!  Three\nfour.\n"));
+        assert_eq!(text(&buffer, ObjectKind::Paragraph, false, (2, 0), 1).as_deref(), Some("One. Two!  Three\nfour.\n\n\n"));
+        assert_eq!(text(&buffer, ObjectKind::Paragraph, false, (6, 0), 1).as_deref(), Some("\n\nlast\n"));
+        assert_eq!(text(&buffer, ObjectKind::Paragraph, true, (0, 0), 2).as_deref(), Some("foo.bar baz  qux\n\n"));
+        assert_eq!(
+            text(&buffer, ObjectKind::Paragraph, false, (0, 0), 2).as_deref(),
+            Some("foo.bar baz  qux\n\nOne. Two!  Three\nfour.\n\n\n")
+        );
+    }
+
+    #[test]
+    fn test_quotes_brackets_and_tags() {
+        let buffer = buffer_with("say(\"a \\\" b\", f(x)) \"c\"\nfn main() {\n    body;\n}\n<p>x <b>bold</b><br></p>");
+        let quote = ObjectKind::Quote('"');
+        assert_eq!(text(&buffer, quote, true, (0, 6), 1).as_deref(), Some("a \\\" b"));
+        assert_eq!(text(&buffer, quote, false, (0, 0), 1).as_deref(), Some("\"a \\\" b\""));
+        assert_eq!(text(&buffer, quote, false, (0, 20), 1).as_deref(), Some(" \"c\""));
+
+        let paren = ObjectKind::Bracket { open: '(', close: ')' };
+        assert_eq!(text(&buffer, paren, true, (0, 17), 1).as_deref(), Some("x"));
+        assert_eq!(text(&buffer, paren, false, (0, 16), 2).as_deref(), Some("(\"a \\\" b\", f(x))"));
+        assert_eq!(text(&buffer, paren, true, (0, 21), 1), None);
+        let brace = ObjectKind::Bracket { open: '{', close: '}' };
+        assert_eq!(text(&buffer, brace, true, (2, 6), 1).as_deref(), Some("    body;\n"));
+        assert_eq!(text(&buffer, brace, false, (3, 0), 1).as_deref(), Some("{\n    body;\n}"));
+
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 10), 1).as_deref(), Some("bold"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, false, (4, 10), 1).as_deref(), Some("<b>bold</b>"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 10), 2).as_deref(), Some("x <b>bold</b><br>"));
+        // on a tag, the element it opens or closes
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 5), 1).as_deref(), Some("bold"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 13), 1).as_deref(), Some("bold"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 17), 1).as_deref(), Some("x <b>bold</b><br>"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (4, 10), 3), None);
+
+        let buffer = buffer_with("<div\n  id=\"a\">\n  <p>(one\n  two)</p>\n</div>");
+        assert_eq!(text(&buffer, ObjectKind::Tag, true, (3, 2), 1).as_deref(), Some("(one\n  two)"));
+        assert_eq!(text(&buffer, ObjectKind::Tag, false, (3, 2), 2).as_deref(), Some("<div\n  id=\"a\">\n  <p>(one\n  two)</p>\n</div>"));
+        assert_eq!(text(&buffer, paren, true, (2, 6), 1).as_deref(), Some("one\n  two"));
+        assert_eq!(text(&buffer, paren, false, (3, 5), 1).as_deref(), Some("(one\n  two)"));
+    }
+}
@@ -0,0 +1,356 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum WindowError {
+    #[error("Not enough room")]
+    NoRoom,
+    #[error("Cannot close last window")]
+    LastWindow,
+}
+
+/// A part of the screen, in cells.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    // the cells across a split: columns when `vertical`, rows otherwise
+    fn along(&self, vertical: bool) -> u16 {
+        if vertical { self.width } else { self.height }
+    }
+}
+
+/// Where `Ctrl-W h`, `j`, `k` and `l` go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Down,
+    Up,
+    Right,
+}
+
+// the least a window takes: one column, and one text row above its status line
+const MIN_WIDTH: u16 = 1;
+const MIN_HEIGHT: u16 = 2;
+
+/// How the screen is divided between windows, named by id. Windows side by
+/// side are kept apart by a one-column separator, stacked ones by their
+/// status lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Window(usize),
+    /// Side by side when `vertical`, stacked otherwise, each child with the
+    /// cells across the split it was last given.
+    Split { vertical: bool, children: Vec<(Layout, u16)> },
+}
+
+// Shares `total` cells between children in proportion to `sizes`, giving
+// each at least what it needs.
+fn share(sizes: &[u16], needs: &[u16], total: u16) -> Vec<u16> {
+    let sum: u32 = sizes.iter().map(|&size| size as u32).sum();
+    if sum == total as u32 {
+        return sizes.to_vec();
+    }
+    let mut shared: Vec<u16> = sizes.iter()
+        .map(|&size| (size as u32 * total as u32 / sum.max(1)) as u16)
+        .collect();
+    // what rounding leaves over goes to the last child
+    let used: u16 = shared.iter().sum();
+    if let Some(last) = shared.last_mut() {
+        *last += total.saturating_sub(used);
+    }
+    for i in 0..shared.len() {
+        while shared[i] < needs[i] {
+            let Some(largest) = (0..shared.len()).filter(|&j| shared[j] > needs[j]).max_by_key(|&j| shared[j]) else {
+                break;
+            };
+            shared[largest] -= 1;
+            shared[i] += 1;
+        }
+    }
+    shared
+}
+
+impl Layout {
+    /// The window ids from top left to bottom right.
+    pub fn windows(&self) -> Vec<usize> {
+        match self {
+            Layout::Window(id) => vec![*id],
+            Layout::Split { children, .. } => children.iter().flat_map(|(child, _)| child.windows()).collect(),
+        }
+    }
+
+    /// Where each window goes in `area`, in the order of `windows`.
+    pub fn rects(&self, area: Rect) -> Vec<(usize, Rect)> {
+        match self {
+            Layout::Window(id) => vec![(*id, area)],
+            Layout::Split { vertical, children } => children.iter()
+                .zip(child_rects(*vertical, children, area))
+                .flat_map(|((child, _), rect)| child.rects(rect))
+                .collect(),
+        }
+    }
+
+    // the fewest cells the windows in here fit in across a split
+    fn needs(&self, vertical: bool) -> u16 {
+        match self {
+            Layout::Window(_) if vertical => MIN_WIDTH,
+            Layout::Window(_) => MIN_HEIGHT,
+            Layout::Split { vertical: inner, children } if *inner == vertical => {
+                let separators = if vertical { children.len() as u16 - 1 } else { 0 };
+                children.iter().map(|(child, _)| child.needs(vertical)).sum::<u16>() + separators
+            }
+            Layout::Split { children, .. } => children.iter().map(|(child, _)| child.needs(vertical)).max().unwrap_or(0),
+        }
+    }
+
+    // Writes down the sizes laid out in `area`, so that resizing starts
+    // from what is on screen.
+    fn fit(&mut self, area: Rect) {
+        if let Layout::Split { vertical, children } = self {
+            let rects = child_rects(*vertical, children, area);
+            for ((child, size), rect) in children.iter_mut().zip(rects) {
+                *size = rect.along(*vertical);
+                child.fit(rect);
+            }
+        }
+    }
+
+    // the child indices leading from here to window `id`
+    fn path(&self, id: usize) -> Option<Vec<usize>> {
+        match self {
+            Layout::Window(window) => (*window == id).then(Vec::new),
+            Layout::Split { children, .. } => children.iter().enumerate().find_map(|(i, (child, _))| {
+                let mut path = child.path(id)?;
+                path.insert(0, i);
+                Some(path)
+            }),
+        }
+    }
+
+    fn node(&self, path: &[usize]) -> &Layout {
+        match (self, path.split_first()) {
+            (Layout::Split { children, .. }, Some((&i, rest))) => children[i].0.node(rest),
+            _ => self,
+        }
+    }
+
+    fn node_mut(&mut self, path: &[usize]) -> &mut Layout {
+        match (self, path.split_first()) {
+            (Layout::Split { children, .. }, Some((&i, rest))) => children[i].0.node_mut(rest),
+            (node, _) => node,
+        }
+    }
+
+    /// Splits window `id` in two, putting window `new` above it, or on its
+    /// left when `vertical`, with half the room.
+    pub fn split(&mut self, id: usize, new: usize, vertical: bool, area: Rect) -> Result<(), WindowError> {
+        self.fit(area);
+        let path = self.path(id).ok_or(WindowError::NoRoom)?;
+        let room = self.rects(area).into_iter()
+            .find(|(window, _)| *window == id)
+            .map_or(0, |(_, rect)| rect.along(vertical));
+        let separator = vertical as u16;
+        let need = if vertical { MIN_WIDTH } else { MIN_HEIGHT };
+        if room < 2 * need + separator {
+            return Err(WindowError::NoRoom);
+        }
+        let new_size = (room - separator) / 2;
+        let old_size = room - separator - new_size;
+        // a split the same way as the one the window is in adds to that one
+        if let Some((&index, parent)) = path.split_last()
+            && let Layout::Split { vertical: across, children } = self.node_mut(parent)
+            && *across == vertical
+        {
+            children[index].1 = old_size;
+            children.insert(index, (Layout::Window(new), new_size));
+            return Ok(());
+        }
+        let node = self.node_mut(&path);
+        *node = Layout::Split {
+            vertical,
+            children: vec![(Layout::Window(new), new_size), (Layout::Window(id), old_size)],
+        };
+        Ok(())
+    }
+
+    /// Takes window `id` off the screen. Its room goes to the window before
+    /// it, or after it when it was the first.
+    pub fn remove(&mut self, id: usize) -> Result<(), WindowError> {
+        let path = self.path(id).unwrap_or_default();
+        let Some((&index, parent)) = path.split_last() else {
+            return Err(WindowError::LastWindow);
+        };
+        if let Layout::Split { vertical, children } = self.node_mut(parent) {
+            let (_, size) = children.remove(index);
+            let to = index.saturating_sub(1);
+            children[to].1 += size + *vertical as u16;
+        }
+        self.collapse();
+        Ok(())
+    }
+
+    // A split left with one child is that child, and one inside a split
+    // the same way becomes part of it.
+    fn collapse(&mut self) {
+        let Layout::Split { vertical, children } = self else { return };
+        let mut flat = Vec::with_capacity(children.len());
+        for (mut child, size) in children.drain(..) {
+            child.collapse();
+            match child {
+                Layout::Split { vertical: inner, children: grandchildren } if inner == *vertical => flat.extend(grandchildren),
+                child => flat.push((child, size)),
+            }
+        }
+        *children = flat;
+        if children.len() == 1
+            && let Some((only, _)) = children.pop()
+        {
+            *self = only;
+        }
+    }
+
+    /// The window next to `id` in `direction`. Of several, the one level
+    /// with `at`, a screen position inside `id` such as its cursor, wins.
+    pub fn neighbour(&self, id: usize, direction: Direction, area: Rect, at: (u16, u16)) -> Option<usize> {
+        let rects = self.rects(area);
+        let &(_, from) = rects.iter().find(|(window, _)| *window == id)?;
+        let beside = |r: &Rect| match direction {
+            Direction::Left => r.x + r.width + 1 == from.x && r.y < from.y + from.height && from.y < r.y + r.height,
+            Direction::Right => from.x + from.width + 1 == r.x && r.y < from.y + from.height && from.y < r.y + r.height,
+            Direction::Up => r.y + r.height == from.y && r.x < from.x + from.width && from.x < r.x + r.width,
+            Direction::Down => from.y + from.height == r.y && r.x < from.x + from.width && from.x < r.x + r.width,
+        };
+        let level = |r: &Rect| match direction {
+            Direction::Left | Direction::Right => (r.y..r.y + r.height).contains(&at.1),
+            Direction::Up | Direction::Down => (r.x..r.x + r.width).contains(&at.0),
+        };
+        let beside: Vec<&(usize, Rect)> = rects.iter().filter(|(_, r)| beside(r)).collect();
+        beside.iter().find(|(_, r)| level(r)).or(beside.first()).map(|(window, _)| *window)
+    }
+
+    /// Makes window `id` `delta` cells wider when `vertical`, or taller,
+    /// taking the room from the windows after it first, then those before.
+    /// A negative `delta` gives room to the next window instead.
+    pub fn resize(&mut self, id: usize, vertical: bool, delta: i32, area: Rect) {
+        self.fit(area);
+        let Some(path) = self.path(id) else { return };
+        // the innermost split the right way that the window is in
+        let Some(depth) = (0..path.len()).rev().find(|&depth| {
+            matches!(self.node(&path[..depth]), Layout::Split { vertical: across, .. } if *across == vertical)
+        }) else {
+            return;
+        };
+        let index = path[depth];
+        let Layout::Split { children, .. } = self.node_mut(&path[..depth]) else { return };
+        let needs: Vec<u16> = children.iter().map(|(child, _)| child.needs(vertical)).collect();
+        if delta >= 0 {
+            let mut wanted = delta.min(u16::MAX as i32) as u16;
+            for other in (index + 1..children.len()).chain((0..index).rev()) {
+                let spare = children[other].1.saturating_sub(needs[other]).min(wanted);
+                children[other].1 -= spare;
+                children[index].1 += spare;
+                wanted -= spare;
+            }
+        } else {
+            let given = (-delta).min(u16::MAX as i32) as u16;
+            let given = given.min(children[index].1.saturating_sub(needs[index]));
+            let to = if index + 1 < children.len() { index + 1 } else { index - 1 };
+            children[index].1 -= given;
+            children[to].1 += given;
+        }
+    }
+
+    /// `{N}Ctrl-W _` and `:resize N`: makes window `id` `size` cells across
+    /// the split given, as near as the other windows allow.
+    pub fn set_size(&mut self, id: usize, vertical: bool, size: u16, area: Rect) {
+        let Some((_, rect)) = self.rects(area).into_iter().find(|(window, _)| *window == id) else { return };
+        self.resize(id, vertical, size as i32 - rect.along(vertical) as i32, area);
+    }
+
+    /// `Ctrl-W =`: gives all windows in a split the same room.
+    pub fn equalize(&mut self) {
+        if let Layout::Split { children, .. } = self {
+            for (child, size) in children {
+                *size = 1;
+                child.equalize();
+            }
+        }
+    }
+}
+
+fn child_rects(vertical: bool, children: &[(Layout, u16)], area: Rect) -> Vec<Rect> {
+    let separators = if vertical { children.len() as u16 - 1 } else { 0 };
+    let sizes: Vec<u16> = children.iter().map(|(_, size)| *size).collect();
+    let needs: Vec<u16> = children.iter().map(|(child, _)| child.needs(vertical)).collect();
+    let mut at = if vertical { area.x } else { area.y };
+    share(&sizes, &needs, area.along(vertical).saturating_sub(separators))
+        .into_iter()
+        .map(|size| {
+            let rect = if vertical { Rect { x: at, width: size, ..area } } else { Rect { y: at, height: size, ..area } };
+            at += size + vertical as u16;
+            rect
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect { x: 0, y: 0, width: 80, height: 24 };
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    #[test]
+    fn test_split_lays_out_and_removes() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, false, SCREEN).unwrap();
+        layout.split(1, 3, true, SCREEN).unwrap();
+        assert_eq!(layout.windows(), [2, 3, 1]);
+        assert_eq!(
+            layout.rects(SCREEN),
+            [(2, rect(0, 0, 80, 12)), (3, rect(0, 12, 39, 12)), (1, rect(40, 12, 40, 12))]
+        );
+        // a second split the same way shares the row with the first
+        layout.split(3, 4, true, SCREEN).unwrap();
+        assert_eq!(layout.rects(SCREEN)[1..3], [(4, rect(0, 12, 19, 12)), (3, rect(20, 12, 19, 12))]);
+
+        layout.remove(4).unwrap();
+        layout.remove(3).unwrap();
+        assert_eq!(layout.rects(SCREEN), [(2, rect(0, 0, 80, 12)), (1, rect(0, 12, 80, 12))]);
+        layout.remove(2).unwrap();
+        assert_eq!(layout, Layout::Window(1));
+        assert_eq!(layout.remove(1), Err(WindowError::LastWindow));
+        assert_eq!(layout.split(1, 2, false, rect(0, 0, 80, 3)), Err(WindowError::NoRoom));
+    }
+
+    #[test]
+    fn test_neighbours_and_resizing() {
+        let mut layout = Layout::Window(1);
+        layout.split(1, 2, true, SCREEN).unwrap();
+        layout.split(1, 3, false, SCREEN).unwrap();
+        // 2 on the left, 3 above 1 on the right
+        assert_eq!(layout.neighbour(2, Direction::Right, SCREEN, (0, 20)), Some(1));
+        assert_eq!(layout.neighbour(2, Direction::Right, SCREEN, (0, 0)), Some(3));
+        assert_eq!(layout.neighbour(1, Direction::Up, SCREEN, (50, 20)), Some(3));
+        assert_eq!(layout.neighbour(1, Direction::Down, SCREEN, (50, 20)), None);
+
+        layout.resize(3, false, 4, SCREEN);
+        assert_eq!(layout.rects(SCREEN)[1..], [(3, rect(40, 0, 40, 16)), (1, rect(40, 16, 40, 8))]);
+        layout.resize(1, true, -10, SCREEN);
+        assert_eq!(layout.rects(SCREEN)[0], (2, rect(0, 0, 49, 24)));
+        layout.set_size(3, false, 100, SCREEN);
+        assert_eq!(layout.rects(SCREEN)[2], (1, rect(50, 22, 30, 2)));
+        layout.equalize();
+        assert_eq!(layout.rects(SCREEN), [(2, rect(0, 0, 39, 24)), (3, rect(40, 0, 40, 12)), (1, rect(40, 12, 40, 12))]);
+        // the layout follows the terminal when it is resized
+        assert_eq!(layout.rects(rect(0, 0, 40, 10))[0], (2, rect(0, 0, 19, 10)));
+    }
+}